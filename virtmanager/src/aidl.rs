@@ -14,21 +14,59 @@
 
 //! Implementation of the AIDL interface of the Virt Manager.
 
-use crate::config::VmConfig;
-use crate::crosvm::VmInstance;
+use crate::binaries::{CrosvmFeatureUnsupported, CrosvmRegistry};
+use crate::callback_queue::CallbackQueue;
+use crate::config::{
+    AsyncExecutor, BaseConfig, DiskIntegrityFailed, NothingToBoot, VmConfig, BASE_CONFIG_PATH,
+};
+use crate::crosvm::{
+    check_config, DeadlineExceeded, DeviceInitFailed, Lifecycle, StopReason, VmInstance,
+};
+use crate::duplicate::DuplicatePolicy;
+use crate::executor;
+use crate::health::{HealthTracker, HostCapabilities};
+use crate::memory::MemoryReserve;
+use crate::pressure::{self, PressurePolicy};
+use crate::protection::ProtectionSupport;
+use crate::quota::UidQuota;
+use crate::retry::{VsockRetry, VsockUnavailable};
+use crate::teardown::TeardownStage;
+use crate::usage::UsageSummary;
+use crate::vsock;
 use crate::{Cid, FIRST_GUEST_CID};
-use android_system_virtmanager::aidl::android::system::virtmanager::IVirtManager::IVirtManager;
+use android_system_virtmanager::aidl::android::system::virtmanager::ConfigCheckResult::ConfigCheckResult;
+use android_system_virtmanager::aidl::android::system::virtmanager::IVirtManager::{
+    IVirtManager, ERROR_ALREADY_RUNNING, ERROR_CROSVM_FEATURE_UNSUPPORTED, ERROR_DEADLINE_EXCEEDED,
+    ERROR_DEVICE_INIT_FAILED, ERROR_DISK_INTEGRITY_FAILED, ERROR_INSUFFICIENT_MEMORY,
+    ERROR_MEMORY_PROTECTION_UNSUPPORTED, ERROR_QUOTA_EXCEEDED, ERROR_VSOCK_UNAVAILABLE,
+};
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtualMachine::{
     BnVirtualMachine, IVirtualMachine,
 };
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtualMachineCallback::IVirtualMachineCallback;
+use android_system_virtmanager::aidl::android::system::virtmanager::ServiceHealth::ServiceHealth;
+use android_system_virtmanager::aidl::android::system::virtmanager::ServiceHealthStatus::ServiceHealthStatus;
 use android_system_virtmanager::aidl::android::system::virtmanager::VirtualMachineDebugInfo::VirtualMachineDebugInfo;
+use android_system_virtmanager::aidl::android::system::virtmanager::VirtualMachineState::VirtualMachineState;
+use android_system_virtmanager::aidl::android::system::virtmanager::VmRefInfo::VmRefInfo;
+use android_system_virtmanager::aidl::android::system::virtmanager::VmStats::VmStats;
+use android_system_virtmanager::aidl::android::system::virtmanager::VmStopReason::VmStopReason;
+use android_system_virtmanager::aidl::android::system::virtmanager::VmStopSummary::VmStopSummary;
+use android_system_virtmanager::aidl::android::system::virtmanager::VsockConnInfo::VsockConnInfo;
 use android_system_virtmanager::binder::{
-    self, BinderFeatures, Interface, ParcelFileDescriptor, StatusCode, Strong, ThreadState,
+    self, BinderFeatures, Interface, ParcelFileDescriptor, Status, StatusCode, Strong, ThreadState,
 };
-use log::{debug, error};
+use anyhow::{Context, Error};
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::ffi::CString;
 use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub const BINDER_SERVICE_IDENTIFIER: &str = "android.system.virtmanager";
 
@@ -36,24 +74,135 @@ pub const BINDER_SERVICE_IDENTIFIER: &str = "android.system.virtmanager";
 /// Only processes running with one of these UIDs are allowed to call debug methods.
 const DEBUG_ALLOWED_UIDS: [u32; 2] = [0, 2000];
 
+/// The number of bytes in a mebibyte.
+const MIB: u64 = 1 << 20;
+
 /// Implementation of `IVirtManager`, the entry point of the AIDL service.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct VirtManager {
-    state: Mutex<State>,
+    state: Arc<Mutex<State>>,
+    /// Held while a VM is being started, so that VMs are admitted one at a time without holding
+    /// `state` while crosvm is launched, which may take a while if it has to be retried.
+    start_lock: Mutex<()>,
+    /// The crosvm binaries which VMs may be run with.
+    crosvm_registry: CrosvmRegistry,
+    /// Queue on which callbacks to clients are delivered, shared by all VMs.
+    callback_queue: CallbackQueue,
+    /// The host memory which VMs may not use, or `None` if no reserve is configured or the host's
+    /// memory couldn't be determined, in which case VMs are started regardless of how much memory
+    /// they need.
+    memory_reserve: Option<MemoryReserve>,
+    /// The async executor for crosvm to use for VMs whose config doesn't specify one, if any. If
+    /// this is `None` then crosvm's own default is used.
+    async_executor: Option<AsyncExecutor>,
+    /// How many VMs each UID may run at once.
+    uid_quota: UidQuota,
+    /// Counts of failures across all VMs, for `getHealth`.
+    health: Arc<Mutex<HealthTracker>>,
+    /// Defaults applied beneath the config of every VM.
+    base_config: BaseConfig,
+    /// How to retry launching crosvm if the vhost-vsock device is unavailable.
+    vsock_retry: VsockRetry,
+    /// What to do when a VM is started from the same config file as one already running.
+    duplicate_policy: DuplicatePolicy,
+    /// Which memory protection the host supports, probed when the service starts.
+    protection_support: ProtectionSupport,
 }
 
-impl Interface for VirtManager {}
+impl VirtManager {
+    /// Create a new Virt Manager which will run VMs with the crosvm binaries in the given registry.
+    pub fn new(crosvm_registry: CrosvmRegistry) -> VirtManager {
+        let state: Arc<Mutex<State>> = Default::default();
+        let memory_reserve = match MemoryReserve::from_env() {
+            Ok(Some(reserve)) => {
+                info!(
+                    "Reserving {} of {} MiB for the host",
+                    reserve.reserve_mib, reserve.total_mib
+                );
+                Some(reserve)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Not limiting guest memory: {:?}", e);
+                None
+            }
+        };
+        let uid_quota = UidQuota::from_env().unwrap_or_else(|e| {
+            warn!("Not limiting VMs per UID: {:?}", e);
+            UidQuota::default()
+        });
+        let vsock_retry = VsockRetry::from_env().unwrap_or_else(|e| {
+            warn!("Not retrying if vhost-vsock is unavailable: {:?}", e);
+            VsockRetry::default()
+        });
+        let duplicate_policy = DuplicatePolicy::from_env().unwrap_or_else(|e| {
+            warn!("Allowing duplicate VMs: {:?}", e);
+            DuplicatePolicy::default()
+        });
+        let base_config = BaseConfig::load(Path::new(BASE_CONFIG_PATH)).unwrap_or_else(|e| {
+            warn!("Not applying base VM config: {:?}", e);
+            BaseConfig::default()
+        });
+        let protection_support = ProtectionSupport::probe();
+        info!("Host memory protection support: {:?}", protection_support);
+        if let Some(policy) = PressurePolicy::from_env() {
+            let state = state.clone();
+            pressure::spawn_monitor(policy, move || state.lock().unwrap().vms());
+        }
+        VirtManager {
+            state,
+            start_lock: Mutex::new(()),
+            crosvm_registry,
+            callback_queue: CallbackQueue::default(),
+            memory_reserve,
+            async_executor: executor::default_from_env(),
+            uid_quota,
+            health: Default::default(),
+            base_config,
+            vsock_retry,
+            duplicate_policy,
+            protection_support,
+        }
+    }
+}
 
-impl IVirtManager for VirtManager {
-    /// Create and start a new VM with the given configuration, assigning it the next available CID.
+impl VirtManager {
+    /// Start a new VM with the given configuration on behalf of the current Binder caller,
+    /// assigning it the given CID, which the caller must have reserved, or otherwise the next
+    /// available CID.
     ///
-    /// Returns a binder `IVirtualMachine` object referring to it, as a handle for the client.
-    fn startVm(
+    /// If the deadline passes before the VM has been started then any crosvm process which was
+    /// spawned is killed, the CID is released once crosvm has exited and an error is returned. A
+    /// reserved CID is reserved again if the VM fails to start, so that the caller can try again.
+    fn start_vm(
         &self,
-        config_fd: &ParcelFileDescriptor,
+        config: &VmConfig,
         log_fd: Option<&ParcelFileDescriptor>,
+        deadline: Option<Instant>,
+        reserved_cid: Option<Cid>,
     ) -> binder::Result<Strong<dyn IVirtualMachine>> {
-        let state = &mut *self.state.lock().unwrap();
+        let config = &VmConfig {
+            async_executor: config.async_executor.or(self.async_executor),
+            ..config.clone()
+        };
+        let crosvm =
+            self.crosvm_registry.select(config.crosvm_version.as_deref()).map_err(|e| {
+                error!("Failed to choose crosvm binary: {:?}", e);
+                StatusCode::BAD_VALUE
+            })?;
+        if let Some(protection) = &config.memory_protection {
+            if let Err(e) = protection.check_host(self.protection_support) {
+                error!("Not starting VM: {:?}", e);
+                let option = CString::new(e.option).ok();
+                return Err(Status::new_service_specific_error(
+                    ERROR_MEMORY_PROTECTION_UNSUPPORTED,
+                    option.as_deref(),
+                ));
+            }
+        }
+        check_deadline(deadline)?;
+        let _starting = self.start_lock.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
         let log_fd = log_fd
             .map(|fd| fd.as_ref().try_clone().map_err(|_| StatusCode::UNKNOWN_ERROR))
             .transpose()?;
@@ -73,17 +222,296 @@ impl IVirtManager for VirtManager {
             }
         })?;
         let requester_debug_pid = ThreadState::get_calling_pid();
-        let cid = state.allocate_cid()?;
-        let instance = start_vm(
-            config_fd.as_ref(),
+        let own_vms =
+            state.vms().into_iter().filter(|vm| vm.running() && vm.requester_uid == requester_uid);
+        match self
+            .duplicate_policy
+            .admit(config.source_path.as_deref(), own_vms, |vm| vm.config.source_path.as_deref())
+        {
+            Ok(Some(existing)) => {
+                // The caller shares the existing handle, so that the VM is only stopped once every
+                // client has dropped it.
+                if let Some(handle) = state.handle(existing.cid) {
+                    info!("Reusing VM with CID {} started from the same config", existing.cid);
+                    return Ok(handle);
+                }
+                // Otherwise every handle to the VM has been dropped, so it is being stopped and a
+                // new VM is started instead.
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Not starting VM: {:?}", e);
+                return Err(Status::new_service_specific_error(ERROR_ALREADY_RUNNING, None));
+            }
+        }
+        let running_owners = state.vms().into_iter().filter(|vm| vm.running());
+        if let Err(e) =
+            self.uid_quota.admit(requester_uid, running_owners.map(|vm| vm.requester_uid))
+        {
+            error!("Not starting VM: {:?}", e);
+            return Err(Status::new_service_specific_error(ERROR_QUOTA_EXCEEDED, None));
+        }
+        if let Some(reserve) = &self.memory_reserve {
+            let running = state.vms().into_iter().filter(|vm| vm.running());
+            let running_mib = running.map(|vm| vm.config.guest_memory_mib());
+            if let Err(e) = reserve.admit(running_mib, config.guest_memory_mib()) {
+                error!("Not starting VM: {:?}", e);
+                return Err(Status::new_service_specific_error(ERROR_INSUFFICIENT_MEMORY, None));
+            }
+        }
+        let cid = match reserved_cid {
+            Some(cid) => {
+                state.take_reservation(cid, requester_uid)?;
+                cid
+            }
+            None => state.allocate_cid()?,
+        };
+        let reserved_by = reserved_cid.map(|_| requester_uid);
+        // The CID is allocated, so other calls can go ahead while crosvm is launched.
+        drop(state);
+        let instance = VmInstance::start(
+            config,
+            crosvm,
+            VirtualMachineCallbacks::new(self.callback_queue.clone(), self.health.clone()),
             cid,
             log_fd,
+            &self.vsock_retry,
+            deadline,
             requester_uid,
             requester_sid,
             requester_debug_pid,
-        )?;
-        state.add_vm(Arc::downgrade(&instance));
-        Ok(VirtualMachine::create(instance))
+        )
+        .map_err(|e| {
+            error!("Failed to start VM: {:?}", e);
+            self.health.lock().unwrap().record_failed_start();
+            self.state.lock().unwrap().unallocate_cid(cid, reserved_by);
+            if e.downcast_ref::<DiskIntegrityFailed>().is_some() {
+                Status::new_service_specific_error(ERROR_DISK_INTEGRITY_FAILED, None)
+            } else if let Some(e) = e.downcast_ref::<CrosvmFeatureUnsupported>() {
+                let message = CString::new(e.to_string()).ok();
+                Status::new_service_specific_error(
+                    ERROR_CROSVM_FEATURE_UNSUPPORTED,
+                    message.as_deref(),
+                )
+            } else if e.downcast_ref::<NothingToBoot>().is_some() {
+                StatusCode::BAD_VALUE.into()
+            } else if e.downcast_ref::<DeadlineExceeded>().is_some() {
+                Status::new_service_specific_error(ERROR_DEADLINE_EXCEEDED, None)
+            } else if e.downcast_ref::<VsockUnavailable>().is_some() {
+                Status::new_service_specific_error(ERROR_VSOCK_UNAVAILABLE, None)
+            } else if let Some(e) = e.downcast_ref::<DeviceInitFailed>() {
+                let device = CString::new(e.device).ok();
+                Status::new_service_specific_error(ERROR_DEVICE_INIT_FAILED, device.as_deref())
+            } else {
+                StatusCode::UNKNOWN_ERROR.into()
+            }
+        })?;
+        if let Err(e) = check_deadline(deadline) {
+            // crosvm holds on to the CID until it has been reaped, so the CID is only given back
+            // once teardown has waited for it to exit.
+            let state = self.state.clone();
+            instance.add_teardown_step(TeardownStage::Network, "CID", move || {
+                state.lock().unwrap().unallocate_cid(cid, reserved_by);
+                Ok(())
+            });
+            instance.kill();
+            return Err(e);
+        }
+        Ok(self.state.lock().unwrap().add_vm(instance))
+    }
+}
+
+impl Interface for VirtManager {}
+
+impl IVirtManager for VirtManager {
+    /// Create and start a new VM with the given configuration, assigning it the next available CID.
+    ///
+    /// Returns a binder `IVirtualMachine` object referring to it, as a handle for the client.
+    fn startVm(
+        &self,
+        config_fd: &ParcelFileDescriptor,
+        log_fd: Option<&ParcelFileDescriptor>,
+        deadline_millis: i64,
+    ) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        let deadline = deadline_from_millis(deadline_millis);
+        let config = VmConfig::load(config_fd.as_ref(), &self.base_config).map_err(|e| {
+            error!("Failed to load VM config from {:?}: {:?}", config_fd, e);
+            StatusCode::BAD_VALUE
+        })?;
+        self.start_vm(&config, log_fd, deadline, None)
+    }
+
+    /// Create and start a new VM with the given configuration on the given CID, which the caller
+    /// must have reserved with `reserveCid`.
+    ///
+    /// Returns a binder `IVirtualMachine` object referring to it, as a handle for the client.
+    fn startVmWithCid(
+        &self,
+        config_fd: &ParcelFileDescriptor,
+        log_fd: Option<&ParcelFileDescriptor>,
+        deadline_millis: i64,
+        cid: i32,
+    ) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        let deadline = deadline_from_millis(deadline_millis);
+        let cid = Cid::try_from(cid).map_err(|_| StatusCode::BAD_VALUE)?;
+        let config = VmConfig::load(config_fd.as_ref(), &self.base_config).map_err(|e| {
+            error!("Failed to load VM config from {:?}: {:?}", config_fd, e);
+            StatusCode::BAD_VALUE
+        })?;
+        self.start_vm(&config, log_fd, deadline, Some(cid))
+    }
+
+    /// Reserve the next available CID for the caller, to start a VM on later with
+    /// `startVmWithCid`.
+    fn reserveCid(&self) -> binder::Result<i32> {
+        let owner = ThreadState::get_calling_uid();
+        let cid = self.state.lock().unwrap().reserve_cid(owner)?;
+        info!("Reserved CID {} for UID {}", cid, owner);
+        Ok(cid as i32)
+    }
+
+    /// Release a CID which the caller reserved but hasn't started a VM on.
+    fn releaseCid(&self, cid: i32) -> binder::Result<()> {
+        let cid = Cid::try_from(cid).map_err(|_| StatusCode::BAD_VALUE)?;
+        let owner = ThreadState::get_calling_uid();
+        self.state.lock().unwrap().release_reservation(cid, owner)?;
+        Ok(())
+    }
+
+    /// Create and start a new VM from the bundle in the given directory, assigning it the next
+    /// available CID.
+    ///
+    /// Returns a binder `IVirtualMachine` object referring to it, as a handle for the client.
+    fn startVmFromBundle(
+        &self,
+        bundle_dir: &str,
+        log_fd: Option<&ParcelFileDescriptor>,
+        deadline_millis: i64,
+    ) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        let deadline = deadline_from_millis(deadline_millis);
+        let config =
+            VmConfig::load_bundle(Path::new(bundle_dir), &self.base_config).map_err(|e| {
+                error!("Failed to load VM bundle from {}: {:?}", bundle_dir, e);
+                StatusCode::BAD_VALUE
+            })?;
+        self.start_vm(&config, log_fd, deadline, None)
+    }
+
+    /// Check whether a VM could be started from the given config file, without starting it.
+    fn checkConfig(&self, config_path: &str) -> binder::Result<ConfigCheckResult> {
+        let config = match File::open(config_path)
+            .map_err(Error::from)
+            .and_then(|file| VmConfig::load(&file, &self.base_config))
+        {
+            Ok(config) => config,
+            Err(e) => {
+                return Ok(ConfigCheckResult {
+                    errors: vec![format!("Failed to load config: {:#}", e)],
+                    warnings: vec![],
+                })
+            }
+        };
+        let mut check = match self.crosvm_registry.select(config.crosvm_version.as_deref()) {
+            Ok(crosvm) => check_config(&config, crosvm),
+            Err(e) => {
+                return Ok(ConfigCheckResult { errors: vec![format!("{:#}", e)], warnings: vec![] })
+            }
+        };
+        if let Some(protection) = &config.memory_protection {
+            if let Err(e) = protection.check_host(self.protection_support) {
+                check.errors.push(e.to_string());
+            }
+        }
+        Ok(ConfigCheckResult {
+            errors: check.errors,
+            warnings: check.warnings.iter().map(ToString::to_string).collect(),
+        })
+    }
+
+    fn getHealth(&self) -> binder::Result<ServiceHealth> {
+        let running: Vec<_> =
+            self.state.lock().unwrap().vms().into_iter().filter(|vm| vm.running()).collect();
+        let stalled: Vec<_> =
+            running.iter().filter(|vm| vm.monitor_stalled()).map(|vm| vm.cid).collect();
+        if !stalled.is_empty() {
+            warn!("Monitor threads of VMs with CIDs {:?} have died or stalled", stalled);
+        }
+        let health = self.health.lock().unwrap().summary(
+            running.len(),
+            stalled.len(),
+            HostCapabilities::probe(),
+            Instant::now(),
+        );
+        Ok(ServiceHealth {
+            status: if health.degraded {
+                ServiceHealthStatus::DEGRADED
+            } else {
+                ServiceHealthStatus::OK
+            },
+            runningVmCount: health.running_vms as i32,
+            stalledMonitorCount: health.stalled_monitors as i32,
+            failedStartCount: health.failed_starts as i64,
+            earlyCrashCount: health.early_crashes as i32,
+            kvmPresent: health.capabilities.kvm,
+            vsockPresent: health.capabilities.vsock,
+        })
+    }
+
+    /// Forward connections from one VM to a vsock port of another, both of which must have been
+    /// started by the caller. Returns the host port which the source VM should connect to.
+    fn connectVsockToVm(&self, source_cid: i32, target_cid: i32, port: i32) -> binder::Result<i32> {
+        let caller = ThreadState::get_calling_uid();
+        let vms = self.state.lock().unwrap().vms();
+        let find_vm = |cid: i32| {
+            vms.iter().find(|vm| vm.cid as i32 == cid && vm.running()).ok_or_else(|| {
+                error!("No running VM with CID {} to forward vsock between", cid);
+                StatusCode::NAME_NOT_FOUND
+            })
+        };
+        let (source, target) = (find_vm(source_cid)?, find_vm(target_cid)?);
+        if source.requester_uid != caller || target.requester_uid != caller {
+            error!("UID {} tried to forward vsock between VMs it doesn't own", caller);
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let (listener, host_port) = vsock::listen().map_err(|e| {
+            error!("Failed to listen for vsock connections to forward: {}", e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        let stop = Arc::new(AtomicBool::new(false));
+        vsock::spawn_forwarder(
+            listener,
+            (source.cid, &source.vsock_connections),
+            (target.cid, port as u32, &target.vsock_connections),
+            stop.clone(),
+        );
+        source.add_teardown_step(TeardownStage::Network, "vsock forwarder", move || {
+            stop.store(true, Ordering::Release);
+            Ok(())
+        });
+        Ok(host_port as i32)
+    }
+
+    /// Start a VM from the given config file in safe mode, with all optional devices removed. This
+    /// method is only intended for debug purposes, and as such is only permitted from the shell
+    /// user.
+    fn debugStartSafeMode(
+        &self,
+        config_path: &str,
+        log_fd: Option<&ParcelFileDescriptor>,
+    ) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let config = File::open(config_path)
+            .map_err(Error::from)
+            .and_then(|file| VmConfig::load(&file, &self.base_config))
+            .map_err(|e| {
+                error!("Failed to load VM config from {}: {:?}", config_path, e);
+                StatusCode::BAD_VALUE
+            })?;
+        self.start_vm(&config.safe_mode(), log_fd, None, None)
     }
 
     /// Get a list of all currently running VMs. This method is only intended for debug purposes,
@@ -103,11 +531,169 @@ impl IVirtManager for VirtManager {
                 requesterSid: vm.requester_sid.clone(),
                 requesterPid: vm.requester_debug_pid,
                 running: vm.running(),
+                monitorStalled: vm.monitor_stalled(),
+                runtimeDir: vm.runtime_dir.to_string_lossy().into_owned(),
             })
             .collect();
         Ok(cids)
     }
 
+    /// Get the optional features enabled for the running VM with the given CID. This method is only
+    /// intended for debug purposes, and as such is only permitted from the shell user.
+    fn debugGetEnabledFeatures(&self, cid: i32) -> binder::Result<Vec<String>> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let state = &*self.state.lock().unwrap();
+        let vm = state
+            .vms()
+            .into_iter()
+            .find(|vm| vm.cid as i32 == cid && vm.running())
+            .ok_or(StatusCode::NAME_NOT_FOUND)?;
+        Ok(vm.config.enabled_features().into_iter().map(str::to_owned).collect())
+    }
+
+    /// List the vsock connections the Virt Manager has open on behalf of the running VM with the
+    /// given CID. This method is only intended for debug purposes, and as such is only permitted
+    /// from the shell user.
+    fn debugListVsockConnections(&self, cid: i32) -> binder::Result<Vec<VsockConnInfo>> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let state = &*self.state.lock().unwrap();
+        let vm = state
+            .vms()
+            .into_iter()
+            .find(|vm| vm.cid as i32 == cid && vm.running())
+            .ok_or(StatusCode::NAME_NOT_FOUND)?;
+        let connections = vm
+            .vsock_connections
+            .list()
+            .into_iter()
+            .map(|connection| VsockConnInfo {
+                kind: connection.kind.name().to_owned(),
+                outgoing: connection.outgoing,
+                peerCid: connection.cid as i32,
+                port: connection.port as i32,
+                ageMillis: connection.opened.elapsed().as_millis() as i64,
+            })
+            .collect();
+        Ok(connections)
+    }
+
+    /// Get the command line crosvm was run with for the running VM with the given CID. This method
+    /// is only intended for debug purposes, and as such is only permitted from the shell user.
+    fn debugGetCrosvmCommand(&self, cid: i32) -> binder::Result<Vec<String>> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let state = &*self.state.lock().unwrap();
+        let vm = state
+            .vms()
+            .into_iter()
+            .find(|vm| vm.cid as i32 == cid && vm.running())
+            .ok_or(StatusCode::NAME_NOT_FOUND)?;
+        Ok(vm.command_line.clone())
+    }
+
+    /// Export everything needed to debug the running VM with the given CID as a tar archive, for
+    /// bug reports. This method is only intended for debug purposes, and as such is only permitted
+    /// from the shell user.
+    fn debugExportDiagnostics(&self, cid: i32) -> binder::Result<ParcelFileDescriptor> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let vm = self
+            .state
+            .lock()
+            .unwrap()
+            .vms()
+            .into_iter()
+            .find(|vm| vm.cid as i32 == cid && vm.running())
+            .ok_or(StatusCode::NAME_NOT_FOUND)?;
+        let file = vm.export_diagnostics().map_err(|e| {
+            error!("Failed to export diagnostics of VM with CID {}: {:?}", cid, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(ParcelFileDescriptor::new(file))
+    }
+
+    /// Get the current resource usage of all running VMs. This method is only intended for debug
+    /// purposes, and as such is only permitted from the shell user.
+    fn debugGetAllStats(&self) -> binder::Result<Vec<VmStats>> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let vms = self.state.lock().unwrap().vms();
+        // The VM may exit between checking whether it is running and reading its usage, in which
+        // case reading fails and it is skipped.
+        let stats = vms
+            .into_iter()
+            .filter(|vm| vm.running())
+            .filter_map(|vm| {
+                let usage = vm.current_usage().ok()?;
+                Some(VmStats {
+                    cid: vm.cid as i32,
+                    uptimeMillis: usage.uptime.as_millis() as i64,
+                    rssKib: usage.rss_kib as i64,
+                    cpuTimeMillis: usage.cpu_time.as_millis() as i64,
+                })
+            })
+            .collect();
+        Ok(stats)
+    }
+
+    /// Pause all running VMs in the given group, or all running VMs if no group is given, carrying
+    /// on if some fail. Returns how many were paused. This method is only intended for debug
+    /// purposes, and as such is only permitted from the shell user.
+    fn debugPauseGroup(&self, group: Option<&str>) -> binder::Result<i32> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let vms = self.state.lock().unwrap().vms();
+        Ok(pause_group(&vms, group) as i32)
+    }
+
+    /// Resume all paused VMs in the given group, or all paused VMs if no group is given, carrying
+    /// on if some fail. Returns how many were resumed. This method is only intended for debug
+    /// purposes, and as such is only permitted from the shell user.
+    fn debugResumeGroup(&self, group: Option<&str>) -> binder::Result<i32> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let vms = self.state.lock().unwrap().vms();
+        Ok(resume_group(&vms, group) as i32)
+    }
+
+    /// Kill the crosvm process of the running VM with the given CID with the given signal, so
+    /// that it is handled as a crash. This method is only intended for debug purposes, and as such
+    /// is only permitted from the shell user.
+    fn debugSimulateCrash(&self, cid: i32, signal: i32) -> binder::Result<()> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let vm = self
+            .state
+            .lock()
+            .unwrap()
+            .vms()
+            .into_iter()
+            .find(|vm| vm.cid as i32 == cid && vm.running())
+            .ok_or(StatusCode::NAME_NOT_FOUND)?;
+        vm.simulate_crash(signal).map_err(|e| {
+            error!("Failed to simulate crash of VM with CID {}: {:?}", cid, e);
+            StatusCode::BAD_VALUE.into()
+        })
+    }
+
     /// Hold a strong reference to a VM in Virt Manager. This method is only intended for debug
     /// purposes, and as such is only permitted from the shell user.
     fn debugHoldVmRef(&self, vmref: &Strong<dyn IVirtualMachine>) -> binder::Result<()> {
@@ -131,6 +717,44 @@ impl IVirtManager for VirtManager {
         let state = &mut *self.state.lock().unwrap();
         Ok(state.debug_drop_vm(cid))
     }
+
+    /// Remove VMs which no longer exist from the list of VMs, returning how many were removed. This
+    /// method is only intended for debug purposes, and as such is only permitted from the shell
+    /// user.
+    fn debugTriggerGc(&self) -> binder::Result<i32> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let state = &mut *self.state.lock().unwrap();
+        let reclaimed = state.collect_garbage();
+        debug!("Garbage collected {} VM references", reclaimed);
+        Ok(reclaimed as i32)
+    }
+
+    /// Release every CID which has been reserved but not used by a VM, whoever reserved it,
+    /// returning how many were released. This method is only intended for debug purposes, and as
+    /// such is only permitted from the shell user.
+    fn debugReleaseCidReservations(&self) -> binder::Result<i32> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let released = self.state.lock().unwrap().release_all_reservations();
+        debug!("Released {} CID reservations", released);
+        Ok(released as i32)
+    }
+
+    /// Get reference counts for all VMs which are still referenced. This method is only intended
+    /// for debug purposes, and as such is only permitted from the shell user.
+    fn debugGetRefCounts(&self) -> binder::Result<Vec<VmRefInfo>> {
+        if !debug_access_allowed() {
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let state = &*self.state.lock().unwrap();
+        Ok(state.debug_ref_counts())
+    }
 }
 
 /// Check whether the caller of the current Binder method is allowed to call debug methods.
@@ -160,10 +784,96 @@ impl IVirtualMachine for VirtualMachine {
         Ok(self.instance.cid as i32)
     }
 
+    fn getStartTimestampMillis(&self) -> binder::Result<i64> {
+        Ok(epoch_millis(self.instance.start_time))
+    }
+
+    fn getConfigWarnings(&self) -> binder::Result<Vec<String>> {
+        Ok(self.instance.config.warnings().iter().map(ToString::to_string).collect())
+    }
+
     fn isRunning(&self) -> binder::Result<bool> {
         Ok(self.instance.running())
     }
 
+    fn pingVm(&self) -> binder::Result<bool> {
+        Ok(self.instance.ping())
+    }
+
+    fn resizeDisk(&self, disk_index: i32, new_size_mib: i64) -> binder::Result<i64> {
+        if disk_index < 0 || new_size_mib <= 0 {
+            return Err(StatusCode::BAD_VALUE.into());
+        }
+        let new_size = (new_size_mib as u64).checked_mul(MIB).ok_or(StatusCode::BAD_VALUE)?;
+        let size = self.instance.resize_disk(disk_index as usize, new_size).map_err(|e| {
+            error!("Failed to resize disk {}: {:?}", disk_index, e);
+            StatusCode::BAD_VALUE
+        })?;
+        Ok((size / MIB) as i64)
+    }
+
+    fn adjustMemory(&self, balloon_mib: i64, duration_millis: i64) -> binder::Result<()> {
+        if balloon_mib < 0 || duration_millis < 0 {
+            return Err(StatusCode::BAD_VALUE.into());
+        }
+        let duration = Duration::from_millis(duration_millis as u64);
+        self.instance.adjust_memory(balloon_mib as u64, duration).map_err(|e| {
+            error!("Failed to adjust memory of VM with CID {}: {:?}", self.instance.cid, e);
+            StatusCode::INVALID_OPERATION.into()
+        })
+    }
+
+    fn getMemoryBalloonSize(&self) -> binder::Result<i64> {
+        Ok(self.instance.balloon_size_mib() as i64)
+    }
+
+    fn pause(&self) -> binder::Result<()> {
+        self.instance.pause().map_err(|e| {
+            error!("Failed to pause VM with CID {}: {:?}", self.instance.cid, e);
+            StatusCode::INVALID_OPERATION.into()
+        })
+    }
+
+    fn resume(&self) -> binder::Result<()> {
+        self.instance.resume().map_err(|e| {
+            error!("Failed to resume VM with CID {}: {:?}", self.instance.cid, e);
+            StatusCode::INVALID_OPERATION.into()
+        })
+    }
+
+    fn getRuntimeDir(&self) -> binder::Result<String> {
+        if !self.instance.running() {
+            error!(
+                "Runtime directory requested for VM with CID {} which has stopped",
+                self.instance.cid
+            );
+            return Err(StatusCode::INVALID_OPERATION.into());
+        }
+        Ok(self.instance.runtime_dir.to_string_lossy().into_owned())
+    }
+
+    fn openPayloadOutput(&self) -> binder::Result<ParcelFileDescriptor> {
+        if !self.instance.running() {
+            error!(
+                "Payload output requested for VM with CID {} which has stopped",
+                self.instance.cid
+            );
+            return Err(StatusCode::INVALID_OPERATION.into());
+        }
+        let file = self.instance.open_payload_output().map_err(|e| {
+            error!("Failed to open payload output of VM with CID {}: {:?}", self.instance.cid, e);
+            StatusCode::INVALID_OPERATION
+        })?;
+        Ok(ParcelFileDescriptor::new(file))
+    }
+
+    fn getGuestKernelVersion(&self) -> binder::Result<String> {
+        self.instance.guest_kernel_version().map_err(|e| {
+            error!("Failed to get kernel version of VM with CID {}: {:?}", self.instance.cid, e);
+            StatusCode::INVALID_OPERATION.into()
+        })
+    }
+
     fn registerCallback(
         &self,
         callback: &Strong<dyn IVirtualMachineCallback>,
@@ -177,29 +887,283 @@ impl IVirtualMachine for VirtualMachine {
 impl Drop for VirtualMachine {
     fn drop(&mut self) {
         debug!("Dropping {:?}", self);
-        self.instance.kill();
+        // Stopping the VM cleanly may take some time, so don't block the Binder thread on it.
+        let instance = self.instance.clone();
+        thread::spawn(move || instance.stop());
     }
 }
 
+/// Apply the given action to each of the given VMs, carrying on if it fails for some, and return
+/// how many it succeeded for. Failures are logged along with the name of the action.
+fn apply_to_each<V>(
+    vms: impl IntoIterator<Item = V>,
+    action_name: &str,
+    action: impl Fn(&V) -> Result<(), Error>,
+) -> usize {
+    let mut succeeded = 0;
+    for vm in vms {
+        match action(&vm) {
+            Ok(()) => succeeded += 1,
+            Err(e) => error!("Failed to {} VM: {:?}", action_name, e),
+        }
+    }
+    succeeded
+}
+
+/// Pause those of the given VMs which are running and in the given group, or all running VMs if no
+/// group is given, returning how many were paused.
+fn pause_group(vms: &[Arc<VmInstance>], group: Option<&str>) -> usize {
+    let running = vms.iter().filter(|vm| vm.lifecycle() == Lifecycle::Running);
+    let in_group = running.filter(|vm| group.is_none() || vm.config.group.as_deref() == group);
+    apply_to_each(in_group, "pause", |vm| {
+        vm.pause().with_context(|| format!("VM with CID {}", vm.cid))
+    })
+}
+
+/// Resume those of the given VMs which are paused and in the given group, or all paused VMs if no
+/// group is given, returning how many were resumed.
+fn resume_group(vms: &[Arc<VmInstance>], group: Option<&str>) -> usize {
+    let paused = vms.iter().filter(|vm| vm.lifecycle() == Lifecycle::Paused);
+    let in_group = paused.filter(|vm| group.is_none() || vm.config.group.as_deref() == group);
+    apply_to_each(in_group, "resume", |vm| {
+        vm.resume().with_context(|| format!("VM with CID {}", vm.cid))
+    })
+}
+
 /// A set of Binders to be called back in response to various events on the VM, such as when it
 /// dies.
-#[derive(Debug, Default)]
-pub struct VirtualMachineCallbacks(Mutex<Vec<Strong<dyn IVirtualMachineCallback>>>);
+///
+/// Callbacks are delivered via a `CallbackQueue` rather than directly, so that the thread reporting
+/// the event is never blocked by a slow client. Events are delivered in the order in which they
+/// happened, and each event to the callbacks in the order in which they were registered.
+#[derive(Debug)]
+pub struct VirtualMachineCallbacks {
+    callbacks: Arc<Mutex<Listeners<Strong<dyn IVirtualMachineCallback>>>>,
+    queue: CallbackQueue,
+    /// Events which have yet to be delivered, in the order in which they happened. Only one job
+    /// delivers them at a time, so that they are delivered in order even though the queue has
+    /// several workers.
+    pending_events: Arc<Mutex<PendingEvents<VmEvent>>>,
+    /// The service's counts of failures across all VMs, to which crashes of this VM are added.
+    health: Arc<Mutex<HealthTracker>>,
+}
 
 impl VirtualMachineCallbacks {
+    /// Create an empty set of callbacks which will be delivered via the given queue, along with
+    /// the health tracker to record crashes in.
+    pub fn new(queue: CallbackQueue, health: Arc<Mutex<HealthTracker>>) -> VirtualMachineCallbacks {
+        VirtualMachineCallbacks {
+            callbacks: Default::default(),
+            queue,
+            pending_events: Default::default(),
+            health,
+        }
+    }
+
+    /// Record that the VM crashed after running for the given time, for the service's health
+    /// summary. This doesn't call any callbacks.
+    pub fn record_crash(&self, uptime: Duration) {
+        self.health.lock().unwrap().record_crash(uptime, Instant::now());
+    }
+
+    /// Call all registered callbacks to say that the VM has changed to the given state.
+    pub fn callback_on_state_changed(&self, cid: Cid, state: Lifecycle) {
+        let state = match state {
+            Lifecycle::Starting => VirtualMachineState::STARTING,
+            Lifecycle::Running => VirtualMachineState::RUNNING,
+            Lifecycle::Paused => VirtualMachineState::PAUSED,
+            Lifecycle::Stopping(_) => VirtualMachineState::STOPPING,
+            Lifecycle::Stopped(_) => VirtualMachineState::STOPPED,
+        };
+        self.post(cid, VmEvent::StateChanged(state));
+    }
+
+    /// Call all registered callbacks to say that the VM has stopped, with a summary of the
+    /// resources it used and any virtio devices which its guest failed to negotiate.
+    pub fn callback_on_stopped(&self, cid: Cid, usage: &UsageSummary, virtio_failures: &[String]) {
+        let summary = VmStopSummary {
+            uptimeMillis: usage.uptime.as_millis() as i64,
+            peakRssKib: usage.peak_rss_kib as i64,
+            cpuTimeMillis: usage.cpu_time.as_millis() as i64,
+            stopReason: match usage.stop_reason {
+                StopReason::Exited => VmStopReason::EXITED,
+                StopReason::Requested => VmStopReason::REQUESTED,
+                StopReason::HostMemoryPressure => VmStopReason::HOST_MEMORY_PRESSURE,
+                StopReason::DependencyTimeout => VmStopReason::DEPENDENCY_TIMEOUT,
+            },
+            exitReason: usage.exit_reason.clone(),
+            virtioNegotiationFailures: virtio_failures.to_vec(),
+        };
+        self.post(cid, VmEvent::Stopped(summary));
+    }
+
     /// Call all registered callbacks to say that the VM has died.
     pub fn callback_on_died(&self, cid: Cid) {
-        let callbacks = &*self.0.lock().unwrap();
-        for callback in callbacks {
-            if let Err(e) = callback.onDied(cid as i32) {
-                error!("Error calling callback: {}", e);
-            }
+        self.post(cid, VmEvent::Died);
+    }
+
+    /// Queue the given event to be delivered to all registered callbacks.
+    fn post(&self, cid: Cid, event: VmEvent) {
+        let description = format!("{} for CID {}", event.name(), cid);
+        if !self.pending_events.lock().unwrap().push(event) {
+            // The job already delivering this VM's events will deliver it too, so a slow client
+            // only holds up one worker.
+            return;
         }
+        let callbacks = self.callbacks.clone();
+        let job = DeliveryJob(Some(self.pending_events.clone()));
+        self.queue.post(description, move || {
+            let pending_events = job.start();
+            deliver_pending(&callbacks, &pending_events, |callback, event| {
+                event.deliver(cid, callback)
+            });
+        });
     }
 
     /// Add a new callback to the set.
     fn add(&self, callback: Strong<dyn IVirtualMachineCallback>) {
-        self.0.lock().unwrap().push(callback);
+        self.callbacks.lock().unwrap().add(callback);
+    }
+}
+
+/// An event in the life of a VM, to be delivered to its callbacks.
+#[derive(Clone, Debug)]
+enum VmEvent {
+    StateChanged(VirtualMachineState),
+    Stopped(VmStopSummary),
+    Died,
+}
+
+impl VmEvent {
+    /// Get the name of the callback method by which the event is delivered.
+    fn name(&self) -> &'static str {
+        match self {
+            VmEvent::StateChanged(_) => "onStateChanged",
+            VmEvent::Stopped(_) => "onStopped",
+            VmEvent::Died => "onDied",
+        }
+    }
+
+    /// Deliver the event about the VM with the given CID to the given callback.
+    fn deliver(
+        &self,
+        cid: Cid,
+        callback: &Strong<dyn IVirtualMachineCallback>,
+    ) -> binder::Result<()> {
+        match self {
+            VmEvent::StateChanged(state) => callback.onStateChanged(cid as i32, *state),
+            VmEvent::Stopped(summary) => callback.onStopped(cid as i32, summary),
+            VmEvent::Died => callback.onDied(cid as i32),
+        }
+    }
+}
+
+/// Events waiting to be delivered, along with whether a job to deliver them has been posted and
+/// hasn't yet finished.
+#[derive(Debug)]
+struct PendingEvents<E> {
+    events: VecDeque<E>,
+    delivering: bool,
+}
+
+impl<E> Default for PendingEvents<E> {
+    fn default() -> Self {
+        PendingEvents { events: VecDeque::new(), delivering: false }
+    }
+}
+
+impl<E> PendingEvents<E> {
+    /// Add an event after those already pending, returning whether a job needs to be posted to
+    /// deliver it because none is already delivering events.
+    fn push(&mut self, event: E) -> bool {
+        self.events.push_back(event);
+        !std::mem::replace(&mut self.delivering, true)
+    }
+
+    /// Take the oldest pending event. If there are none then delivery has finished, so the next
+    /// event needs a new job.
+    fn pop(&mut self) -> Option<E> {
+        let event = self.events.pop_front();
+        if event.is_none() {
+            self.delivering = false;
+        }
+        event
+    }
+}
+
+/// The pending events for a job which has been posted to deliver them. If the queue drops the job
+/// without running it then delivery is marked as finished, so that the next event posts another.
+struct DeliveryJob<E>(Option<Arc<Mutex<PendingEvents<E>>>>);
+
+impl<E> DeliveryJob<E> {
+    /// Get the pending events for the job to deliver, now that it is running.
+    fn start(mut self) -> Arc<Mutex<PendingEvents<E>>> {
+        self.0.take().unwrap()
+    }
+}
+
+impl<E> Drop for DeliveryJob<E> {
+    fn drop(&mut self) {
+        if let Some(pending_events) = self.0.take() {
+            pending_events.lock().unwrap().delivering = false;
+        }
+    }
+}
+
+/// Listeners for events, kept in the order in which they were registered.
+#[derive(Debug)]
+struct Listeners<L> {
+    /// The listeners along with the IDs they were registered with, in order.
+    listeners: Vec<(u64, L)>,
+    next_id: u64,
+}
+
+impl<L> Default for Listeners<L> {
+    fn default() -> Self {
+        Listeners { listeners: vec![], next_id: 0 }
+    }
+}
+
+impl<L: Clone> Listeners<L> {
+    /// Register a new listener, after all those already registered.
+    fn add(&mut self, listener: L) {
+        self.listeners.push((self.next_id, listener));
+        self.next_id += 1;
+    }
+
+    /// Get the registered listeners, in order.
+    fn snapshot(&self) -> Vec<(u64, L)> {
+        self.listeners.clone()
+    }
+
+    /// Unregister the listener with the given ID.
+    fn remove(&mut self, id: u64) {
+        self.listeners.retain(|&(listener_id, _)| listener_id != id);
+    }
+}
+
+/// Deliver the pending events, oldest first, by calling `call` for each of the given listeners in
+/// the order in which they were registered, until none are left. An error from one listener doesn't
+/// stop the event being delivered to the others, and listeners whose client has died are removed.
+fn deliver_pending<L: Clone, E>(
+    listeners: &Mutex<Listeners<L>>,
+    pending_events: &Mutex<PendingEvents<E>>,
+    call: impl Fn(&L, &E) -> binder::Result<()>,
+) {
+    // Don't hold either lock while calling clients.
+    let next_event = || pending_events.lock().unwrap().pop();
+    while let Some(event) = next_event() {
+        let snapshot = listeners.lock().unwrap().snapshot();
+        for (id, listener) in snapshot {
+            match call(&listener, &event) {
+                Ok(()) => {}
+                Err(e) if e.transaction_error() == StatusCode::DEAD_OBJECT => {
+                    warn!("Removing callback whose client has died");
+                    listeners.lock().unwrap().remove(id);
+                }
+                Err(e) => error!("Error calling callback: {}", e),
+            }
+        }
     }
 }
 
@@ -212,12 +1176,20 @@ struct State {
     /// The VMs which have been started. When VMs are started a weak reference is added to this list
     /// while a strong reference is returned to the caller over Binder. Once all copies of the
     /// Binder client are dropped the weak reference here will become invalid, and will be removed
-    /// from the list opportunistically the next time `add_vm` is called.
+    /// from the list opportunistically the next time `add_vm` is called, or by `collect_garbage`.
     vms: Vec<Weak<VmInstance>>,
 
+    /// The handle returned to clients for each VM, by CID, so that a VM which is reused is shared
+    /// through the same handle rather than each client having its own.
+    handles: BTreeMap<Cid, binder::Weak<dyn IVirtualMachine>>,
+
     /// Vector of strong VM references held on behalf of users that cannot hold them themselves.
     /// This is only used for debugging purposes.
     debug_held_vms: Vec<Strong<dyn IVirtualMachine>>,
+
+    /// CIDs which have been reserved for VMs to be started on later, along with the UID which
+    /// reserved each. A CID stops being reserved once a VM is started on it.
+    reserved_cids: BTreeMap<Cid, u32>,
 }
 
 impl State {
@@ -227,13 +1199,31 @@ impl State {
         self.vms.iter().filter_map(Weak::upgrade).collect()
     }
 
-    /// Add a new VM to the list.
-    fn add_vm(&mut self, vm: Weak<VmInstance>) {
-        // Garbage collect any entries from the stored list which no longer exist.
+    /// Remove any entries from the list of VMs which no longer exist, returning how many were
+    /// removed.
+    fn collect_garbage(&mut self) -> usize {
+        let before = self.vms.len();
         self.vms.retain(|vm| vm.strong_count() > 0);
+        let live: Vec<Cid> = self.vms().iter().map(|vm| vm.cid).collect();
+        self.handles.retain(|cid, _| live.contains(cid));
+        before - self.vms.len()
+    }
+
+    /// Add a new VM to the list, returning the handle for clients to refer to it by.
+    fn add_vm(&mut self, vm: Arc<VmInstance>) -> Strong<dyn IVirtualMachine> {
+        self.collect_garbage();
 
         // Actually add the new VM.
-        self.vms.push(vm);
+        self.vms.push(Arc::downgrade(&vm));
+        let cid = vm.cid;
+        let handle = VirtualMachine::create(vm);
+        self.handles.insert(cid, Strong::downgrade(&handle));
+        handle
+    }
+
+    /// Get the handle to the VM with the given CID, if any client still holds it.
+    fn handle(&self, cid: Cid) -> Option<Strong<dyn IVirtualMachine>> {
+        self.handles.get(&cid)?.upgrade().ok()
     }
 
     /// Store a strong VM reference.
@@ -247,6 +1237,23 @@ impl State {
         Some(self.debug_held_vms.swap_remove(pos))
     }
 
+    /// Get the reference counts of all VMs which still have strong references to them.
+    fn debug_ref_counts(&self) -> Vec<VmRefInfo> {
+        self.vms()
+            .into_iter()
+            .map(|vm| {
+                let cid = vm.cid as i32;
+                VmRefInfo {
+                    cid,
+                    // Don't count the reference we have just created by upgrading the weak one.
+                    strongCount: Arc::strong_count(&vm) as i32 - 1,
+                    weakCount: Arc::weak_count(&vm) as i32,
+                    debugHeld: self.debug_held_vms.iter().any(|held| held.getCid() == Ok(cid)),
+                }
+            })
+            .collect()
+    }
+
     /// Get the next available CID, or an error if we have run out.
     fn allocate_cid(&mut self) -> binder::Result<Cid> {
         // TODO(qwandor): keep track of which CIDs are currently in use so that we can reuse them.
@@ -254,31 +1261,456 @@ impl State {
         self.next_cid = self.next_cid.checked_add(1).ok_or(StatusCode::UNKNOWN_ERROR)?;
         Ok(cid)
     }
+
+    /// Return a CID which was allocated but never used by a VM, so that it can be allocated again.
+    /// This only has an effect if it was the most recently allocated CID.
+    fn release_cid(&mut self, cid: Cid) {
+        if cid.checked_add(1) == Some(self.next_cid) {
+            self.next_cid = cid;
+        }
+    }
+
+    /// Allocate a CID and reserve it for the given UID to start a VM on later.
+    fn reserve_cid(&mut self, owner: u32) -> binder::Result<Cid> {
+        let cid = self.allocate_cid()?;
+        self.reserved_cids.insert(cid, owner);
+        Ok(cid)
+    }
+
+    /// Stop the given CID being reserved, so that the given UID can start a VM on it. Fails if it
+    /// isn't reserved, or is reserved by another UID.
+    fn take_reservation(&mut self, cid: Cid, owner: u32) -> Result<(), StatusCode> {
+        match self.reserved_cids.get(&cid) {
+            None => {
+                error!("CID {} isn't reserved", cid);
+                Err(StatusCode::NAME_NOT_FOUND)
+            }
+            Some(&reserved_by) if reserved_by != owner => {
+                error!("UID {} tried to use CID {} reserved by UID {}", owner, cid, reserved_by);
+                Err(StatusCode::PERMISSION_DENIED)
+            }
+            Some(_) => {
+                self.reserved_cids.remove(&cid);
+                Ok(())
+            }
+        }
+    }
+
+    /// Give back a CID which was allocated or reserved for a VM which then failed to start. If it
+    /// was reserved by the given UID then it is reserved for them again, otherwise it is released.
+    fn unallocate_cid(&mut self, cid: Cid, reserved_by: Option<u32>) {
+        match reserved_by {
+            Some(owner) => {
+                self.reserved_cids.insert(cid, owner);
+            }
+            None => self.release_cid(cid),
+        }
+    }
+
+    /// Release the given CID, which must have been reserved by the given UID.
+    fn release_reservation(&mut self, cid: Cid, owner: u32) -> Result<(), StatusCode> {
+        self.take_reservation(cid, owner)?;
+        self.release_cid(cid);
+        Ok(())
+    }
+
+    /// Release every reserved CID, returning how many there were.
+    fn release_all_reservations(&mut self) -> usize {
+        let reserved = std::mem::take(&mut self.reserved_cids);
+        for &cid in reserved.keys().rev() {
+            self.release_cid(cid);
+        }
+        reserved.len()
+    }
 }
 
 impl Default for State {
     fn default() -> Self {
-        State { next_cid: FIRST_GUEST_CID, vms: vec![], debug_held_vms: vec![] }
-    }
-}
-
-/// Start a new VM instance from the given VM config file. This assumes the VM is not already
-/// running.
-fn start_vm(
-    config_file: &File,
-    cid: Cid,
-    log_fd: Option<File>,
-    requester_uid: u32,
-    requester_sid: String,
-    requester_debug_pid: i32,
-) -> binder::Result<Arc<VmInstance>> {
-    let config = VmConfig::load(config_file).map_err(|e| {
-        error!("Failed to load VM config from {:?}: {:?}", config_file, e);
-        StatusCode::BAD_VALUE
-    })?;
-    Ok(VmInstance::start(&config, cid, log_fd, requester_uid, requester_sid, requester_debug_pid)
-        .map_err(|e| {
-        error!("Failed to start VM from {:?}: {:?}", config_file, e);
-        StatusCode::UNKNOWN_ERROR
-    })?)
+        State {
+            next_cid: FIRST_GUEST_CID,
+            vms: vec![],
+            handles: BTreeMap::new(),
+            debug_held_vms: vec![],
+            reserved_cids: BTreeMap::new(),
+        }
+    }
+}
+
+/// Convert a deadline given over Binder in milliseconds from now to an `Instant`. Zero or negative
+/// values mean there is no deadline.
+fn deadline_from_millis(deadline_millis: i64) -> Option<Instant> {
+    if deadline_millis > 0 {
+        Some(Instant::now() + Duration::from_millis(deadline_millis as u64))
+    } else {
+        None
+    }
+}
+
+/// Return a `ERROR_DEADLINE_EXCEEDED` error if the given deadline has passed.
+fn check_deadline(deadline: Option<Instant>) -> binder::Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() > deadline => {
+            error!("Deadline exceeded while starting VM");
+            Err(Status::new_service_specific_error(ERROR_DEADLINE_EXCEEDED, None))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Convert the given wall-clock time to milliseconds since the Unix epoch, which is negative for
+/// times before it.
+fn epoch_millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DiskImage;
+    use crate::crosvm::tests::{kernel_config, kill_stub_vm, start_stub_vm, stub_crosvm, test_dir};
+    use anyhow::anyhow;
+
+    #[test]
+    fn collect_garbage_removes_dead_vms() {
+        let mut state = State::default();
+        state.vms.push(Weak::new());
+        state.vms.push(Weak::new());
+        assert_eq!(state.collect_garbage(), 2);
+        assert!(state.vms.is_empty());
+        assert!(state.vms().is_empty());
+        assert_eq!(state.collect_garbage(), 0);
+    }
+
+    #[test]
+    fn reserved_cids_are_only_usable_by_their_owners() {
+        let mut state = State::default();
+        let first = state.reserve_cid(10001).unwrap();
+        let second = state.reserve_cid(10001).unwrap();
+        let other = state.reserve_cid(10002).unwrap();
+        assert_eq!(state.allocate_cid().unwrap(), other + 1);
+
+        // Starting a VM on a reserved CID takes the reservation, and it is reserved again if the
+        // VM fails to start.
+        assert_eq!(state.take_reservation(first, 10002), Err(StatusCode::PERMISSION_DENIED));
+        assert_eq!(state.take_reservation(first, 10001), Ok(()));
+        state.unallocate_cid(first, Some(10001));
+        assert_eq!(state.take_reservation(first, 10001), Ok(()));
+        assert_eq!(state.take_reservation(first, 10001), Err(StatusCode::NAME_NOT_FOUND));
+
+        assert_eq!(state.release_reservation(second, 10002), Err(StatusCode::PERMISSION_DENIED));
+        assert_eq!(state.release_reservation(second, 10001), Ok(()));
+        assert_eq!(state.release_reservation(second, 10001), Err(StatusCode::NAME_NOT_FOUND));
+
+        // Leftover reservations are released whoever made them.
+        assert_eq!(state.release_all_reservations(), 1);
+        assert_eq!(state.release_all_reservations(), 0);
+        assert_eq!(state.take_reservation(other, 10002), Err(StatusCode::NAME_NOT_FOUND));
+    }
+
+    #[test]
+    fn events_reach_listeners_in_order_despite_failures() {
+        let listeners = Mutex::new(Listeners::default());
+        for name in &["first", "failing", "dead", "last"] {
+            listeners.lock().unwrap().add(*name);
+        }
+        let pending_events = Mutex::new(PendingEvents::default());
+        assert!(pending_events.lock().unwrap().push(1));
+        assert!(!pending_events.lock().unwrap().push(2));
+        let calls = Mutex::new(vec![]);
+
+        deliver_pending(&listeners, &pending_events, |&listener, &event| match listener {
+            "failing" => Err(StatusCode::UNKNOWN_ERROR.into()),
+            "dead" => Err(StatusCode::DEAD_OBJECT.into()),
+            _ => {
+                calls.lock().unwrap().push((listener, event));
+                Ok(())
+            }
+        });
+
+        assert_eq!(*calls.lock().unwrap(), [("first", 1), ("last", 1), ("first", 2), ("last", 2)]);
+        let remaining: Vec<&str> =
+            listeners.lock().unwrap().snapshot().into_iter().map(|(_, name)| name).collect();
+        assert_eq!(remaining, ["first", "failing", "last"]);
+        assert!(pending_events.lock().unwrap().events.is_empty());
+        // Delivery has finished, so the next event needs a new job.
+        assert!(pending_events.lock().unwrap().push(3));
+    }
+
+    #[test]
+    fn hung_client_does_not_hold_up_other_vms() {
+        use android_system_virtmanager::aidl::android::system::virtmanager::IVirtualMachineCallback::BnVirtualMachineCallback;
+        use std::sync::mpsc::{channel, Receiver, Sender};
+
+        /// A callback which never returns from any call, until the sender is dropped.
+        struct HungCallback(Mutex<Receiver<()>>);
+        /// A callback which sends the CID of each VM it is told has died.
+        struct DiedCallback(Mutex<Sender<i32>>);
+
+        impl Interface for HungCallback {}
+        impl Interface for DiedCallback {}
+
+        impl IVirtualMachineCallback for HungCallback {
+            fn onDied(&self, _cid: i32) -> binder::Result<()> {
+                let _ = self.0.lock().unwrap().recv();
+                Ok(())
+            }
+            fn onStopped(&self, _cid: i32, _summary: &VmStopSummary) -> binder::Result<()> {
+                let _ = self.0.lock().unwrap().recv();
+                Ok(())
+            }
+            fn onStateChanged(&self, _cid: i32, _state: VirtualMachineState) -> binder::Result<()> {
+                let _ = self.0.lock().unwrap().recv();
+                Ok(())
+            }
+        }
+
+        impl IVirtualMachineCallback for DiedCallback {
+            fn onDied(&self, cid: i32) -> binder::Result<()> {
+                self.0.lock().unwrap().send(cid).unwrap();
+                Ok(())
+            }
+            fn onStopped(&self, _cid: i32, _summary: &VmStopSummary) -> binder::Result<()> {
+                Ok(())
+            }
+            fn onStateChanged(&self, _cid: i32, _state: VirtualMachineState) -> binder::Result<()> {
+                Ok(())
+            }
+        }
+
+        let queue = CallbackQueue::default();
+        let (_release, hang) = channel();
+        let hung = VirtualMachineCallbacks::new(queue.clone(), Default::default());
+        let callback = HungCallback(Mutex::new(hang));
+        hung.add(BnVirtualMachineCallback::new_binder(callback, BinderFeatures::default()));
+        let (died, died_cids) = channel();
+        let other = VirtualMachineCallbacks::new(queue, Default::default());
+        let callback = DiedCallback(Mutex::new(died));
+        other.add(BnVirtualMachineCallback::new_binder(callback, BinderFeatures::default()));
+
+        // More events than the queue has workers, all for the VM whose client is hung.
+        let stopped = Lifecycle::Stopped(StopReason::Requested);
+        for &state in &[Lifecycle::Running, Lifecycle::Paused, Lifecycle::Running, stopped] {
+            hung.callback_on_state_changed(10, state);
+        }
+        hung.callback_on_died(10);
+        other.callback_on_died(11);
+
+        assert_eq!(died_cids.recv_timeout(Duration::from_secs(2)), Ok(11));
+    }
+
+    #[test]
+    fn group_action_continues_past_failures() {
+        let vms = vec![("vm1", true), ("vm2", false), ("vm3", true)];
+        let paused = Mutex::new(vec![]);
+
+        let count = apply_to_each(&vms, "pause", |&&(name, pausable)| {
+            if !pausable {
+                return Err(anyhow!("{} isn't running", name));
+            }
+            paused.lock().unwrap().push(name);
+            Ok(())
+        });
+
+        assert_eq!(count, 2);
+        assert_eq!(*paused.lock().unwrap(), ["vm1", "vm3"]);
+    }
+
+    #[test]
+    fn groups_of_vms_are_paused_and_resumed() {
+        let dir = test_dir("group_pause");
+        let crosvm = stub_crosvm(&dir, "case \"$1\" in run) exec sleep 10 ;; esac\n");
+        let failing_dir = dir.join("failing");
+        std::fs::create_dir(&failing_dir).unwrap();
+        // A stub crosvm on which every control command fails.
+        let failing =
+            stub_crosvm(&failing_dir, "case \"$1\" in run) exec sleep 10 ;; esac\nexit 1\n");
+        let in_group = |group: &str| VmConfig { group: Some(group.to_owned()), ..kernel_config() };
+        let vms = vec![
+            start_stub_vm(&in_group("media"), &crosvm, 204),
+            start_stub_vm(&in_group("media"), &crosvm, 205),
+            start_stub_vm(&in_group("media"), &failing, 206),
+            start_stub_vm(&in_group("other"), &crosvm, 207),
+        ];
+        let lifecycles = || vms.iter().map(|vm| vm.lifecycle()).collect::<Vec<_>>();
+        let (running, paused) = (Lifecycle::Running, Lifecycle::Paused);
+
+        assert_eq!(pause_group(&vms, Some("media")), 2);
+        assert_eq!(lifecycles(), [paused, paused, running, running]);
+        // VMs which are already paused aren't paused again.
+        assert_eq!(pause_group(&vms, None), 1);
+        assert_eq!(lifecycles(), [paused, paused, running, paused]);
+
+        assert_eq!(resume_group(&vms, Some("other")), 1);
+        assert_eq!(lifecycles(), [paused, paused, running, running]);
+        assert_eq!(resume_group(&vms, None), 2);
+        assert_eq!(lifecycles(), [running; 4]);
+
+        for vm in &vms {
+            kill_stub_vm(vm);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reused_vm_runs_until_every_client_drops_it() {
+        let dir = test_dir("reuse");
+        let crosvm = stub_crosvm(&dir, "exec sleep 10\n");
+        let path = Path::new("/data/local/tmp/vm.json");
+        let config = VmConfig { source_path: Some(path.to_owned()), ..kernel_config() };
+        let instance = start_stub_vm(&config, &crosvm, 208);
+        let mut state = State::default();
+        let first = state.add_vm(instance.clone());
+
+        // Starting the same config again gets the same handle, not a second one of its own.
+        let existing = DuplicatePolicy::ReuseExisting
+            .admit(Some(path), state.vms(), |vm| vm.config.source_path.as_deref())
+            .unwrap()
+            .unwrap();
+        let second = state.handle(existing.cid).unwrap();
+        drop(existing);
+        // The test, the handle and the VM's monitor thread each hold a reference.
+        assert_eq!(Arc::strong_count(&instance), 3);
+
+        drop(second);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(instance.lifecycle(), Lifecycle::Running);
+
+        drop(first);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while instance.running() {
+            assert!(Instant::now() < deadline, "VM still running with no handles");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(state.handle(208).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ping_reports_whether_crosvm_is_alive() {
+        let dir = test_dir("ping");
+        let crosvm = stub_crosvm(&dir, "exec sleep 10\n");
+        let vm = VirtualMachine { instance: start_stub_vm(&kernel_config(), &crosvm, 200) };
+        assert!(vm.pingVm().unwrap());
+
+        vm.instance.kill();
+        // Unlike isRunning, this doesn't wait for the monitor thread to notice that crosvm died.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while vm.pingVm().unwrap() {
+            assert!(Instant::now() < deadline, "crosvm still alive after being killed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        kill_stub_vm(&vm.instance);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ref_counts_follow_handles() {
+        let dir = test_dir("ref_counts");
+        let crosvm = stub_crosvm(&dir, "exec sleep 10\n");
+        let instance = start_stub_vm(&kernel_config(), &crosvm, 201);
+        let mut state = State::default();
+        let handle = state.add_vm(instance);
+
+        // The handle and the VM's monitor thread each hold a reference.
+        let counts = state.debug_ref_counts();
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].cid, 201);
+        assert_eq!(counts[0].strongCount, 2);
+        assert_eq!(counts[0].weakCount, 1);
+        assert!(!counts[0].debugHeld);
+
+        state.debug_hold_vm(handle.clone());
+        assert!(state.debug_ref_counts()[0].debugHeld);
+        let held = state.debug_drop_vm(201).unwrap();
+        assert!(!state.debug_ref_counts()[0].debugHeld);
+        assert!(state.debug_drop_vm(201).is_none());
+
+        // Once the last handle is dropped the VM is stopped, and then nothing refers to it.
+        drop(held);
+        drop(handle);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !state.debug_ref_counts().is_empty() {
+            assert!(Instant::now() < deadline, "VM still referenced with no handles");
+            thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_writable_disks_can_be_grown() {
+        let dir = test_dir("resize_disk");
+        let (commands, data) = (dir.join("commands"), dir.join("data.img"));
+        // A stub crosvm which records disk commands, and resizes the image as crosvm would.
+        let crosvm = stub_crosvm(
+            &dir,
+            &format!(
+                "case \"$1\" in\n\
+                 run) exec sleep 10 ;;\n\
+                 disk) echo \"$@\" >> {}; truncate -s \"$4\" {} ;;\n\
+                 esac\n",
+                commands.display(),
+                data.display()
+            ),
+        );
+        File::create(&data).unwrap().set_len(MIB).unwrap();
+        let disk = |image: &Path, id: &str, writable| DiskImage {
+            id: Some(id.to_owned()),
+            image: image.to_str().unwrap().to_owned(),
+            writable,
+            verity: None,
+            sha256: None,
+        };
+        let config = VmConfig {
+            disks: vec![disk(Path::new("/dev/null"), "root", false), disk(&data, "data", true)],
+            // Boot from the data disk, so that it is attached as the first device.
+            boot_order: vec!["data".to_owned()],
+            ..kernel_config()
+        };
+        let vm = VirtualMachine { instance: start_stub_vm(&config, &crosvm, 202) };
+
+        assert_eq!(vm.resizeDisk(1, 2).unwrap(), 2);
+        let socket = vm.instance.runtime_dir.join("crosvm.sock");
+        let expected = format!("disk resize 0 {} {}\n", 2 * MIB, socket.display());
+        assert_eq!(std::fs::read_to_string(&commands).unwrap(), expected);
+
+        // Read-only disks, shrinking and staying the same size are all refused without asking
+        // crosvm.
+        for &(index, size_mib) in &[(0, 4), (1, 1), (1, 2), (2, 4), (-1, 4), (1, 0)] {
+            let error = vm.resizeDisk(index, size_mib).unwrap_err();
+            assert_eq!(error.transaction_error(), StatusCode::BAD_VALUE);
+        }
+        assert_eq!(std::fs::read_to_string(&commands).unwrap(), expected);
+        assert_eq!(std::fs::metadata(&data).unwrap().len(), 2 * MIB);
+
+        kill_stub_vm(&vm.instance);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn start_timestamp_is_epoch_millis() {
+        assert_eq!(epoch_millis(UNIX_EPOCH + Duration::from_millis(1234)), 1234);
+        assert_eq!(epoch_millis(UNIX_EPOCH - Duration::from_millis(1234)), -1234);
+    }
+
+    #[test]
+    fn start_timestamp_is_when_vm_started() {
+        let dir = test_dir("start_timestamp");
+        let crosvm = stub_crosvm(&dir, "exec sleep 10\n");
+        let before = SystemTime::now();
+        let vm = VirtualMachine { instance: start_stub_vm(&kernel_config(), &crosvm, 203) };
+        let after = SystemTime::now();
+
+        let millis = vm.getStartTimestampMillis().unwrap();
+        assert_eq!(millis, epoch_millis(vm.instance.start_time));
+        assert!(epoch_millis(before) <= millis && millis <= epoch_millis(after));
+        // The timestamp is of the start moment, so doesn't change as the VM runs.
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(vm.getStartTimestampMillis().unwrap(), millis);
+
+        kill_stub_vm(&vm.instance);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
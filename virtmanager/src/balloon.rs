@@ -0,0 +1,143 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Gradual adjustment of a VM's memory balloon, so that large changes don't stall the guest.
+
+use std::time::{Duration, Instant};
+
+/// How often the balloon is moved towards its target while it is being adjusted.
+const STEP_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The furthest the balloon is moved in a single step, in MiB.
+const MAX_STEP_MIB: u64 = 256;
+
+/// The size of a VM's memory balloon, which is moved towards a target in steps.
+#[derive(Debug)]
+pub struct Balloon {
+    /// The size the balloon was last set to, in MiB.
+    size_mib: u64,
+    /// The size the balloon is being moved towards, in MiB.
+    target_mib: u64,
+    /// How far to move the balloon in each step, in MiB.
+    step_mib: u64,
+    /// When the next step is due.
+    next_step: Instant,
+}
+
+impl Default for Balloon {
+    /// Create a balloon for a VM which has just started, and so has an empty balloon.
+    fn default() -> Balloon {
+        Balloon { size_mib: 0, target_mib: 0, step_mib: 0, next_step: Instant::now() }
+    }
+}
+
+impl Balloon {
+    /// Get the size the balloon was last set to, in MiB.
+    pub fn size_mib(&self) -> u64 {
+        self.size_mib
+    }
+
+    /// Start moving the balloon towards the given size, in equal steps spread over the given
+    /// duration so that the target is reached at the end of it. No step is larger than
+    /// `MAX_STEP_MIB`, so large changes may take longer. If the duration is zero then the first
+    /// step is due straight away.
+    pub fn set_target(&mut self, target_mib: u64, duration: Duration, now: Instant) {
+        let distance = if target_mib > self.size_mib {
+            target_mib - self.size_mib
+        } else {
+            self.size_mib - target_mib
+        };
+        let steps = (duration.as_millis() / STEP_INTERVAL.as_millis()).max(1) as u64;
+        self.target_mib = target_mib;
+        self.step_mib = ((distance + steps - 1) / steps).max(1).min(MAX_STEP_MIB);
+        self.next_step = if duration == Duration::from_secs(0) { now } else { now + STEP_INTERVAL };
+    }
+
+    /// If a step is due at the given time, return the size in MiB the balloon should now be set to
+    /// on its way to its target. The balloon's size doesn't change until `set_size` is called once
+    /// it has been set, so if setting it fails then the same step is tried again next time.
+    pub fn step(&mut self, now: Instant) -> Option<u64> {
+        if self.size_mib == self.target_mib || now < self.next_step {
+            return None;
+        }
+        self.next_step = now + STEP_INTERVAL;
+        Some(if self.target_mib > self.size_mib {
+            (self.size_mib + self.step_mib).min(self.target_mib)
+        } else {
+            self.size_mib.saturating_sub(self.step_mib).max(self.target_mib)
+        })
+    }
+
+    /// Record that the balloon has been set to the given size, in MiB.
+    pub fn set_size(&mut self, size_mib: u64) {
+        self.size_mib = size_mib;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Step the balloon every 100 ms from the given time until it has had no steps for a second,
+    /// returning the sizes it was set to along with how long after the start each was.
+    fn run(balloon: &mut Balloon, start: Instant) -> Vec<(u64, Duration)> {
+        let mut steps = vec![];
+        let mut now = start;
+        loop {
+            if let Some(size) = balloon.step(now) {
+                balloon.set_size(size);
+                steps.push((size, now - start));
+            } else if steps.last().map_or(now - start, |&(_, at)| now - start - at)
+                > Duration::from_secs(1)
+            {
+                return steps;
+            }
+            now += Duration::from_millis(100);
+        }
+    }
+
+    #[test]
+    fn target_is_reached_in_steps_over_duration() {
+        let start = Instant::now();
+        let mut balloon = Balloon::default();
+        balloon.set_target(400, Duration::from_secs(2), start);
+
+        let steps = run(&mut balloon, start);
+        let sizes: Vec<u64> = steps.iter().map(|&(size, _)| size).collect();
+        assert_eq!(sizes, [100, 200, 300, 400]);
+        assert_eq!(steps.last().unwrap().1, Duration::from_secs(2));
+        assert_eq!(balloon.size_mib(), 400);
+
+        // Deflating by more than the maximum step takes longer than asked for.
+        let start = start + Duration::from_secs(10);
+        balloon.set_target(0, Duration::from_secs(0), start);
+        let sizes: Vec<u64> = run(&mut balloon, start).iter().map(|&(size, _)| size).collect();
+        assert_eq!(sizes, [144, 0]);
+    }
+
+    #[test]
+    fn failed_step_is_tried_again() {
+        let start = Instant::now();
+        let mut balloon = Balloon::default();
+        balloon.set_target(200, Duration::from_secs(1), start);
+
+        // Setting the balloon fails, so its size isn't changed.
+        assert_eq!(balloon.step(start + STEP_INTERVAL), Some(100));
+        assert_eq!(balloon.size_mib(), 0);
+        assert_eq!(balloon.step(start + STEP_INTERVAL), None);
+        assert_eq!(balloon.step(start + 2 * STEP_INTERVAL), Some(100));
+        balloon.set_size(100);
+        assert_eq!(balloon.step(start + 3 * STEP_INTERVAL), Some(200));
+    }
+}
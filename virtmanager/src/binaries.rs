@@ -0,0 +1,276 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Registry of the crosvm binaries which are available to run VMs.
+
+use anyhow::{anyhow, bail, Context, Error};
+use log::{info, warn};
+use std::collections::BTreeSet;
+use std::env;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The crosvm binary shipped in the APEX, which is always registered.
+const DEFAULT_CROSVM_PATH: &str = "/apex/com.android.virt/bin/crosvm";
+
+/// Environment variable with a colon-separated list of additional crosvm binaries to register, e.g.
+/// while a device is part way through an upgrade.
+const EXTRA_CROSVM_PATHS_VAR: &str = "VIRTMANAGER_EXTRA_CROSVM";
+
+/// The version recorded for the default binary if it can't be probed. This sorts before any real
+/// version.
+const UNKNOWN_VERSION: &str = "unknown";
+
+/// A crosvm binary along with the version it reported.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CrosvmBinary {
+    /// The path to the binary.
+    pub path: PathBuf,
+    /// The version reported by `crosvm version`.
+    pub version: String,
+    /// The flags listed by `crosvm run --help`, or `None` if they couldn't be probed, in which
+    /// case every flag is assumed to be supported.
+    pub run_flags: Option<BTreeSet<String>>,
+}
+
+impl CrosvmBinary {
+    /// Probe the binary at the given path for its version and the flags it supports.
+    fn probe(path: PathBuf) -> Result<CrosvmBinary, Error> {
+        let version = probe_version(&path)?;
+        let run_flags = probe_run_flags(&path);
+        if run_flags.is_none() {
+            warn!("Couldn't probe flags supported by {:?}, assuming it supports all", path);
+        }
+        Ok(CrosvmBinary { path, version, run_flags })
+    }
+
+    /// Check that the binary supports all the flags among the given arguments to `crosvm run`.
+    pub fn check_run_flags<'a>(
+        &self,
+        args: impl IntoIterator<Item = &'a OsStr>,
+    ) -> Result<(), CrosvmFeatureUnsupported> {
+        let supported = match &self.run_flags {
+            Some(supported) => supported,
+            None => return Ok(()),
+        };
+        for arg in args {
+            if let Some(flag) = arg.to_str().and_then(flag_name) {
+                if !supported.contains(flag) {
+                    return Err(CrosvmFeatureUnsupported {
+                        flag: flag.to_owned(),
+                        version: self.version.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The error returned when a config needs a flag which the chosen crosvm binary doesn't support.
+#[derive(Debug, thiserror::Error)]
+#[error("crosvm version {version} doesn't support {flag}, which the config requires.")]
+pub struct CrosvmFeatureUnsupported {
+    /// The unsupported flag, such as `--protected-vm`.
+    pub flag: String,
+    /// The version of the crosvm binary.
+    pub version: String,
+}
+
+/// The set of crosvm binaries which VMs may be run with.
+#[derive(Clone, Debug, Default)]
+pub struct CrosvmRegistry {
+    binaries: Vec<CrosvmBinary>,
+}
+
+impl CrosvmRegistry {
+    /// Build the registry from the default crosvm binary and any extra binaries listed in the
+    /// environment, probing each for its version. Extra binaries which can't be probed are skipped.
+    pub fn from_env() -> CrosvmRegistry {
+        let mut registry = CrosvmRegistry::default();
+        let default_path = PathBuf::from(DEFAULT_CROSVM_PATH);
+        let default = CrosvmBinary::probe(default_path.clone()).unwrap_or_else(|e| {
+            warn!("Failed to probe version of {:?}: {:?}", default_path, e);
+            CrosvmBinary {
+                path: default_path,
+                version: UNKNOWN_VERSION.to_owned(),
+                run_flags: None,
+            }
+        });
+        registry.register(default);
+        if let Some(extra) = env::var_os(EXTRA_CROSVM_PATHS_VAR) {
+            for path in env::split_paths(&extra) {
+                match CrosvmBinary::probe(path.clone()) {
+                    Ok(binary) => {
+                        info!("Found crosvm version {} at {:?}", binary.version, path);
+                        registry.register(binary);
+                    }
+                    Err(e) => warn!("Ignoring crosvm binary {:?}: {:?}", path, e),
+                }
+            }
+        }
+        registry
+    }
+
+    /// Add a binary to the registry.
+    pub fn register(&mut self, binary: CrosvmBinary) {
+        self.binaries.push(binary);
+    }
+
+    /// Choose the binary with the given version, or the newest one if no version is specified.
+    pub fn select(&self, version: Option<&str>) -> Result<&CrosvmBinary, Error> {
+        match version {
+            Some(version) => {
+                self.binaries.iter().find(|binary| binary.version == version).ok_or_else(|| {
+                    anyhow!("No crosvm binary with version {} is installed", version)
+                })
+            }
+            None => self
+                .binaries
+                .iter()
+                .max_by_key(|binary| version_key(&binary.version))
+                .ok_or_else(|| anyhow!("No crosvm binaries are installed")),
+        }
+    }
+}
+
+/// Run the given crosvm binary to find out its version.
+fn probe_version(path: &Path) -> Result<String, Error> {
+    let output = Command::new(path).arg("version").output().context("Failed to run crosvm")?;
+    if !output.status.success() {
+        bail!("crosvm version exited with {}", output.status);
+    }
+    // The output is of the form "crosvm 1.2.3".
+    let stdout = String::from_utf8(output.stdout)?;
+    let version = stdout.split_whitespace().last().context("Empty version output")?;
+    Ok(version.to_owned())
+}
+
+/// Run the given crosvm binary to find out which flags `crosvm run` accepts, from its help text.
+/// Returns `None` if it can't be run or doesn't list any flags.
+fn probe_run_flags(path: &Path) -> Option<BTreeSet<String>> {
+    // crosvm may exit with an error status after printing help, so don't check the status.
+    let output = Command::new(path).arg("run").arg("--help").output().ok()?;
+    let help = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let flags = parse_flags(&help);
+    if flags.is_empty() {
+        None
+    } else {
+        Some(flags)
+    }
+}
+
+/// Find all the flags mentioned in the given help text.
+fn parse_flags(help: &str) -> BTreeSet<String> {
+    help.split(|c: char| c.is_whitespace() || c == ',' || c == '[' || c == ']')
+        .filter_map(flag_name)
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Get the name of the flag in the given argument, without any `=value` part, or `None` if it isn't
+/// a flag.
+fn flag_name(arg: &str) -> Option<&str> {
+    let name = arg.strip_prefix("--")?;
+    let end = name.find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    let end = end.unwrap_or_else(|| name.len());
+    if end == 0 {
+        None
+    } else {
+        Some(&arg[..end + 2])
+    }
+}
+
+/// Key for ordering dotted version strings numerically, treating any non-numeric components as 0.
+fn version_key(version: &str) -> Vec<u32> {
+    version.split('.').map(|part| part.parse().unwrap_or(0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> CrosvmRegistry {
+        let mut registry = CrosvmRegistry::default();
+        registry.register(CrosvmBinary {
+            path: PathBuf::from("/data/local/tmp/crosvm_new"),
+            version: "0.10.0".to_owned(),
+            run_flags: None,
+        });
+        registry.register(CrosvmBinary {
+            path: PathBuf::from("/data/local/tmp/crosvm_old"),
+            version: "0.9.1".to_owned(),
+            run_flags: None,
+        });
+        registry
+    }
+
+    #[test]
+    fn select_specific_version() {
+        let registry = registry();
+        assert_eq!(
+            registry.select(Some("0.9.1")).unwrap().path,
+            Path::new("/data/local/tmp/crosvm_old")
+        );
+        assert!(registry.select(Some("1.0.0")).is_err());
+    }
+
+    #[test]
+    fn select_newest_by_default() {
+        assert_eq!(registry().select(None).unwrap().path, Path::new("/data/local/tmp/crosvm_new"));
+    }
+
+    #[test]
+    fn unsupported_flags_are_rejected() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        // A stub crosvm which only knows about a few flags.
+        let stub = env::temp_dir().join(format!("crosvm_stub_{}", std::process::id()));
+        fs::write(
+            &stub,
+            "#!/bin/sh\n\
+             if [ \"$1\" = version ]; then echo 'crosvm 0.8.0'; exit 0; fi\n\
+             echo 'Usage: crosvm run [options] <kernel>' >&2\n\
+             echo '  --cpus=N, --mem <N>  Number of CPUs and memory' >&2\n\
+             echo '  --disk=PATH[,ro]     Add a disk' >&2\n\
+             exit 1\n",
+        )
+        .unwrap();
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+        let binary = CrosvmBinary::probe(stub.clone()).unwrap();
+        fs::remove_file(&stub).unwrap();
+
+        assert_eq!(binary.version, "0.8.0");
+        let expected: BTreeSet<String> =
+            vec!["--cpus", "--mem", "--disk"].into_iter().map(str::to_owned).collect();
+        assert_eq!(binary.run_flags.as_ref(), Some(&expected));
+
+        let args = ["--cpus", "2", "--mem=512", "/data/kernel"];
+        assert!(binary.check_run_flags(args.iter().map(OsStr::new)).is_ok());
+        let args = ["--cpus", "2", "--watchdog", "--disk=/data/disk.img"];
+        let error = binary.check_run_flags(args.iter().map(OsStr::new)).unwrap_err();
+        assert_eq!(error.flag, "--watchdog");
+        assert!(error.to_string().contains("--watchdog"));
+
+        // Binaries whose flags couldn't be probed are assumed to support everything.
+        let unprobed = CrosvmBinary { run_flags: None, ..binary };
+        assert!(unprobed.check_run_flags(args.iter().map(OsStr::new)).is_ok());
+    }
+}
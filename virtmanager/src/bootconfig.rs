@@ -0,0 +1,165 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rendering of bootconfig parameters, which the kernel reads from the end of the initrd if it is
+//! given `bootconfig` on its command line.
+
+use anyhow::{bail, Context, Error};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// The magic string which ends a bootconfig blob.
+const MAGIC: &[u8] = b"#BOOTCONFIG\n";
+
+/// The largest bootconfig data the kernel accepts, in bytes.
+const MAX_DATA_SIZE: usize = 32767;
+
+/// The alignment the kernel requires of the bootconfig data, in bytes.
+const DATA_ALIGNMENT: usize = 4;
+
+/// The bootconfig key under which the guest is given a random seed, if its config asks for one.
+pub const ENTROPY_SEED_KEY: &str = "androidboot.entropy_seed";
+
+/// Where entropy seeds for guests are read from.
+const ENTROPY_SOURCE: &str = "/dev/urandom";
+
+/// The size of the entropy seeds given to guests, in bytes.
+const ENTROPY_SEED_SIZE: usize = 32;
+
+/// Check that the given key is a series of words made of letters, digits, `-` and `_`, separated
+/// by `.`.
+fn validate_key(key: &str) -> Result<(), Error> {
+    let valid_word = |word: &str| {
+        !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+    if !key.split('.').all(valid_word) {
+        bail!("Invalid bootconfig key {:?}.", key);
+    }
+    Ok(())
+}
+
+/// Quote the given value for bootconfig. Bootconfig has no escape sequences, so the value is
+/// quoted with whichever of `"` and `'` it doesn't contain.
+fn quote(value: &str) -> Result<String, Error> {
+    if value.chars().any(|c| c.is_control()) {
+        bail!("Bootconfig value {:?} contains control characters.", value);
+    }
+    let quote = if !value.contains('"') {
+        '"'
+    } else if !value.contains('\'') {
+        '\''
+    } else {
+        bail!("Bootconfig value {:?} contains both kinds of quote, so can't be quoted.", value);
+    };
+    Ok(format!("{}{}{}", quote, value, quote))
+}
+
+/// Check that the given parameters can be rendered as bootconfig.
+pub fn validate(params: &BTreeMap<String, String>) -> Result<(), Error> {
+    render(params).map(|_| ())
+}
+
+/// Render the given parameters as a bootconfig blob, to be appended to an initrd: one
+/// `key = "value"` line per parameter, padded to the alignment the kernel requires, followed by
+/// its size, checksum and the bootconfig magic.
+pub fn render(params: &BTreeMap<String, String>) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    for (key, value) in params {
+        validate_key(key)?;
+        data.extend(format!("{} = {}\n", key, quote(value)?).into_bytes());
+    }
+    // The kernel requires the data to end with a null, so there is always some padding.
+    data.resize((data.len() / DATA_ALIGNMENT + 1) * DATA_ALIGNMENT, 0);
+    if data.len() > MAX_DATA_SIZE {
+        bail!("Bootconfig is {} bytes, more than the maximum of {}.", data.len(), MAX_DATA_SIZE);
+    }
+    let checksum = data.iter().map(|&byte| u32::from(byte)).fold(0u32, u32::wrapping_add);
+    let mut blob = data;
+    blob.extend(&(blob.len() as u32).to_le_bytes());
+    blob.extend(&checksum.to_le_bytes());
+    blob.extend(MAGIC);
+    Ok(blob)
+}
+
+/// Read a fresh entropy seed for a guest from the host, as hex.
+pub fn entropy_seed() -> Result<String, Error> {
+    let mut seed = [0; ENTROPY_SEED_SIZE];
+    File::open(ENTROPY_SOURCE)
+        .and_then(|mut source| source.read_exact(&mut seed))
+        .with_context(|| format!("Failed to read entropy seed from {}", ENTROPY_SOURCE))?;
+    Ok(seed.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Write a copy of the given initrd to the given path, with the given parameters appended as
+/// bootconfig.
+pub fn write_initrd(
+    initrd: &Path,
+    params: &BTreeMap<String, String>,
+    path: &Path,
+) -> Result<(), Error> {
+    let blob = render(params)?;
+    fs::copy(initrd, path).with_context(|| format!("Failed to copy initrd {:?}", initrd))?;
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    file.write_all(&blob).with_context(|| format!("Failed to append bootconfig to {:?}", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries.iter().map(|&(key, value)| (key.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn entries_appear_in_blob() {
+        let blob = render(&params(&[
+            ("androidboot.hardware", "virt"),
+            ("androidboot.serialno", "say \"hi\""),
+        ]))
+        .unwrap();
+
+        let data = b"androidboot.hardware = \"virt\"\nandroidboot.serialno = 'say \"hi\"'\n";
+        let padded_size = (data.len() / 4 + 1) * 4;
+        assert_eq!(&blob[..data.len()], &data[..]);
+        assert!(blob[data.len()..padded_size].iter().all(|&byte| byte == 0));
+        let footer = &blob[padded_size..];
+        assert_eq!(footer[..4], (padded_size as u32).to_le_bytes());
+        let checksum: u32 = data.iter().map(|&byte| u32::from(byte)).sum();
+        assert_eq!(footer[4..8], checksum.to_le_bytes());
+        assert_eq!(&footer[8..], MAGIC);
+    }
+
+    #[test]
+    fn invalid_entries_are_rejected() {
+        assert!(validate(&params(&[("androidboot.", "x")])).is_err());
+        assert!(validate(&params(&[("android boot", "x")])).is_err());
+        assert!(validate(&params(&[("key", "both \" and '")])).is_err());
+        assert!(validate(&params(&[("key", "new\nline")])).is_err());
+        assert!(validate(&params(&[("key", &"x".repeat(MAX_DATA_SIZE))])).is_err());
+        assert!(validate(&params(&[("key-1.sub_key", "")])).is_ok());
+    }
+
+    #[test]
+    fn entropy_seeds_are_fresh() {
+        let seed = entropy_seed().unwrap();
+        assert_eq!(seed.len(), 2 * ENTROPY_SEED_SIZE);
+        assert!(seed.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(seed, entropy_seed().unwrap());
+        validate(&params(&[(ENTROPY_SEED_KEY, &seed)])).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A bounded queue for delivering callbacks to clients off the thread which noticed the event.
+
+use log::{error, warn};
+use std::fmt::{self, Debug, Formatter};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The number of worker threads delivering callbacks.
+const WORKER_COUNT: usize = 4;
+
+/// The maximum number of callbacks which may be waiting to be delivered.
+const QUEUE_CAPACITY: usize = 64;
+
+/// Callbacks which have been waiting longer than this by the time a worker is free are dropped.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Job {
+    queued: Instant,
+    description: String,
+    run: Box<dyn FnOnce() + Send>,
+}
+
+/// A handle to a pool of worker threads which run callbacks posted to them. Posting never blocks,
+/// so a slow client can't stall the thread which posts the callback, such as a VM's monitor thread.
+#[derive(Clone)]
+pub struct CallbackQueue {
+    sender: SyncSender<Job>,
+}
+
+impl Default for CallbackQueue {
+    /// Create a new queue with the default number of workers, capacity and timeout.
+    fn default() -> Self {
+        CallbackQueue::with_limits(WORKER_COUNT, QUEUE_CAPACITY, DELIVERY_TIMEOUT)
+    }
+}
+
+impl CallbackQueue {
+    /// Create a new queue with the given number of worker threads, which holds at most `capacity`
+    /// pending callbacks and drops those which have been pending for longer than `timeout`.
+    pub fn with_limits(workers: usize, capacity: usize, timeout: Duration) -> CallbackQueue {
+        let (sender, receiver) = sync_channel(capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            thread::spawn(move || run_worker(&receiver, timeout));
+        }
+        CallbackQueue { sender }
+    }
+
+    /// Post a callback to be run by one of the workers. If the queue is full then the callback is
+    /// dropped and an error logged.
+    pub fn post(&self, description: impl Into<String>, run: impl FnOnce() + Send + 'static) {
+        let job =
+            Job { queued: Instant::now(), description: description.into(), run: Box::new(run) };
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(job)) => {
+                error!("Callback queue full, dropping {}", job.description)
+            }
+            Err(TrySendError::Disconnected(job)) => {
+                error!("Callback workers have gone, dropping {}", job.description)
+            }
+        }
+    }
+}
+
+impl Debug for CallbackQueue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("CallbackQueue").finish()
+    }
+}
+
+/// Run callbacks from the queue until all senders have been dropped.
+fn run_worker(receiver: &Mutex<Receiver<Job>>, timeout: Duration) {
+    loop {
+        // The lock is only held while waiting for a job, not while running it.
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        if job.queued.elapsed() > timeout {
+            warn!("Dropping {} which couldn't be delivered within {:?}", job.description, timeout);
+        } else {
+            (job.run)();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn slow_callback_does_not_delay_others() {
+        let queue = CallbackQueue::with_limits(2, 8, Duration::from_secs(10));
+        let (sender, receiver) = channel();
+
+        let slow_sender = sender.clone();
+        queue.post("slow", move || {
+            thread::sleep(Duration::from_secs(5));
+            let _ = slow_sender.send("slow");
+        });
+        queue.post("fast", move || sender.send("fast").unwrap());
+
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(1)), Ok("fast"));
+    }
+
+    #[test]
+    fn stale_callback_is_dropped() {
+        let queue = CallbackQueue::with_limits(1, 8, Duration::from_millis(100));
+        let (sender, receiver) = channel();
+
+        queue.post("blocker", || thread::sleep(Duration::from_millis(500)));
+        queue.post("stale", move || sender.send(()).unwrap());
+
+        assert!(receiver.recv_timeout(Duration::from_secs(2)).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-VM cgroups, so that a VM's crosvm process can be paused with the cgroup v2 freezer.
+
+use anyhow::{Context, Error};
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The cgroup v2 directory under which each VM which uses the freezer gets its own cgroup.
+const CGROUP_BASE: &str = "/sys/fs/cgroup/virtmanager";
+
+/// The file in a cgroup listing the processes in it, to which a PID is written to move a process
+/// into the cgroup.
+const PROCS_FILE: &str = "cgroup.procs";
+
+/// The file in a cgroup to which `1` is written to freeze it, and `0` to thaw it.
+const FREEZE_FILE: &str = "cgroup.freeze";
+
+/// A cgroup containing a single VM's crosvm process and its children.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Create a new cgroup with the given name, such as that of the VM's runtime directory.
+    pub fn create(name: &OsStr) -> Result<Cgroup, Error> {
+        Cgroup::create_in(Path::new(CGROUP_BASE), name)
+    }
+
+    fn create_in(base: &Path, name: &OsStr) -> Result<Cgroup, Error> {
+        let path = base.join(name);
+        fs::create_dir_all(&path).with_context(|| format!("Failed to create cgroup {:?}", path))?;
+        Ok(Cgroup { path })
+    }
+
+    /// Have the given command move itself into the cgroup before it runs, so that it and every
+    /// process it starts are in the cgroup from the beginning.
+    pub fn add_command(&self, command: &mut Command) -> Result<(), Error> {
+        let procs_path = self.path.join(PROCS_FILE);
+        let procs = OpenOptions::new()
+            .write(true)
+            .open(&procs_path)
+            .with_context(|| format!("Failed to open {:?}", procs_path))?;
+        // Safe because the closure only makes a syscall which is safe to call between fork and
+        // exec, writing from a static buffer to a file which lives as long as the closure. Writing
+        // 0 moves the writing process.
+        unsafe {
+            command.pre_exec(move || {
+                if libc::write(procs.as_raw_fd(), b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Freeze or thaw all the processes in the cgroup.
+    pub fn set_frozen(&self, frozen: bool) -> Result<(), Error> {
+        let freeze = self.path.join(FREEZE_FILE);
+        fs::write(&freeze, if frozen { "1" } else { "0" })
+            .with_context(|| format!("Failed to write {:?}", freeze))
+    }
+
+    /// Remove the cgroup, which must no longer contain any processes.
+    pub fn remove(&self) -> Result<(), Error> {
+        fs::remove_dir(&self.path)
+            .with_context(|| format!("Failed to remove cgroup {:?}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn freezer_file_is_written_on_pause_and_cleared_on_resume() {
+        // A plain directory stands in for the cgroup filesystem, in which the kernel would
+        // otherwise create the control files.
+        let base = env::temp_dir().join("virtmanager_cgroup_test");
+        let _ = fs::remove_dir_all(&base);
+        let cgroup = Cgroup::create_in(&base, OsStr::new("1000_vm10")).unwrap();
+        let freeze = base.join("1000_vm10").join(FREEZE_FILE);
+
+        cgroup.set_frozen(true).unwrap();
+        assert_eq!(fs::read_to_string(&freeze).unwrap(), "1");
+        cgroup.set_frozen(false).unwrap();
+        assert_eq!(fs::read_to_string(&freeze).unwrap(), "0");
+
+        fs::remove_file(&freeze).unwrap();
+        cgroup.remove().unwrap();
+        assert!(!base.join("1000_vm10").exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -14,13 +14,86 @@
 
 //! Function and types for VM configuration.
 
-use anyhow::{bail, Error};
+use crate::bootconfig;
+use crate::protection::MemProtectionConfig;
+use crate::Cid;
+use anyhow::{anyhow, bail, Context, Error};
+use log::{info, warn};
+use ring::digest;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::BufReader;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// The name of the config file within a VM bundle directory.
+const BUNDLE_CONFIG_FILE: &str = "vm_config.json";
+
+/// Where the kernel shows the paths of our open file descriptors.
+const FD_PATH_PREFIX: &str = "/proc/self/fd/";
+
+/// The config file with defaults for every VM, if there is one.
+pub const BASE_CONFIG_PATH: &str = "/data/misc/virtmanager_config/base_vm_config.json";
+
+/// The optional CPU features which crosvm supports, each of which is enabled by the flag of the
+/// same name.
+pub const CPU_FEATURES: [&str; 3] = ["no-smt", "host-cpu-topology", "itmt"];
+
+/// CPU features which are only supported on x86_64 hosts.
+const X86_64_CPU_FEATURES: [&str; 1] = ["itmt"];
+
+/// The amount of guest memory crosvm gives a VM if none is specified, in MiB.
+pub const DEFAULT_MEMORY_MIB: u64 = 256;
+
+/// The least severe kernel log level, `KERN_DEBUG`.
+const MAX_KERNEL_LOG_LEVEL: u8 = 7;
+
+/// The log levels which crosvm accepts.
+const CROSVM_LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// The PCI address of the host bridge which crosvm always puts at the start of bus 0, as
+/// (domain, bus, device, function).
+const HOST_BRIDGE_PCI_ADDRESS: (u16, u8, u8, u8) = (0, 0, 0, 0);
+
+/// Block size used for both data and hashes of dm-verity protected disks.
+const VERITY_BLOCK_SIZE: u64 = 4096;
+
+/// Fields which have been renamed, as (old name, new name). They are still accepted under their
+/// old names, with a warning.
+const RENAMED_FIELDS: [(&str, &str); 0] = [];
+
+/// Something about a VM configuration which doesn't stop the VM from being started, but which the
+/// client should know about.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConfigWarning {
+    /// A field was given under an old name, which may stop being accepted in future.
+    DeprecatedField {
+        /// The name the field was given under.
+        name: &'static str,
+        /// The name it should be given under instead.
+        replacement: &'static str,
+    },
+    /// The configuration is valid, but part of it probably doesn't do what was intended.
+    Ineffective(String),
+}
+
+impl Display for ConfigWarning {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ConfigWarning::DeprecatedField { name, replacement } => {
+                write!(f, "The field {} is deprecated; use {} instead.", name, replacement)
+            }
+            ConfigWarning::Ineffective(message) => write!(f, "{}", message),
+        }
+    }
+}
 
 /// Configuration for a particular VM to be started.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub struct VmConfig {
     /// The filename of the kernel image, if any.
     pub kernel: Option<String>,
@@ -32,36 +105,1595 @@ pub struct VmConfig {
     /// The bootloader to use. If this is supplied then the kernel and initrd must not be supplied;
     /// the bootloader is instead responsibly for loading the kernel from one of the disks.
     pub bootloader: Option<String>,
+    /// A file in which the bootloader can store persistent variables, such as the NVRAM of UEFI
+    /// firmware. This is created if it doesn't already exist, and requires a bootloader.
+    pub pflash: Option<String>,
     /// Disk images to be made available to the VM.
     #[serde(default)]
     pub disks: Vec<DiskImage>,
+    /// The ids of disks in the order the firmware should try to boot from them. These disks are
+    /// attached to the VM first, in this order, followed by any other disks in the order they are
+    /// declared. If this is empty then all disks are attached in the order they are declared.
+    #[serde(default)]
+    pub boot_order: Vec<String>,
+    /// Whether to start the VM without optional platform devices such as ACPI and the RTC. This
+    /// lets very small guests boot faster, but most full operating systems need these devices.
+    #[serde(default)]
+    pub minimal_platform: bool,
+    /// Whether to give the guest a watchdog device, which resets the guest if it stops being
+    /// petted.
+    #[serde(default)]
+    pub watchdog: bool,
+    /// How long in seconds the guest may go without petting the watchdog before it is reset. This
+    /// requires `watchdog`. If this is not specified then crosvm's default is used.
+    pub watchdog_timeout_secs: Option<u32>,
+    /// Whether to skip logging routine lifecycle events such as the VM starting and stopping, for
+    /// workloads which start many short-lived VMs. Errors and crashes are still logged.
+    #[serde(default)]
+    pub quiet: bool,
+    /// The version of crosvm to run the VM with, if several are installed. If this is not
+    /// specified then the newest installed version is used.
+    pub crosvm_version: Option<String>,
+    /// The payload binary for the guest to run as init, if any. This is passed on the kernel
+    /// command line, so requires a kernel.
+    pub payload: Option<PayloadConfig>,
+    /// The hostname for the guest to use, passed on the kernel command line. If this is not
+    /// specified then one is derived from the VM's CID.
+    pub hostname: Option<String>,
+    /// The vsock port on which an agent in the guest listens for requests from the host, if any.
+    /// Each request is a line, to which the agent replies with a line starting with `ok` if it
+    /// succeeded. When the VM is stopped the agent is sent `shutdown`; it should reply `ok` and
+    /// then power off the guest. When the guest's kernel version is requested the agent is sent
+    /// `uname`; it should reply `ok` followed by a space and what `uname -a` prints.
+    pub agent_port: Option<u32>,
+    /// Services on other VMs which must accept vsock connections before this VM is considered
+    /// to have finished starting. If they aren't all reachable within a minute then the VM is
+    /// stopped.
+    #[serde(default)]
+    pub wait_for: Vec<Dependency>,
+    /// The amount of guest memory in MiB. If this is not specified then crosvm's default of
+    /// `DEFAULT_MEMORY_MIB` is used.
+    pub memory_mib: Option<u64>,
+    /// The importance of the VM relative to others. When the host is under critical memory
+    /// pressure, VMs with lower priorities are stopped first.
+    #[serde(default)]
+    pub priority: i32,
+    /// The `SCHED_FIFO` real-time priority to run crosvm and its vCPU threads with, from 1 to 99.
+    /// If this is not specified then the normal scheduling policy is used.
+    pub rt_priority: Option<u32>,
+    /// The I/O scheduling class and level to run crosvm with, such as to let background VMs yield
+    /// disk bandwidth. If this is not specified then crosvm inherits the Virt Manager's.
+    pub io_priority: Option<IoPriority>,
+    /// The limit on the number of files crosvm may have open, which must be within the hard limit
+    /// of the Virt Manager. If this is not specified then the Virt Manager's own limit is used.
+    pub open_files_limit: Option<u64>,
+    /// The host NUMA node to bind all of crosvm's memory, including guest memory, to. If this is
+    /// not specified then memory may be allocated from any node.
+    pub numa_node: Option<u32>,
+    /// How to store the console output in the VM's runtime directory, if the client doesn't
+    /// provide its own log file descriptor. If this is not specified then crosvm writes it to a
+    /// single file which is never rotated.
+    pub console_log: Option<ConsoleLogConfig>,
+    /// The tag under which to mirror the VM's console output into the Android log, one entry per
+    /// line, as well as writing it to the client's log file descriptor or the runtime directory.
+    /// If this is not specified then the console output isn't logged.
+    pub console_logcat_tag: Option<String>,
+    /// The most bytes of console output to write to the client's log file descriptor, or to the
+    /// runtime directory if there isn't one, over the VM's lifetime. Once this is reached a final
+    /// line saying so is written and the rest of the output is dropped, while the VM keeps
+    /// running. If this is not specified then there is no limit.
+    pub max_log_bytes: Option<u64>,
+    /// The verbosity of crosvm's own logs, which are written to `crosvm.log` in the runtime
+    /// directory: one of `off`, `error`, `warn`, `info`, `debug` or `trace`. If this is not
+    /// specified then only warnings and errors are logged.
+    pub crosvm_log_level: Option<String>,
+    /// The CPU model to present to the guest. Only `host`, which passes through the host's CPU
+    /// model and is the default, is supported by crosvm.
+    pub cpu_model: Option<String>,
+    /// Optional CPU features to enable, from `no-smt`, `host-cpu-topology` and `itmt`.
+    #[serde(default)]
+    pub cpu_features: Vec<String>,
+    /// How crosvm should perform asynchronous I/O. If this is not specified then the Virt
+    /// Manager's default is used if it has one, or otherwise crosvm's.
+    pub async_executor: Option<AsyncExecutor>,
+    /// The size in MiB to cap the VM's scratch directory at, by mounting a tmpfs on it. If this is
+    /// not specified then the scratch directory is on the same filesystem as the runtime
+    /// directory. Mounting the tmpfs needs the Virt Manager to have `CAP_SYS_ADMIN`, without which
+    /// the VM fails to start.
+    pub scratch_size_mib: Option<u64>,
+    /// How interrupts should be handled. If this is not specified then crosvm's default is used.
+    pub irqchip: Option<IrqChip>,
+    /// The vhost-vsock device on the host to use for the VM's vsock device. If this is not
+    /// specified then crosvm's default of `/dev/vhost-vsock` is used.
+    pub vhost_vsock_device: Option<String>,
+    /// PCI addresses, of the form `[domain:]bus:device.function` in hex, at which to put stub
+    /// devices which do nothing, so that guests which expect particular slots to be populated see
+    /// stable enumeration.
+    #[serde(default)]
+    pub stub_pci_devices: Vec<String>,
+    /// How `pause` suspends the VM. If this is not specified then crosvm's control socket is used.
+    pub pause_mechanism: Option<PauseMechanism>,
+    /// Whether to run crosvm in its own mount namespace, in which only the runtime directory, the
+    /// images the VM uses and the parts of the host which crosvm itself needs are visible.
+    #[serde(default)]
+    pub isolate_mounts: bool,
+    /// A label to name the VM's crosvm process after, followed by the VM's CID, so that it can be
+    /// told apart from those of other VMs in `ps`. The label is truncated if need be to fit the
+    /// kernel's limit of 15 bytes on process names. If this is not specified then the process is
+    /// named `crosvm`.
+    pub process_label: Option<String>,
+    /// The name of the group the VM belongs to, if any, so that all the VMs in the group can be
+    /// paused and resumed together.
+    pub group: Option<String>,
+    /// Parameters to pass to the kernel as bootconfig, which is appended to the initrd, so this
+    /// requires an initrd. Keys are words of letters, digits, `-` and `_` separated by `.`, such as
+    /// `androidboot.hardware`, and values may contain either kind of quote but not both.
+    #[serde(default)]
+    pub bootconfig: BTreeMap<String, String>,
+    /// Whether to give the guest a seed freshly read from the host's `/dev/urandom` on each boot,
+    /// for crypto early in boot before its rng device is ready. The seed is hex, passed as the
+    /// bootconfig key `androidboot.entropy_seed` rather than on the kernel command line so that it
+    /// is never logged, so this requires an initrd.
+    #[serde(default)]
+    pub entropy_seed: bool,
+    /// How the hypervisor should protect the VM's memory from the host, if at all. The host must
+    /// support the options chosen, or the VM fails to start. Protected VMs boot through the pKVM
+    /// firmware, so can't have a bootloader or pflash of their own.
+    pub memory_protection: Option<MemProtectionConfig>,
+    /// Warnings about the config file itself, found while loading it.
+    #[serde(skip)]
+    pub load_warnings: Vec<ConfigWarning>,
+    /// The canonical path of the config file, if it was loaded from a file with a path rather
+    /// than, say, a pipe.
+    #[serde(skip)]
+    pub source_path: Option<PathBuf>,
 }
 
 impl VmConfig {
     /// Ensure that the configuration has a valid combination of fields set, or return an error if
     /// not.
     pub fn validate(&self) -> Result<(), Error> {
+        if self.bootloader.is_none() && self.kernel.is_none() && self.disks.is_empty() {
+            return Err(NothingToBoot.into());
+        }
         if self.bootloader.is_none() && self.kernel.is_none() {
             bail!("VM must have either a bootloader or a kernel image.");
         }
         if self.bootloader.is_some() && (self.kernel.is_some() || self.initrd.is_some()) {
             bail!("Can't have both bootloader and kernel/initrd image.");
         }
+        if self.pflash.is_some() && self.bootloader.is_none() {
+            bail!("pflash storage can only be given along with a bootloader.");
+        }
+        if let Some(priority) = self.rt_priority {
+            if !(1..=99).contains(&priority) {
+                bail!("Real-time priority {} is not between 1 and 99.", priority);
+            }
+        }
+        if let Some(io_priority) = &self.io_priority {
+            io_priority.validate()?;
+        }
+        if let Some(timeout) = self.watchdog_timeout_secs {
+            if !self.watchdog {
+                bail!("A watchdog timeout can only be given if the watchdog is enabled.");
+            }
+            if timeout == 0 {
+                bail!("Watchdog timeout must be positive.");
+            }
+        }
+        if let Some(label) = &self.process_label {
+            if label.is_empty() || label.contains(&['/', '\0'][..]) {
+                bail!("Invalid process label {:?}.", label);
+            }
+        }
+        if self.group.as_deref() == Some("") {
+            bail!("VM group name must not be empty.");
+        }
+        if self.scratch_size_mib == Some(0) {
+            bail!("Scratch directory size must be positive.");
+        }
+        if self.memory_mib == Some(0) {
+            bail!("Guest memory must be greater than 0.");
+        }
+        if let Some(model) = &self.cpu_model {
+            if model != "host" {
+                bail!("Unsupported CPU model {:?}, only host passthrough is supported.", model);
+            }
+        }
+        for (index, feature) in self.cpu_features.iter().enumerate() {
+            if !CPU_FEATURES.contains(&feature.as_str()) {
+                bail!("Unknown CPU feature {:?}, expected one of {:?}.", feature, CPU_FEATURES);
+            }
+            if self.cpu_features[..index].contains(feature) {
+                bail!("CPU feature {} is given more than once.", feature);
+            }
+        }
+        if let Some(level) = &self.crosvm_log_level {
+            if !CROSVM_LOG_LEVELS.contains(&level.as_str()) {
+                bail!(
+                    "Unknown crosvm log level {:?}, expected one of {:?}.",
+                    level,
+                    CROSVM_LOG_LEVELS
+                );
+            }
+        }
+        if let Some(console_log) = &self.console_log {
+            if console_log.max_segment_size == 0 {
+                bail!("Console log segment size must be positive.");
+            }
+            if console_log.max_segments == Some(0) {
+                bail!("Console log must keep at least one rotated segment.");
+            }
+            if let Some(level) = console_log.max_kernel_log_level {
+                if level > MAX_KERNEL_LOG_LEVEL {
+                    bail!(
+                        "Kernel log level {} is not between 0 and {}.",
+                        level,
+                        MAX_KERNEL_LOG_LEVEL
+                    );
+                }
+            }
+        }
+        if let Some(tag) = &self.console_logcat_tag {
+            if tag.is_empty() || tag.contains('\0') {
+                bail!("Invalid console log tag {:?}.", tag);
+            }
+        }
+        if self.open_files_limit == Some(0) {
+            bail!("Open files limit must be positive.");
+        }
+        if let Some(irqchip) = self.irqchip {
+            if irqchip != IrqChip::Kernel && !cfg!(target_arch = "x86_64") {
+                bail!("irqchip mode {:?} is only supported on x86_64.", irqchip);
+            }
+        }
+        if let Some(hostname) = &self.hostname {
+            validate_hostname(hostname)?;
+        }
+        if let Some(device) = &self.vhost_vsock_device {
+            let metadata = fs::metadata(device)
+                .with_context(|| format!("Failed to find vhost-vsock device {}", device))?;
+            if !metadata.file_type().is_char_device() {
+                bail!("vhost-vsock device {} is not a character device.", device);
+            }
+        }
+        let mut stub_pci_addresses = vec![];
+        for address in &self.stub_pci_devices {
+            let parsed = parse_pci_address(address)?;
+            if parsed == HOST_BRIDGE_PCI_ADDRESS {
+                bail!("Stub PCI device {} would collide with crosvm's host bridge.", address);
+            }
+            if stub_pci_addresses.contains(&parsed) {
+                bail!("Stub PCI device address {} is given more than once.", address);
+            }
+            stub_pci_addresses.push(parsed);
+        }
+        for disk in &self.disks {
+            disk.validate()?;
+        }
+        for (index, disk) in self.disks.iter().enumerate() {
+            if disk.id.is_some() && self.disks[..index].iter().any(|other| other.id == disk.id) {
+                bail!("Duplicate disk id {:?}.", disk.id);
+            }
+        }
+        for (index, id) in self.boot_order.iter().enumerate() {
+            if !self.disks.iter().any(|disk| disk.id.as_ref() == Some(id)) {
+                bail!("Boot order refers to unknown disk id {}.", id);
+            }
+            if self.boot_order[..index].contains(id) {
+                bail!("Disk id {} appears more than once in boot order.", id);
+            }
+        }
+        if self.disks.iter().any(|disk| disk.verity.is_some()) {
+            if self.kernel.is_none() {
+                bail!("dm-verity protected disks need a kernel image.");
+            }
+            if self.disk_count() > 26 {
+                bail!("Too many disks for dm-verity device names.");
+            }
+        }
+        if self.uses_bootconfig() {
+            if self.initrd.is_none() {
+                bail!("Bootconfig and entropy seeds can only be given along with an initrd.");
+            }
+            bootconfig::validate(&self.bootconfig)?;
+        }
+        if self.entropy_seed && self.bootconfig.contains_key(bootconfig::ENTROPY_SEED_KEY) {
+            bail!(
+                "Bootconfig key {} is reserved for the entropy seed.",
+                bootconfig::ENTROPY_SEED_KEY
+            );
+        }
+        if let Some(payload) = &self.payload {
+            if self.kernel.is_none() {
+                bail!("A payload can only be given along with a kernel image.");
+            }
+            payload.validate()?;
+        }
+        if let Some(protection) = &self.memory_protection {
+            protection.validate()?;
+            if protection.protected && (self.bootloader.is_some() || self.pflash.is_some()) {
+                bail!("Protected VMs can't have a bootloader or pflash.");
+            }
+        }
+        for warning in self.warnings() {
+            warn!("{}", warning);
+        }
         Ok(())
     }
 
-    /// Load the configuration for a VM from the given JSON file.
-    pub fn load(file: &File) -> Result<VmConfig, Error> {
+    /// Get the amount of guest memory the VM will have, in MiB.
+    pub fn guest_memory_mib(&self) -> u64 {
+        self.memory_mib.unwrap_or(DEFAULT_MEMORY_MIB)
+    }
+
+    /// Get warnings found while loading the configuration, followed by warnings about parts of it
+    /// which are valid, but probably don't do what was intended.
+    pub fn warnings(&self) -> Vec<ConfigWarning> {
+        let mut ineffective = vec![];
+        if self.minimal_platform && self.bootloader.is_some() {
+            ineffective.push(
+                "Minimal platform mode is enabled, but bootloaders usually need ACPI and an RTC."
+                    .to_owned(),
+            );
+        }
+        for feature in &self.cpu_features {
+            if X86_64_CPU_FEATURES.contains(&feature.as_str()) && !cfg!(target_arch = "x86_64") {
+                ineffective
+                    .push(format!("CPU feature {} is only supported on x86_64 hosts.", feature));
+            }
+        }
+        if self.hostname.is_some() && self.kernel.is_none() {
+            ineffective.push(
+                "The hostname is passed on the kernel command line, so is ignored by bootloaders."
+                    .to_owned(),
+            );
+        }
+        let mut warnings = self.load_warnings.clone();
+        warnings.extend(ineffective.into_iter().map(ConfigWarning::Ineffective));
+        warnings
+    }
+
+    /// Get the parameters to pass to the kernel of the VM with the given CID, including the
+    /// hostname and those needed to set up dm-verity and to start the payload if there is one.
+    pub fn kernel_params(&self, cid: Cid) -> Result<Option<String>, Error> {
+        let mut params: Vec<String> = self.params.iter().cloned().collect();
+        if self.kernel.is_some() {
+            match &self.hostname {
+                Some(hostname) => params.push(format!("hostname={}", hostname)),
+                None => params.push(format!("hostname=vm-{}", cid)),
+            }
+        }
+        if let Some(verity) = self.verity_params()? {
+            params.push(verity);
+        }
+        if self.uses_bootconfig() {
+            params.push("bootconfig".to_owned());
+        }
+        if let Some(payload) = &self.payload {
+            params.push(format!("init={}", payload.binary));
+            if !payload.args.is_empty() {
+                // Everything after `--` is passed by the kernel to init as arguments.
+                params.push("--".to_owned());
+                params.extend(payload.args.iter().cloned());
+            }
+        }
+        Ok(if params.is_empty() { None } else { Some(params.join(" ")) })
+    }
+
+    /// Whether the VM is given bootconfig, either from its config or for its entropy seed.
+    pub fn uses_bootconfig(&self) -> bool {
+        !self.bootconfig.is_empty() || self.entropy_seed
+    }
+
+    /// Get the indices of the disks in the order in which they are attached to the VM, which is the
+    /// boot order followed by the remaining disks in the order they are declared.
+    pub fn device_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = self
+            .boot_order
+            .iter()
+            .filter_map(|id| self.disks.iter().position(|disk| disk.id.as_ref() == Some(id)))
+            .collect();
+        order.extend((0..self.disks.len()).filter(|index| !order.contains(index)));
+        order
+    }
+
+    /// Get the index of the device as which the disk with the given index is attached to the VM.
+    pub fn device_index(&self, disk_index: usize) -> Option<usize> {
+        self.device_order().iter().position(|&index| index == disk_index)
+    }
+
+    /// Get the dm-verity hashtree images for all protected disks, in the order in which they are
+    /// attached to the VM after the disks themselves.
+    pub fn verity_hashtrees(&self) -> Vec<&str> {
+        self.device_order()
+            .into_iter()
+            .filter_map(|index| self.disks[index].verity.as_ref())
+            .map(|verity| verity.hashtree.as_str())
+            .collect()
+    }
+
+    /// The total number of disk devices the VM will see, including dm-verity hashtrees.
+    fn disk_count(&self) -> usize {
+        self.disks.len() + self.verity_hashtrees().len()
+    }
+
+    /// Build the `dm-mod.create` kernel parameter to set up a dm-verity device for each protected
+    /// disk, if there are any.
+    fn verity_params(&self) -> Result<Option<String>, Error> {
+        let mut tables = vec![];
+        let mut hashtree_index = self.disks.len();
+        for (device_index, index) in self.device_order().into_iter().enumerate() {
+            let disk = &self.disks[index];
+            if let Some(verity) = &disk.verity {
+                let size = fs::metadata(&disk.image)
+                    .with_context(|| format!("Failed to get size of {}", disk.image))?
+                    .len();
+                tables.push(format!(
+                    "verity{},,,ro,0 {} verity 1 {} {} {} {} {} 0 sha256 {} -",
+                    index,
+                    size / 512,
+                    guest_disk_device(device_index),
+                    guest_disk_device(hashtree_index),
+                    VERITY_BLOCK_SIZE,
+                    VERITY_BLOCK_SIZE,
+                    size / VERITY_BLOCK_SIZE,
+                    verity.root_hash,
+                ));
+                hashtree_index += 1;
+            }
+        }
+        Ok(if tables.is_empty() {
+            None
+        } else {
+            Some(format!("dm-mod.create=\"{}\"", tables.join(";")))
+        })
+    }
+
+    /// Get the names of the optional features which the configuration enables, such as dm-verity
+    /// or a real-time scheduling policy, in a fixed order.
+    pub fn enabled_features(&self) -> Vec<&'static str> {
+        let mut features = vec![];
+        if self.bootloader.is_some() {
+            features.push("bootloader");
+        }
+        if self.pflash.is_some() {
+            features.push("pflash");
+        }
+        if self.initrd.is_some() {
+            features.push("initrd");
+        }
+        if !self.bootconfig.is_empty() {
+            features.push("bootconfig");
+        }
+        if self.entropy_seed {
+            features.push("entropy-seed");
+        }
+        if self.memory_protection.as_ref().map_or(false, |protection| protection.protected) {
+            features.push("protected-vm");
+        }
+        if self.disks.iter().any(|disk| disk.writable) {
+            features.push("writable-disk");
+        }
+        if self.disks.iter().any(|disk| disk.verity.is_some()) {
+            features.push("verity");
+        }
+        if !self.boot_order.is_empty() {
+            features.push("boot-order");
+        }
+        if self.minimal_platform {
+            features.push("minimal-platform");
+        }
+        if self.payload.is_some() {
+            features.push("payload");
+        }
+        if self.agent_port.is_some() {
+            features.push("guest-agent");
+        }
+        if self.rt_priority.is_some() {
+            features.push("realtime");
+        }
+        if self.io_priority.is_some() {
+            features.push("io-priority");
+        }
+        if self.open_files_limit.is_some() {
+            features.push("open-files-limit");
+        }
+        if self.numa_node.is_some() {
+            features.push("numa-binding");
+        }
+        if self.cpu_model.is_some() {
+            features.push("cpu-model");
+        }
+        if !self.cpu_features.is_empty() {
+            features.push("cpu-features");
+        }
+        if self.async_executor.is_some() {
+            features.push("async-executor");
+        }
+        if self.scratch_size_mib.is_some() {
+            features.push("scratch-tmpfs");
+        }
+        if self.watchdog {
+            features.push("watchdog");
+        }
+        match self.irqchip {
+            None | Some(IrqChip::Kernel) => {}
+            Some(IrqChip::Split) => features.push("split-irqchip"),
+            Some(IrqChip::Userspace) => features.push("userspace-irqchip"),
+        }
+        if self.vhost_vsock_device.is_some() {
+            features.push("custom-vhost-vsock");
+        }
+        if !self.stub_pci_devices.is_empty() {
+            features.push("stub-pci");
+        }
+        if self.pause_mechanism == Some(PauseMechanism::CgroupFreezer) {
+            features.push("cgroup-freezer");
+        }
+        if self.isolate_mounts {
+            features.push("isolated-mounts");
+        }
+        features
+    }
+
+    /// Get a copy of the configuration stripped down to just what is needed to boot: the kernel or
+    /// bootloader, the root disk and the console. This is used to start a VM in safe mode, to
+    /// isolate boot problems from those caused by optional devices.
+    ///
+    /// Only the fields listed here are kept, so that new optional features are left out of safe
+    /// mode unless they are added. Memory protection is kept, as running a protected VM
+    /// unprotected would expose its memory to the host.
+    pub fn safe_mode(&self) -> VmConfig {
+        let root_disk = self.device_order().first().map(|&index| self.disks[index].clone());
+        let safe = VmConfig {
+            kernel: self.kernel.clone(),
+            initrd: self.initrd.clone(),
+            params: self.params.clone(),
+            bootloader: self.bootloader.clone(),
+            boot_order: root_disk.iter().filter_map(|disk| disk.id.clone()).collect(),
+            disks: root_disk.into_iter().collect(),
+            quiet: self.quiet,
+            crosvm_version: self.crosvm_version.clone(),
+            memory_mib: self.memory_mib,
+            priority: self.priority,
+            group: self.group.clone(),
+            memory_protection: self.memory_protection.clone(),
+            load_warnings: self.load_warnings.clone(),
+            source_path: self.source_path.clone(),
+            ..Default::default()
+        };
+        let removed: Vec<&str> = self
+            .enabled_features()
+            .into_iter()
+            .filter(|feature| !safe.enabled_features().contains(feature))
+            .collect();
+        if !removed.is_empty() {
+            info!("Safe mode: removing features {:?}", removed);
+        }
+        let extra_disks = self.disks.len() - safe.disks.len();
+        if extra_disks > 0 {
+            info!("Safe mode: removing {} disk(s) other than the root disk", extra_disks);
+        }
+        safe
+    }
+
+    /// Load the configuration for a VM from the given JSON file, with the given base config
+    /// beneath it. Fields given under their old names are renamed, unless they are also given
+    /// under their new names, and a warning is recorded in `load_warnings` for each.
+    pub fn load(file: &File, base: &BaseConfig) -> Result<VmConfig, Error> {
         let buffered = BufReader::new(file);
-        Ok(serde_json::from_reader(buffered)?)
+        let mut value: Value = serde_json::from_reader(buffered)?;
+        let mut load_warnings = vec![];
+        if let Some(fields) = value.as_object_mut() {
+            load_warnings = rename_deprecated_fields(fields);
+            base.apply(fields);
+        }
+        Ok(VmConfig {
+            load_warnings,
+            source_path: canonical_path(file),
+            ..serde_json::from_value(value)?
+        })
+    }
+
+    /// Load the configuration for a VM from a bundle directory, which contains the config file
+    /// along with all the files it refers to, with the given base config beneath it. Relative
+    /// paths in the config are resolved against the bundle directory, and all paths must refer to
+    /// existing files within it, including any paths from the base config. The pflash file is
+    /// created when the VM starts if need be, so only its directory must exist.
+    pub fn load_bundle(dir: &Path, base: &BaseConfig) -> Result<VmConfig, Error> {
+        let dir = dir.canonicalize().with_context(|| format!("Failed to find bundle {:?}", dir))?;
+        let config_path = dir.join(BUNDLE_CONFIG_FILE);
+        let config_file = File::open(&config_path)
+            .with_context(|| format!("Failed to open {:?}", config_path))?;
+        let mut config = VmConfig::load(&config_file, base)?;
+        for (path, must_exist) in config.file_paths_mut() {
+            let joined = dir.join(&*path);
+            // A file which exists is resolved itself, in case it is a link out of the bundle.
+            let resolved = if must_exist || joined.symlink_metadata().is_ok() {
+                joined.canonicalize()
+            } else {
+                let name = joined.file_name().with_context(|| format!("{} isn't a file", path))?;
+                joined.parent().unwrap_or(&dir).canonicalize().map(|parent| parent.join(name))
+            }
+            .with_context(|| format!("Failed to find {} in bundle", path))?;
+            if !resolved.starts_with(&dir) {
+                bail!("{} is outside of the bundle {:?}.", path, dir);
+            }
+            *path = resolved.to_str().context("Path is not valid UTF-8")?.to_owned();
+        }
+        Ok(config)
+    }
+
+    /// Get all the host file paths referred to by the configuration, along with whether each file
+    /// must already exist.
+    fn file_paths_mut(&mut self) -> Vec<(&mut String, bool)> {
+        let mut paths = vec![];
+        paths.extend(self.kernel.as_mut());
+        paths.extend(self.initrd.as_mut());
+        paths.extend(self.bootloader.as_mut());
+        for disk in &mut self.disks {
+            paths.push(&mut disk.image);
+            if let Some(verity) = &mut disk.verity {
+                paths.push(&mut verity.hashtree);
+            }
+        }
+        let mut paths: Vec<_> = paths.into_iter().map(|path| (path, true)).collect();
+        paths.extend(self.pflash.as_mut().map(|path| (path, false)));
+        paths
+    }
+}
+
+/// Get the canonical path of the given open file, as the kernel reports it, or `None` if it doesn't
+/// have one, such as if it is a pipe or socket.
+fn canonical_path(file: &File) -> Option<PathBuf> {
+    let path = fs::read_link(format!("{}{}", FD_PATH_PREFIX, file.as_raw_fd())).ok()?;
+    Some(path).filter(|path| path.is_absolute())
+}
+
+/// Rename any fields in the given VM config fields which are given under their old names in
+/// `RENAMED_FIELDS`, unless they are also given under their new names, in which case the old ones
+/// are dropped. Returns a warning for each.
+fn rename_deprecated_fields(fields: &mut Map<String, Value>) -> Vec<ConfigWarning> {
+    rename_fields(fields, &RENAMED_FIELDS)
+}
+
+/// Rename the given fields, as (old name, new name), as for `rename_deprecated_fields`.
+fn rename_fields(
+    fields: &mut Map<String, Value>,
+    renamed: &[(&'static str, &'static str)],
+) -> Vec<ConfigWarning> {
+    let mut warnings = vec![];
+    for &(name, replacement) in renamed {
+        if let Some(field) = fields.remove(name) {
+            fields.entry(replacement).or_insert(field);
+            warnings.push(ConfigWarning::DeprecatedField { name, replacement });
+        }
+    }
+    warnings
+}
+
+/// Defaults for every VM, given as fields of a VM config, which are applied beneath each VM's own
+/// config when it is loaded.
+///
+/// A field which a VM's config gives replaces the same field from the base config, including if it
+/// is given as `null`, an empty list or a different list. The exception is fields which are
+/// objects, such as `payload`, which are merged in the same way field by field.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BaseConfig {
+    fields: Map<String, Value>,
+}
+
+impl BaseConfig {
+    /// Load the base config from the given JSON file. If the file doesn't exist then there are no
+    /// defaults.
+    pub fn load(path: &Path) -> Result<BaseConfig, Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BaseConfig::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {:?}", path)),
+        };
+        let value = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse {:?}", path))?;
+        let mut fields = match value {
+            Value::Object(fields) => fields,
+            _ => bail!("Base config {:?} is not an object.", path),
+        };
+        for warning in rename_deprecated_fields(&mut fields) {
+            warn!("Base config: {}", warning);
+        }
+        serde_json::from_value::<VmConfig>(Value::Object(fields.clone()))
+            .with_context(|| format!("Invalid base config {:?}", path))?;
+        Ok(BaseConfig { fields })
+    }
+
+    /// Add the fields of the base config to the given VM config fields, where they aren't given.
+    fn apply(&self, fields: &mut Map<String, Value>) {
+        merge_beneath(&self.fields, fields);
+    }
+}
+
+/// Add the given base fields to the given fields where they aren't given, merging fields which are
+/// objects in both.
+fn merge_beneath(base: &Map<String, Value>, fields: &mut Map<String, Value>) {
+    for (name, base_value) in base {
+        match fields.get_mut(name) {
+            Some(Value::Object(value)) => {
+                if let Value::Object(base_value) = base_value {
+                    merge_beneath(base_value, value);
+                }
+            }
+            Some(_) => {}
+            None => {
+                fields.insert(name.clone(), base_value.clone());
+            }
+        }
+    }
+}
+
+/// Where the interrupt controller for the VM is emulated.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IrqChip {
+    /// Entirely in the host kernel.
+    Kernel,
+    /// The IOAPIC in crosvm, and the rest in the host kernel.
+    Split,
+    /// Entirely in crosvm.
+    Userspace,
+}
+
+/// An I/O scheduling priority for crosvm, as set by `ioprio_set`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct IoPriority {
+    /// The scheduling class.
+    pub class: IoPriorityClass,
+    /// The level within the class, from 0 for the highest priority to 7 for the lowest. The idle
+    /// class has no levels, so this must be 0 for it.
+    #[serde(default)]
+    pub level: u8,
+}
+
+impl IoPriority {
+    /// The highest level, which has the lowest priority.
+    const MAX_LEVEL: u8 = 7;
+
+    fn validate(&self) -> Result<(), Error> {
+        match self.class {
+            IoPriorityClass::Idle if self.level != 0 => {
+                bail!(
+                    "The idle I/O priority class has no levels, but level {} was given.",
+                    self.level
+                )
+            }
+            _ if self.level > IoPriority::MAX_LEVEL => {
+                bail!("I/O priority level {} is not between 0 and 7.", self.level)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the value to pass to `ioprio_set` for this priority.
+    pub fn ioprio(&self) -> i32 {
+        /// The number of bits for the level, below the class.
+        const CLASS_SHIFT: i32 = 13;
+        let class = match self.class {
+            IoPriorityClass::Realtime => 1,
+            IoPriorityClass::BestEffort => 2,
+            IoPriorityClass::Idle => 3,
+        };
+        (class << CLASS_SHIFT) | i32::from(self.level)
+    }
+}
+
+/// An I/O scheduling class.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoPriorityClass {
+    /// I/O is always served before that of other classes. This needs `CAP_SYS_ADMIN`.
+    Realtime,
+    /// The normal class.
+    BestEffort,
+    /// I/O is only served when no other process needs the disk.
+    Idle,
+}
+
+/// How a VM is paused.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseMechanism {
+    /// By asking crosvm to suspend the VM's vCPUs over its control socket.
+    ControlSocket,
+    /// By freezing the cgroup which crosvm is run in, with the cgroup v2 freezer. This stops all
+    /// of crosvm's threads, including device emulation.
+    CgroupFreezer,
+}
+
+/// How crosvm performs asynchronous I/O for its devices.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AsyncExecutor {
+    /// With io_uring, which needs support from the host kernel.
+    Uring,
+    /// With epoll, which is always available.
+    Epoll,
+}
+
+impl AsyncExecutor {
+    /// Get the name crosvm uses for the executor.
+    pub fn name(self) -> &'static str {
+        match self {
+            AsyncExecutor::Uring => "uring",
+            AsyncExecutor::Epoll => "epoll",
+        }
     }
 }
 
 /// A disk image to be made available to the VM.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct DiskImage {
+    /// An identifier for the disk, by which it can be referred to elsewhere in the config.
+    pub id: Option<String>,
     /// The filename of the disk image.
     pub image: String,
     /// Whether this disk should be writable by the VM.
     pub writable: bool,
+    /// dm-verity parameters for the guest to verify the disk's integrity, if any.
+    pub verity: Option<VerityConfig>,
+    /// The SHA-256 digest of the whole image, as a hex string. If this is given then the image is
+    /// hashed when it is opened for crosvm and rejected if it doesn't match. This reads the whole
+    /// image, so may be slow for large images.
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+impl DiskImage {
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(verity) = &self.verity {
+            if self.writable {
+                bail!("Disk {} can't be both writable and dm-verity protected.", self.image);
+            }
+            verity.validate(Path::new(&self.image))?;
+        }
+        if let Some(expected) = &self.sha256 {
+            if !is_sha256_hex(expected) {
+                bail!("Disk {} SHA-256 {:?} is not a hex digest.", self.image, expected);
+            }
+        }
+        Ok(())
+    }
+
+    /// Open the disk image, checking that it matches its SHA-256 digest if it has one. crosvm
+    /// should be given the returned file rather than the image's path, so that it gets the file
+    /// which was hashed even if the image is replaced in the meantime.
+    pub fn open_verified(&self) -> Result<File, Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(self.writable)
+            .open(&self.image)
+            .with_context(|| format!("Failed to open disk {}", self.image))?;
+        if let Some(expected) = &self.sha256 {
+            let actual = sha256_of(&mut file)
+                .with_context(|| format!("Failed to hash disk {}", self.image))?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(DiskIntegrityFailed {
+                    image: self.image.clone(),
+                    expected: expected.clone(),
+                    actual,
+                }
+                .into());
+            }
+        }
+        Ok(file)
+    }
+}
+
+/// The error returned when a config has no kernel, bootloader or disk, so the VM has nothing to
+/// boot from.
+#[derive(Debug, thiserror::Error)]
+#[error("VM has no kernel, bootloader or disk, so nothing to boot.")]
+pub struct NothingToBoot;
+
+/// The error returned when a disk image doesn't match the SHA-256 digest given for it.
+#[derive(Debug, thiserror::Error)]
+#[error("Disk {image} has SHA-256 {actual}, but {expected} was expected.")]
+pub struct DiskIntegrityFailed {
+    /// The filename of the disk image.
+    pub image: String,
+    /// The digest given in the config.
+    pub expected: String,
+    /// The digest of the image as it is on disk.
+    pub actual: String,
+}
+
+/// Compute the SHA-256 digest of the given file as a lowercase hex string.
+fn sha256_file(path: &Path) -> Result<String, Error> {
+    sha256_of(&mut File::open(path)?)
+}
+
+/// Compute the SHA-256 digest of everything read from the given reader as a lowercase hex string.
+fn sha256_of(file: &mut impl Read) -> Result<String, Error> {
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        context.update(&buffer[..read]);
+    }
+    Ok(context.finish().as_ref().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Check whether the given string is a SHA-256 digest as a hex string.
+fn is_sha256_hex(digest: &str) -> bool {
+    digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// dm-verity parameters for a read-only disk. The disk image may be shared by many VMs, each with
+/// its own root hash in its config, so that every deployment is bound to the image it expects.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct VerityConfig {
+    /// The filename of the hashtree image for the disk, without a superblock or salt.
+    pub hashtree: String,
+    /// The SHA-256 root hash of the hashtree, as a hex string.
+    pub root_hash: String,
+}
+
+impl VerityConfig {
+    /// Check that the root hash is well-formed and is that of the hashtree, for the given disk
+    /// image.
+    fn validate(&self, image: &Path) -> Result<(), Error> {
+        if !Path::new(&self.hashtree).is_file() {
+            bail!("dm-verity hashtree {} does not exist.", self.hashtree);
+        }
+        if !is_sha256_hex(&self.root_hash) {
+            bail!("dm-verity root hash {:?} is not a SHA-256 hex digest.", self.root_hash);
+        }
+        let actual = verity_root_hash(image, Path::new(&self.hashtree))?;
+        if !actual.eq_ignore_ascii_case(&self.root_hash) {
+            bail!(
+                "dm-verity root hash {} doesn't match hashtree {}, whose root hash is {}.",
+                self.root_hash,
+                self.hashtree,
+                actual
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Compute the dm-verity root hash of the given disk image and its hashtree as a lowercase hex
+/// string. dm-verity puts the top level of the hashtree first, and the root hash is the hash of
+/// that level's single block. If the image is only one block then the hashtree has no levels, and
+/// the root hash is the hash of the image's block itself.
+fn verity_root_hash(image: &Path, hashtree: &Path) -> Result<String, Error> {
+    let image_size =
+        fs::metadata(image).with_context(|| format!("Failed to get size of {:?}", image))?.len();
+    let top = if image_size <= VERITY_BLOCK_SIZE { image } else { hashtree };
+    let mut block = vec![0; VERITY_BLOCK_SIZE as usize];
+    File::open(top)
+        .and_then(|mut file| file.read_exact(&mut block))
+        .with_context(|| format!("Failed to read the first block of {:?}", top))?;
+    let hash = digest::digest(&digest::SHA256, &block);
+    Ok(hash.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Check that the given hostname is valid according to RFC 1123.
+fn validate_hostname(hostname: &str) -> Result<(), Error> {
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if hostname.len() > 253 || !hostname.split('.').all(valid_label) {
+        bail!("Invalid hostname {:?}.", hostname);
+    }
+    Ok(())
+}
+
+/// Parse a PCI address of the form `[domain:]bus:device.function` in hex, into its domain, bus,
+/// device and function numbers.
+fn parse_pci_address(address: &str) -> Result<(u16, u8, u8, u8), Error> {
+    let invalid =
+        || anyhow!("Invalid PCI address {:?}, expected [domain:]bus:device.function.", address);
+    let mut parts: Vec<&str> = address.split(':').collect();
+    if parts.len() == 2 {
+        parts.insert(0, "0");
+    }
+    let (device, function) = match parts.as_slice() {
+        [_, _, slot] => {
+            let mut slot = slot.split('.');
+            match (slot.next(), slot.next(), slot.next()) {
+                (Some(device), Some(function), None) => (device, function),
+                _ => return Err(invalid()),
+            }
+        }
+        _ => return Err(invalid()),
+    };
+    let domain = u16::from_str_radix(parts[0], 16).map_err(|_| invalid())?;
+    let bus = u8::from_str_radix(parts[1], 16).map_err(|_| invalid())?;
+    let device = u8::from_str_radix(device, 16).map_err(|_| invalid())?;
+    let function = u8::from_str_radix(function, 16).map_err(|_| invalid())?;
+    if device > 0x1f || function > 7 {
+        return Err(invalid());
+    }
+    Ok((domain, bus, device, function))
+}
+
+/// The name of the device node under which the guest will see the disk with the given index.
+fn guest_disk_device(index: usize) -> String {
+    format!("/dev/vd{}", (b'a' + index as u8) as char)
+}
+
+/// How to store the console output of a VM.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ConsoleLogConfig {
+    /// The size in bytes at which the active console log segment is rotated.
+    pub max_segment_size: u64,
+    /// Whether to compress rotated segments with gzip. The active segment is never compressed.
+    #[serde(default)]
+    pub compress: bool,
+    /// How many rotated segments to keep, besides the active one. Older segments are deleted. If
+    /// this is not specified then the Virt Manager's default is used.
+    #[serde(default)]
+    pub max_segments: Option<usize>,
+    /// The least severe kernel log level to keep, from 0 (emergency) to 7 (debug). Lines starting
+    /// with a `<N>` level prefix above this are dropped; other lines are always kept. If this is
+    /// not specified then all lines are kept.
+    #[serde(default)]
+    pub max_kernel_log_level: Option<u8>,
+}
+
+/// A service on another VM which a VM depends on.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Dependency {
+    /// The CID of the VM providing the service.
+    pub cid: Cid,
+    /// The vsock port on which the service accepts connections.
+    pub port: u32,
+}
+
+/// A payload binary for the guest to run.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PayloadConfig {
+    /// The absolute path of the binary within the guest's filesystem.
+    pub binary: String,
+    /// Arguments to pass to the binary.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Whether to give the payload a console of its own, separate from the kernel console. The
+    /// guest sees it as `/dev/hvc0`, to which the payload should write its stdout and stderr, and
+    /// clients can read what it writes with `openPayloadOutput`.
+    #[serde(default)]
+    pub separate_output: bool,
+}
+
+impl PayloadConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if !self.binary.starts_with('/') {
+            bail!("Payload binary {:?} must be an absolute path.", self.binary);
+        }
+        if self.binary.contains(char::is_whitespace)
+            || self.args.iter().any(|arg| arg.is_empty() || arg.contains(char::is_whitespace))
+        {
+            bail!("Payload binary and arguments must be non-empty and not contain whitespace.");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::fs::symlink;
+
+    fn kernel_config() -> VmConfig {
+        VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() }
+    }
+
+    #[test]
+    fn payload_is_passed_as_init() {
+        let config = VmConfig {
+            params: Some("console=hvc0".to_owned()),
+            payload: Some(PayloadConfig {
+                binary: "/system/bin/payload".to_owned(),
+                args: vec!["--verbose".to_owned(), "1".to_owned()],
+                separate_output: false,
+            }),
+            ..kernel_config()
+        };
+        config.validate().unwrap();
+        assert_eq!(
+            config.kernel_params(10).unwrap().unwrap(),
+            "console=hvc0 hostname=vm-10 init=/system/bin/payload -- --verbose 1"
+        );
+    }
+
+    #[test]
+    fn disk_sha256_is_checked() {
+        let image = std::env::temp_dir().join("virtmanager_disk_sha256_test.img");
+        fs::write(&image, "hello\n").unwrap();
+        let config = |sha256: &str| VmConfig {
+            disks: vec![DiskImage {
+                id: None,
+                image: image.to_str().unwrap().to_owned(),
+                writable: false,
+                verity: None,
+                sha256: Some(sha256.to_owned()),
+            }],
+            ..kernel_config()
+        };
+
+        let open = |sha256| {
+            let config = config(sha256);
+            config.validate().unwrap();
+            config.disks[0].open_verified()
+        };
+
+        let mut file =
+            open("5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03").unwrap();
+        open("5891B5B522D5DF086D0FF0B110FBD9D21BB4FC7163AF34D08286A2E846F6BE03").unwrap();
+        // The file which was hashed is still the one which was opened after the image is replaced.
+        fs::remove_file(&image).unwrap();
+        fs::write(&image, "goodbye\n").unwrap();
+        let mut contents = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello\n");
+
+        fs::write(&image, "hello\n").unwrap();
+        let error =
+            open("0000000000000000000000000000000000000000000000000000000000000000").unwrap_err();
+        let error = error.downcast_ref::<DiskIntegrityFailed>().unwrap();
+        assert_eq!(
+            error.actual,
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03"
+        );
+        assert!(config("not a digest").validate().is_err());
+        fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn rt_priority_must_be_in_range() {
+        assert!(VmConfig { rt_priority: Some(50), ..kernel_config() }.validate().is_ok());
+        assert!(VmConfig { rt_priority: Some(0), ..kernel_config() }.validate().is_err());
+        assert!(VmConfig { rt_priority: Some(100), ..kernel_config() }.validate().is_err());
+    }
+
+    #[test]
+    fn unknown_irqchip_mode_is_rejected() {
+        assert_eq!(serde_json::from_str::<IrqChip>("\"split\"").unwrap(), IrqChip::Split);
+        assert!(serde_json::from_str::<IrqChip>("\"hardware\"").is_err());
+    }
+
+    #[test]
+    fn hostname_is_passed_to_kernel() {
+        let config = VmConfig { hostname: Some("guest-1.example".to_owned()), ..kernel_config() };
+        config.validate().unwrap();
+        assert_eq!(config.kernel_params(10).unwrap().unwrap(), "hostname=guest-1.example");
+    }
+
+    #[test]
+    fn invalid_hostname_is_rejected() {
+        for hostname in &["", "-guest", "guest_1", "guest..example", &"a".repeat(64)] {
+            let config = VmConfig { hostname: Some(hostname.to_string()), ..kernel_config() };
+            assert!(config.validate().is_err(), "Hostname {:?} was accepted", hostname);
+        }
+    }
+
+    #[test]
+    fn verity_parameters_are_passed_to_kernel() {
+        let dir = std::env::temp_dir().join("virtmanager_verity_test");
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("system.img");
+        let hashtree = dir.join("system.hashtree");
+        fs::write(&image, vec![0; 8192]).unwrap();
+        fs::write(&hashtree, vec![0; 4096]).unwrap();
+        let root_hash = sha256_file(&hashtree).unwrap();
+        let config = VmConfig {
+            disks: vec![
+                DiskImage {
+                    id: None,
+                    image: "/dev/null".to_owned(),
+                    writable: true,
+                    verity: None,
+                    sha256: None,
+                },
+                DiskImage {
+                    id: None,
+                    image: image.to_str().unwrap().to_owned(),
+                    writable: false,
+                    verity: Some(VerityConfig {
+                        hashtree: hashtree.to_str().unwrap().to_owned(),
+                        root_hash: root_hash.clone(),
+                    }),
+                    sha256: None,
+                },
+            ],
+            ..kernel_config()
+        };
+        config.validate().unwrap();
+        assert_eq!(
+            config.kernel_params(10).unwrap().unwrap(),
+            format!(
+                "hostname=vm-10 dm-mod.create=\"verity1,,,ro,0 16 verity 1 {} {} sha256 {} -\"",
+                "/dev/vdb /dev/vdc", "4096 4096 2 0", root_hash
+            )
+        );
+        assert_eq!(config.verity_hashtrees(), [hashtree.to_str().unwrap()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn disk(id: &str) -> DiskImage {
+        DiskImage {
+            id: Some(id.to_owned()),
+            image: id.to_owned(),
+            writable: false,
+            verity: None,
+            sha256: None,
+        }
+    }
+
+    #[test]
+    fn boot_order_puts_disks_first() {
+        let config = VmConfig {
+            disks: vec![disk("data"), disk("recovery"), disk("system")],
+            boot_order: vec!["system".to_owned(), "recovery".to_owned()],
+            ..kernel_config()
+        };
+        config.validate().unwrap();
+        assert_eq!(config.device_order(), [2, 1, 0]);
+        assert_eq!(config.device_index(0), Some(2));
+    }
+
+    #[test]
+    fn boot_order_rejects_unknown_disk() {
+        let config = VmConfig {
+            disks: vec![disk("system")],
+            boot_order: vec!["vendor".to_owned()],
+            ..kernel_config()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn enabled_features_reflect_config() {
+        assert!(kernel_config().enabled_features().is_empty());
+        let config = VmConfig {
+            disks: vec![DiskImage {
+                id: None,
+                image: "/dev/null".to_owned(),
+                writable: true,
+                verity: None,
+                sha256: None,
+            }],
+            agent_port: Some(5678),
+            rt_priority: Some(10),
+            irqchip: Some(IrqChip::Split),
+            ..kernel_config()
+        };
+        assert_eq!(
+            config.enabled_features(),
+            ["writable-disk", "guest-agent", "realtime", "split-irqchip"]
+        );
+
+        let config = VmConfig {
+            cpu_model: Some("host".to_owned()),
+            async_executor: Some(AsyncExecutor::Epoll),
+            scratch_size_mib: Some(64),
+            stub_pci_devices: vec!["00:05.0".to_owned()],
+            pause_mechanism: Some(PauseMechanism::CgroupFreezer),
+            ..kernel_config()
+        };
+        assert_eq!(
+            config.enabled_features(),
+            ["cpu-model", "async-executor", "scratch-tmpfs", "stub-pci", "cgroup-freezer"]
+        );
+        // Pausing through the control socket is what happens anyway.
+        let config =
+            VmConfig { pause_mechanism: Some(PauseMechanism::ControlSocket), ..kernel_config() };
+        assert!(config.enabled_features().is_empty());
+    }
+
+    #[test]
+    fn bootconfig_needs_initrd_and_is_enabled_on_command_line() {
+        let bootconfig: BTreeMap<String, String> =
+            vec![("androidboot.hardware".to_owned(), "virt".to_owned())].into_iter().collect();
+        let config = VmConfig { bootconfig: bootconfig.clone(), ..kernel_config() };
+        assert!(config.validate().is_err());
+
+        let config = VmConfig { initrd: Some("/data/local/tmp/initrd".to_owned()), ..config };
+        config.validate().unwrap();
+        assert!(config.kernel_params(10).unwrap().unwrap().split(' ').any(|p| p == "bootconfig"));
+        assert_eq!(config.enabled_features(), ["initrd", "bootconfig"]);
+
+        let invalid = VmConfig {
+            bootconfig: vec![("not a key".to_owned(), "virt".to_owned())].into_iter().collect(),
+            ..config
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn entropy_seed_needs_initrd_and_its_own_key() {
+        let config = VmConfig { entropy_seed: true, ..kernel_config() };
+        assert!(config.validate().is_err());
+
+        let config = VmConfig { initrd: Some("/data/local/tmp/initrd".to_owned()), ..config };
+        config.validate().unwrap();
+        assert!(config.kernel_params(10).unwrap().unwrap().split(' ').any(|p| p == "bootconfig"));
+        assert_eq!(config.enabled_features(), ["initrd", "entropy-seed"]);
+
+        let clashing = VmConfig {
+            bootconfig: vec![(bootconfig::ENTROPY_SEED_KEY.to_owned(), "0".to_owned())]
+                .into_iter()
+                .collect(),
+            ..config
+        };
+        assert!(clashing.validate().is_err());
+    }
+
+    #[test]
+    fn protected_vm_cant_have_bootloader() {
+        let protection = MemProtectionConfig { protected: true, without_firmware: false };
+        let config = VmConfig { memory_protection: Some(protection), ..kernel_config() };
+        config.validate().unwrap();
+        assert_eq!(config.enabled_features(), ["protected-vm"]);
+
+        let config =
+            VmConfig { bootloader: Some("/data/local/tmp/bootloader".to_owned()), ..config };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn config_must_have_something_to_boot() {
+        let error = VmConfig::default().validate().unwrap_err();
+        assert!(error.downcast_ref::<NothingToBoot>().is_some());
+        // Disks alone aren't enough, but for a different reason.
+        let disk_only = VmConfig {
+            disks: vec![DiskImage {
+                id: None,
+                image: "/dev/null".to_owned(),
+                writable: false,
+                verity: None,
+                sha256: None,
+            }],
+            ..Default::default()
+        };
+        assert!(disk_only.validate().unwrap_err().downcast_ref::<NothingToBoot>().is_none());
+
+        kernel_config().validate().unwrap();
+        let bootloader_only = VmConfig {
+            bootloader: Some("/data/local/tmp/bootloader".to_owned()),
+            ..Default::default()
+        };
+        bootloader_only.validate().unwrap();
+    }
+
+    #[test]
+    fn io_priority_level_must_suit_class() {
+        let config = |class, level| VmConfig {
+            io_priority: Some(IoPriority { class, level }),
+            ..kernel_config()
+        };
+        assert!(config(IoPriorityClass::BestEffort, 7).validate().is_ok());
+        assert!(config(IoPriorityClass::BestEffort, 8).validate().is_err());
+        assert!(config(IoPriorityClass::Idle, 0).validate().is_ok());
+        assert!(config(IoPriorityClass::Idle, 4).validate().is_err());
+        assert_eq!(
+            IoPriority { class: IoPriorityClass::BestEffort, level: 4 }.ioprio(),
+            (2 << 13) | 4
+        );
+    }
+
+    #[test]
+    fn verity_root_hash_must_match_hashtree() {
+        let dir = std::env::temp_dir().join("virtmanager_verity_root_hash_test");
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("base.img");
+        let hashtree = dir.join("base.hashtree");
+        fs::write(&image, vec![1; 8192]).unwrap();
+        fs::write(&hashtree, vec![2; 4096]).unwrap();
+        let small_image = dir.join("small.img");
+        fs::write(&small_image, vec![3; 4096]).unwrap();
+        let verity = |root_hash: String| VerityConfig {
+            hashtree: hashtree.to_str().unwrap().to_owned(),
+            root_hash,
+        };
+
+        let tree_hash = sha256_file(&hashtree).unwrap();
+        verity(tree_hash.to_uppercase()).validate(&image).unwrap();
+        assert!(verity("ab".repeat(32)).validate(&image).is_err());
+        // A single block image has no hashtree levels, so its root hash is that of its block.
+        assert!(verity(tree_hash).validate(&small_image).is_err());
+        verity(sha256_file(&small_image).unwrap()).validate(&small_image).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn verity_rejects_malformed_root_hash() {
+        let config = VmConfig {
+            disks: vec![DiskImage {
+                id: None,
+                image: "/dev/null".to_owned(),
+                writable: false,
+                verity: Some(VerityConfig {
+                    hashtree: "/dev/null".to_owned(),
+                    root_hash: "not hex".to_owned(),
+                }),
+                sha256: None,
+            }],
+            ..kernel_config()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn config_records_where_it_was_loaded_from() {
+        let path = std::env::temp_dir().join("virtmanager_source_path_test.json");
+        fs::write(&path, r#"{"kernel": "/data/local/tmp/kernel"}"#).unwrap();
+
+        let config = VmConfig::load(&File::open(&path).unwrap(), &Default::default()).unwrap();
+        let expected = path.canonicalize().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.source_path, Some(expected));
+    }
+
+    #[test]
+    fn deprecated_field_is_renamed_with_warning() {
+        // No fields have been renamed yet, so pretend one has.
+        let renamed = [("small_platform", "minimal_platform")];
+        let fields = |json: &str| match serde_json::from_str(json).unwrap() {
+            Value::Object(fields) => fields,
+            _ => unreachable!(),
+        };
+
+        let mut old = fields(r#"{"kernel": "/data/local/tmp/kernel", "small_platform": true}"#);
+        let warnings = rename_fields(&mut old, &renamed);
+        let config: VmConfig = serde_json::from_value(Value::Object(old)).unwrap();
+        assert!(config.minimal_platform);
+        assert_eq!(
+            warnings,
+            [ConfigWarning::DeprecatedField {
+                name: "small_platform",
+                replacement: "minimal_platform"
+            }]
+        );
+
+        // The new name wins if both are given.
+        let mut both = fields(r#"{"small_platform": true, "minimal_platform": false}"#);
+        assert_eq!(rename_fields(&mut both, &renamed).len(), 1);
+        assert_eq!(both.get("minimal_platform"), Some(&Value::Bool(false)));
+        assert!(!both.contains_key("small_platform"));
+    }
+
+    #[test]
+    fn base_config_applies_beneath_vm_config() {
+        let dir = std::env::temp_dir().join("virtmanager_base_config_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let base_path = dir.join("base.json");
+        fs::write(
+            &base_path,
+            r#"{"memory_mib": 512, "cpu_features": ["no-smt"], "payload": {"binary": "/init"}}"#,
+        )
+        .unwrap();
+        let base = BaseConfig::load(&base_path).unwrap();
+        let load = |json: &str| {
+            let path = dir.join("vm.json");
+            fs::write(&path, json).unwrap();
+            VmConfig::load(&File::open(&path).unwrap(), &base).unwrap()
+        };
+
+        let config = load(r#"{"kernel": "/kernel"}"#);
+        assert_eq!(config.memory_mib, Some(512));
+        assert_eq!(config.cpu_features, ["no-smt"]);
+
+        let config = load(
+            r#"{"kernel": "/kernel", "memory_mib": 1024, "cpu_features": [],
+                "payload": {"args": ["-v"]}}"#,
+        );
+        assert_eq!(config.memory_mib, Some(1024));
+        assert!(config.cpu_features.is_empty());
+        let payload = config.payload.unwrap();
+        assert_eq!(payload.binary, "/init");
+        assert_eq!(payload.args, ["-v"]);
+
+        // A missing base config has no defaults.
+        assert_eq!(BaseConfig::load(&dir.join("missing.json")).unwrap(), BaseConfig::default());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bundle_paths_are_resolved_against_root() {
+        let bundle = std::env::temp_dir().join("virtmanager_bundle_test");
+        let _ = fs::remove_dir_all(&bundle);
+        fs::create_dir_all(bundle.join("images")).unwrap();
+        fs::write(bundle.join("kernel"), "").unwrap();
+        fs::write(bundle.join("images/disk.img"), "").unwrap();
+        fs::write(
+            bundle.join(BUNDLE_CONFIG_FILE),
+            r#"{
+                "kernel": "kernel",
+                "disks": [{"image": "images/disk.img", "writable": false}],
+                "pflash": "images/pflash.img"
+            }"#,
+        )
+        .unwrap();
+
+        let config = VmConfig::load_bundle(&bundle, &Default::default()).unwrap();
+
+        let root = bundle.canonicalize().unwrap();
+        assert_eq!(config.kernel.unwrap(), root.join("kernel").to_str().unwrap());
+        assert_eq!(config.disks[0].image, root.join("images/disk.img").to_str().unwrap());
+        // The pflash file doesn't exist yet, but is still within the bundle.
+        assert_eq!(config.pflash.unwrap(), root.join("images/pflash.img").to_str().unwrap());
+        fs::remove_dir_all(&bundle).unwrap();
+    }
+
+    #[test]
+    fn bundle_paths_must_be_within_bundle() {
+        let bundle = std::env::temp_dir().join("virtmanager_bundle_escape_test");
+        let _ = fs::remove_dir_all(&bundle);
+        fs::create_dir_all(&bundle).unwrap();
+        fs::write(bundle.join(BUNDLE_CONFIG_FILE), r#"{"kernel": "/proc/self/exe"}"#).unwrap();
+        assert!(VmConfig::load_bundle(&bundle, &Default::default()).is_err());
+
+        // Nor can a pflash file be outside the bundle, behind a link out of it, or in a directory
+        // which doesn't exist.
+        fs::write(bundle.join("kernel"), "").unwrap();
+        symlink("/data/local/tmp/pflash.img", bundle.join("link.img")).unwrap();
+        let invalid = ["../pflash.img", "/data/local/tmp/pflash.img", "link.img", "images/pflash"];
+        for pflash in &invalid {
+            let config = format!(r#"{{"kernel": "kernel", "pflash": "{}"}}"#, pflash);
+            fs::write(bundle.join(BUNDLE_CONFIG_FILE), config).unwrap();
+            assert!(
+                VmConfig::load_bundle(&bundle, &Default::default()).is_err(),
+                "pflash {} was accepted",
+                pflash
+            );
+        }
+        fs::remove_dir_all(&bundle).unwrap();
+    }
+
+    #[test]
+    fn stub_pci_devices_are_validated() {
+        assert_eq!(parse_pci_address("00:1f.7").unwrap(), (0, 0, 0x1f, 7));
+        assert_eq!(parse_pci_address("0001:02:03.4").unwrap(), (1, 2, 3, 4));
+        for invalid in &["00:20.0", "00:01.8", "00:01", "1:2:3:4.0", "zz:01.0", "00:01.0.0"] {
+            assert!(parse_pci_address(invalid).is_err(), "{} should be invalid", invalid);
+        }
+
+        let config = VmConfig {
+            stub_pci_devices: vec!["00:05.0".to_owned(), "0000:00:06.0".to_owned()],
+            ..kernel_config()
+        };
+        assert!(config.validate().is_ok());
+        let config = VmConfig {
+            stub_pci_devices: vec!["00:05.0".to_owned(), "0000:00:05.0".to_owned()],
+            ..kernel_config()
+        };
+        assert!(config.validate().is_err());
+        let config =
+            VmConfig { stub_pci_devices: vec!["0000:00:00.0".to_owned()], ..kernel_config() };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn payload_requires_absolute_path() {
+        let config = VmConfig {
+            payload: Some(PayloadConfig {
+                binary: "payload".to_owned(),
+                args: vec![],
+                separate_output: false,
+            }),
+            ..kernel_config()
+        };
+        assert!(config.validate().is_err());
+    }
 }
@@ -0,0 +1,453 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Capture of a VM's console output into rotated log segments in its runtime directory.
+
+use crate::config::ConsoleLogConfig;
+use anyhow::{Context, Error};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::error;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// The file in the runtime directory to which the console output of the VM is written, if the
+/// client didn't provide its own log file descriptor. When the log is rotated this is the active
+/// segment.
+pub const CONSOLE_LOG_FILE: &str = "console.log";
+
+/// The file in the runtime directory to which the output of the VM's payload is written, if the
+/// payload has a console of its own.
+pub const PAYLOAD_OUTPUT_FILE: &str = "payload.log";
+
+/// How many rotated console log segments are kept if the config doesn't say.
+const DEFAULT_MAX_SEGMENTS: usize = 8;
+
+/// A destination for console output, which is written to it a whole line at a time.
+pub trait LineSink: Send {
+    /// Write a line of console output, including its newline if it had one.
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()>;
+}
+
+impl LineSink for File {
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        self.write_all(line)
+    }
+}
+
+/// Writes console output to another sink until a limit on the total number of bytes written is
+/// reached, then writes a final line saying that the output was truncated and drops the rest. Lines
+/// are never split, so the line which would go over the limit is the first to be dropped.
+pub struct Truncating {
+    sink: Box<dyn LineSink>,
+    max_bytes: u64,
+    written: u64,
+    truncated: bool,
+}
+
+impl Truncating {
+    /// Write up to the given number of bytes to the given sink.
+    pub fn new(sink: Box<dyn LineSink>, max_bytes: u64) -> Truncating {
+        Truncating { sink, max_bytes, written: 0, truncated: false }
+    }
+}
+
+impl LineSink for Truncating {
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.truncated {
+            return Ok(());
+        }
+        if self.written + line.len() as u64 > self.max_bytes {
+            self.truncated = true;
+            let marker = format!("log truncated after {} bytes\n", self.written);
+            return self.sink.write_line(marker.as_bytes());
+        }
+        self.sink.write_line(line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Console output of a VM, written to a series of segments. Once the active segment reaches its
+/// maximum size it is renamed to `console.<n>.log`, or compressed to `console.<n>.log.gz`, and a
+/// new active segment is started. Only the most recent rotated segments are kept.
+#[derive(Debug)]
+pub struct ConsoleLog {
+    dir: PathBuf,
+    config: ConsoleLogConfig,
+    active: File,
+    active_size: u64,
+    next_segment: usize,
+}
+
+impl ConsoleLog {
+    /// Start a new console log in the given directory.
+    pub fn create(dir: &Path, config: &ConsoleLogConfig) -> io::Result<ConsoleLog> {
+        Ok(ConsoleLog {
+            dir: dir.to_owned(),
+            config: config.clone(),
+            active: File::create(dir.join(CONSOLE_LOG_FILE))?,
+            active_size: 0,
+            next_segment: 0,
+        })
+    }
+
+    /// Retire the active segment, compressing it if configured to, and start a new one. If that
+    /// leaves more rotated segments than configured then the oldest is deleted.
+    fn rotate(&mut self) -> io::Result<()> {
+        let active_path = self.dir.join(CONSOLE_LOG_FILE);
+        let segment_path = segment_path(&self.dir, self.next_segment, false);
+        self.next_segment += 1;
+        fs::rename(&active_path, &segment_path)?;
+        self.active = File::create(&active_path)?;
+        self.active_size = 0;
+        if self.config.compress {
+            compress(&segment_path)?;
+        }
+        let max_segments = self.config.max_segments.unwrap_or(DEFAULT_MAX_SEGMENTS);
+        if let Some(oldest) = self.next_segment.checked_sub(max_segments + 1) {
+            fs::remove_file(segment_path(&self.dir, oldest, self.config.compress))?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the path of the rotated console log segment with the given number in the given directory.
+fn segment_path(dir: &Path, segment: usize, compressed: bool) -> PathBuf {
+    let extension = if compressed { ".gz" } else { "" };
+    dir.join(format!("console.{}.log{}", segment, extension))
+}
+
+/// Read up to the given number of bytes from the end of the console log in the given directory,
+/// including from rotated segments if the active segment is shorter than that. Compressed segments
+/// are decompressed.
+pub fn read_tail(dir: &Path, max_size: u64) -> io::Result<Vec<u8>> {
+    let mut active = File::open(dir.join(CONSOLE_LOG_FILE))?;
+    let len = active.metadata()?.len();
+    active.seek(SeekFrom::Start(len.saturating_sub(max_size)))?;
+    let mut tail = vec![];
+    active.read_to_end(&mut tail)?;
+
+    // Rotated segments are numbered from oldest to newest, so read back from the newest.
+    let mut segments: Vec<(usize, PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?;
+            let number = name.strip_prefix("console.")?;
+            let number = number.strip_suffix(".log.gz").or_else(|| number.strip_suffix(".log"))?;
+            Some((number.parse().ok()?, path))
+        })
+        .collect();
+    segments.sort();
+    for (_, path) in segments.iter().rev() {
+        if tail.len() as u64 >= max_size {
+            break;
+        }
+        let mut segment = vec![];
+        if path.extension() == Some("gz".as_ref()) {
+            GzDecoder::new(File::open(path)?).read_to_end(&mut segment)?;
+        } else {
+            File::open(path)?.read_to_end(&mut segment)?;
+        }
+        segment.extend_from_slice(&tail);
+        tail = segment;
+    }
+    let excess = (tail.len() as u64).saturating_sub(max_size) as usize;
+    tail.drain(..excess);
+    Ok(tail)
+}
+
+impl LineSink for ConsoleLog {
+    /// Append a line to the log, first rotating the active segment if the line would take it over
+    /// the maximum size. Lines are never split across segments. Kernel log lines less severe than
+    /// the configured level are dropped.
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if let (Some(max_level), Some(level)) =
+            (self.config.max_kernel_log_level, kernel_log_level(line))
+        {
+            if level > max_level {
+                return Ok(());
+            }
+        }
+        if self.active_size > 0
+            && self.active_size + line.len() as u64 > self.config.max_segment_size
+        {
+            self.rotate()?;
+        }
+        self.active.write_all(line)?;
+        self.active_size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Writes a line to the Android log with the given tag.
+type LogWriter = Box<dyn FnMut(&CStr, &CStr) + Send>;
+
+/// Mirrors console output into the Android log, with one log entry per line.
+pub struct Logcat {
+    tag: CString,
+    write: LogWriter,
+}
+
+impl Logcat {
+    /// Mirror console output into the Android log under the given tag.
+    pub fn new(tag: &str) -> Result<Logcat, Error> {
+        Logcat::with_writer(
+            tag,
+            Box::new(|tag, text| {
+                // Safe because we pass valid C strings, which liblog doesn't keep.
+                unsafe {
+                    android_log_sys::__android_log_write(
+                        android_log_sys::LogPriority::INFO as libc::c_int,
+                        tag.as_ptr(),
+                        text.as_ptr(),
+                    );
+                }
+            }),
+        )
+    }
+
+    fn with_writer(tag: &str, write: LogWriter) -> Result<Logcat, Error> {
+        let tag = CString::new(tag).context("Log tag contains a NUL character")?;
+        Ok(Logcat { tag, write })
+    }
+}
+
+impl LineSink for Logcat {
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        // NUL characters can't be logged, so replace them rather than dropping the line.
+        let text: Vec<u8> = line.iter().map(|&c| if c == 0 { b'?' } else { c }).collect();
+        let text = CString::new(text).expect("NUL characters were replaced");
+        (self.write)(&self.tag, &text);
+        Ok(())
+    }
+}
+
+/// Get the level of a kernel log line from its `<N>` prefix, or `None` if it doesn't have one.
+fn kernel_log_level(line: &[u8]) -> Option<u8> {
+    match line {
+        [b'<', level @ b'0'..=b'7', b'>', ..] => Some(level - b'0'),
+        _ => None,
+    }
+}
+
+/// Compress the given file with gzip, replacing it with a file of the same name plus `.gz`.
+fn compress(path: &Path) -> io::Result<()> {
+    let mut compressed_path = path.as_os_str().to_owned();
+    compressed_path.push(".gz");
+    let mut encoder = GzEncoder::new(File::create(compressed_path)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+/// Spawn a thread which copies console output from the given stream into each of the given sinks,
+/// line by line, until the stream is closed.
+pub fn capture(console: impl Read + Send + 'static, sinks: Vec<Box<dyn LineSink>>) {
+    thread::spawn(move || copy_lines(console, sinks));
+}
+
+/// Copy whole lines from the given stream into each of the given sinks until the stream is closed.
+/// A final line without a newline is copied as it is. If writing to a sink fails then it is
+/// dropped, and the others carry on. The stream is read to the end even if every sink has been
+/// dropped, so that crosvm doesn't block writing to it.
+fn copy_lines(console: impl Read, mut sinks: Vec<Box<dyn LineSink>>) {
+    let mut console = BufReader::new(console);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        match console.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {
+                sinks = sinks
+                    .into_iter()
+                    .filter_map(|mut sink| match sink.write_line(&line) {
+                        Ok(()) => Some(sink),
+                        Err(e) => {
+                            error!("Failed to write console output: {}", e);
+                            None
+                        }
+                    })
+                    .collect();
+            }
+            Err(e) => {
+                error!("Failed to read console output: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn rotated_segment_is_compressed() {
+        let dir = env::temp_dir().join("virtmanager_console_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config = ConsoleLogConfig {
+            max_segment_size: 16,
+            compress: true,
+            max_segments: None,
+            max_kernel_log_level: None,
+        };
+        let mut log = ConsoleLog::create(&dir, &config).unwrap();
+
+        for line in &["first line\n", "second line\n", "third line\n"] {
+            log.write_line(line.as_bytes()).unwrap();
+        }
+
+        assert!(!dir.join("console.0.log").exists());
+        let mut first = String::new();
+        GzDecoder::new(File::open(dir.join("console.0.log.gz")).unwrap())
+            .read_to_string(&mut first)
+            .unwrap();
+        assert_eq!(first, "first line\n");
+        let mut second = String::new();
+        GzDecoder::new(File::open(dir.join("console.1.log.gz")).unwrap())
+            .read_to_string(&mut second)
+            .unwrap();
+        assert_eq!(second, "second line\n");
+        assert_eq!(fs::read_to_string(dir.join(CONSOLE_LOG_FILE)).unwrap(), "third line\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oldest_segments_are_deleted_and_tail_spans_segments() {
+        let dir = env::temp_dir().join("virtmanager_console_segments_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for &compress in &[false, true] {
+            let config = ConsoleLogConfig {
+                max_segment_size: 8,
+                compress,
+                max_segments: Some(2),
+                max_kernel_log_level: None,
+            };
+            let mut log = ConsoleLog::create(&dir, &config).unwrap();
+            for line in &["line 0\n", "line 1\n", "line 2\n", "line 3\n", "line 4\n"] {
+                log.write_line(line.as_bytes()).unwrap();
+            }
+
+            // Lines 0 and 1 were in segments which have been deleted.
+            assert!(!segment_path(&dir, 0, compress).exists());
+            assert!(!segment_path(&dir, 1, compress).exists());
+            assert!(segment_path(&dir, 2, compress).exists());
+            assert_eq!(read_tail(&dir, 9).unwrap(), b"3\nline 4\n");
+            assert_eq!(read_tail(&dir, 1 << 10).unwrap(), b"line 2\nline 3\nline 4\n");
+            fs::remove_dir_all(&dir).unwrap();
+            fs::create_dir_all(&dir).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn kernel_log_lines_below_threshold_are_dropped() {
+        let dir = env::temp_dir().join("virtmanager_console_level_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config = ConsoleLogConfig {
+            max_segment_size: 1 << 20,
+            compress: false,
+            max_segments: None,
+            max_kernel_log_level: Some(4),
+        };
+        let mut log = ConsoleLog::create(&dir, &config).unwrap();
+
+        for line in &[
+            "<0>panic\n",
+            "<3>error\n",
+            "<4>warning\n",
+            "<6>info\n",
+            "<7>debug\n",
+            "plain output\n",
+            "<9>not a level\n",
+        ] {
+            log.write_line(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(
+            fs::read_to_string(dir.join(CONSOLE_LOG_FILE)).unwrap(),
+            "<0>panic\n<3>error\n<4>warning\nplain output\n<9>not a level\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn output_is_truncated_at_limit() {
+        let path = env::temp_dir().join("virtmanager_console_truncate_test.log");
+        let log = Box::new(File::create(&path).unwrap());
+        let console = "first line\nsecond line\nthird line\nfourth line\n".as_bytes();
+
+        copy_lines(console, vec![Box::new(Truncating::new(log, 30))]);
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "first line\nsecond line\nlog truncated after 23 bytes\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn console_is_drained_after_sinks_fail() {
+        struct Failing;
+        impl LineSink for Failing {
+            fn write_line(&mut self, _line: &[u8]) -> io::Result<()> {
+                Err(io::Error::new(io::ErrorKind::Other, "sink failed"))
+            }
+        }
+        let output = "first line\nsecond line\nthird line\n";
+        let mut console = io::Cursor::new(output.as_bytes());
+
+        copy_lines(&mut console, vec![Box::new(Failing)]);
+
+        assert_eq!(console.position(), output.len() as u64);
+    }
+
+    #[test]
+    fn lines_are_mirrored_to_logcat_with_tag() {
+        let logged = Arc::new(Mutex::new(vec![]));
+        let logcat = {
+            let logged = logged.clone();
+            Logcat::with_writer(
+                "crosvm-test",
+                Box::new(move |tag, text| {
+                    let entry =
+                        (tag.to_str().unwrap().to_owned(), text.to_str().unwrap().to_owned());
+                    logged.lock().unwrap().push(entry);
+                }),
+            )
+            .unwrap()
+        };
+        // Split the output part way through lines, as it may be when read from a pipe.
+        let console = "first li".as_bytes().chain("ne\nsecond line\r\nunfinished".as_bytes());
+
+        copy_lines(console, vec![Box::new(logcat)]);
+
+        let entries = |lines: &[&str]| -> Vec<(String, String)> {
+            lines.iter().map(|line| ("crosvm-test".to_owned(), (*line).to_owned())).collect()
+        };
+        assert_eq!(*logged.lock().unwrap(), entries(&["first line", "second line", "unfinished"]));
+    }
+}
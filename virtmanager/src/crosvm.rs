@@ -15,24 +15,261 @@
 //! Functions for running instances of `crosvm`.
 
 use crate::aidl::VirtualMachineCallbacks;
-use crate::config::VmConfig;
-use crate::Cid;
-use anyhow::Error;
-use log::{error, info};
+use crate::balloon::Balloon;
+use crate::binaries::CrosvmBinary;
+use crate::bootconfig;
+use crate::cgroup::Cgroup;
+use crate::config::{ConfigWarning, Dependency, IrqChip, PauseMechanism, VmConfig};
+use crate::console::{
+    self, ConsoleLog, LineSink, Logcat, Truncating, CONSOLE_LOG_FILE, PAYLOAD_OUTPUT_FILE,
+};
+use crate::diagnostics::TarWriter;
+use crate::health::Heartbeat;
+use crate::process::{
+    check_numa_node_exists, check_open_files_limit_allowed, check_realtime_priority_allowed,
+    pass_file, set_io_priority, set_numa_node, set_open_files_limit, set_realtime_priority,
+    MountIsolation,
+};
+use crate::retry::{self, VsockRetry, VsockUnavailable};
+use crate::runtime_dir;
+use crate::teardown::{Teardown, TeardownStage};
+use crate::usage::{UsageSample, UsageTracker};
+use crate::vsock::{self, ConnectionKind, ConnectionTracker};
+use crate::{Cid, FIRST_GUEST_CID};
+use anyhow::{bail, Context, Error};
+use log::{error, log, warn, Level};
+use shared_child::unix::SharedChildExt;
 use shared_child::SharedChild;
-use std::fs::File;
-use std::process::Command;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const CROSVM_PATH: &str = "/apex/com.android.virt/bin/crosvm";
+/// The prefix of paths which refer to file descriptors open in our own process.
+const FD_PATH_PREFIX: &str = "/proc/self/fd/";
+
+/// What file descriptor paths are replaced with in reported command lines.
+const REDACTED_FD_PATH: &str = "<redacted fd>";
+
+/// The file in the runtime directory to which crosvm's own logs are written.
+const CROSVM_LOG_FILE: &str = "crosvm.log";
+
+/// The crosvm control socket in the runtime directory.
+const CONTROL_SOCKET_FILE: &str = "crosvm.sock";
+
+/// How many of each VM's most recent lifecycle states to remember, for diagnostics.
+const MAX_STATE_HISTORY: usize = 32;
+
+/// How much of the end of the console log to include in diagnostic bundles, in bytes.
+const DIAGNOSTICS_CONSOLE_TAIL_SIZE: u64 = 64 << 10;
+
+/// How much of the end of crosvm's own log to include in diagnostic bundles, in bytes.
+const DIAGNOSTICS_CROSVM_LOG_TAIL_SIZE: u64 = 256 << 10;
+
+/// Parts of the names of environment variables whose values are redacted in diagnostic bundles.
+const SENSITIVE_ENV_MARKERS: [&str; 5] = ["CREDENTIAL", "KEY", "PASSWORD", "SECRET", "TOKEN"];
+
+/// The marker which replaces the values of sensitive environment variables.
+const REDACTED_ENV_VALUE: &str = "<redacted>";
+
+/// The directory in the runtime directory containing the link to the crosvm binary which is run,
+/// if the crosvm process is named after the VM.
+const PROCESS_LINK_DIR: &str = "bin";
+
+/// The longest process name the kernel keeps, in bytes.
+const MAX_PROCESS_NAME_LEN: usize = 15;
+
+/// How long to wait for the guest to power off after its agent has accepted a shutdown request.
+const GUEST_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for crosvm to exit after sending it SIGTERM.
+const SIGTERM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Timeout for each read or write when talking to the guest agent.
+const AGENT_IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often to check whether crosvm has exited while waiting for it to stop.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The size of pflash files created for VMs, which is enough for the variable stores of common UEFI
+/// firmware.
+const PFLASH_SIZE: u64 = 4 << 20;
+
+/// The verbosity of crosvm's own logs if the config doesn't specify one.
+const DEFAULT_CROSVM_LOG_LEVEL: &str = "warn";
+
+/// How long to wait for the services a VM depends on to become reachable.
+const DEPENDENCY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often to try connecting to the services a VM depends on while waiting for them.
+const DEPENDENCY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long crosvm must keep running after it is launched, when vsock retries are enabled, to be
+/// taken to have started successfully, having opened the vhost-vsock device and set up all its
+/// devices.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// The errors with which crosvm reports that it failed to set up a device, and the name of the
+/// device for each. crosvm follows each with a colon and the underlying error.
+const DEVICE_INIT_FAILURES: [(&str, &str); 12] = [
+    ("failed to create balloon", "balloon"),
+    ("failed to create block device", "block"),
+    ("failed to create console device", "console"),
+    ("failed to create gpu device", "gpu"),
+    ("failed to set up input device", "input"),
+    ("failed to set up virtio networking", "net"),
+    ("failed to create pmem device", "pmem"),
+    ("failed to set up rng", "rng"),
+    ("failed to create sound device", "sound"),
+    ("failed to create tpm device", "tpm"),
+    ("failed to set up virtual socket device", "vsock"),
+    ("failed to create wayland device", "wayland"),
+];
+
+/// Phrases which crosvm logs when a guest driver acknowledges virtio device features which the
+/// device didn't offer, meaning that the guest kernel and device failed to negotiate.
+const NEGOTIATION_FAILURE_MARKERS: [&str; 2] = ["unknown feature", "unsupported feature"];
+
+/// The signals which `simulate_crash` may send to crosvm, all of which kill it without giving it a
+/// chance to clean up.
+const CRASH_SIGNALS: [libc::c_int; 5] =
+    [libc::SIGABRT, libc::SIGBUS, libc::SIGILL, libc::SIGKILL, libc::SIGSEGV];
+
+/// The directory on which the root directory of crosvm is mounted for VMs whose mounts are
+/// isolated. Each VM's crosvm has its own mount namespace, so they can all share this.
+const ISOLATED_ROOT_DIR: &str = "/data/misc/virtmanager_root";
+
+/// Parts of the host which crosvm needs even when its mounts are isolated, if they exist: the
+/// libraries and linker config it is run with, devices such as KVM, and procfs.
+const ISOLATED_HOST_PATHS: [&str; 5] = ["/apex", "/dev", "/linkerconfig", "/proc", "/system"];
+
+/// How often the monitor thread samples the resource usage of crosvm while it is running.
+const USAGE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why a VM stopped running.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StopReason {
+    /// A client asked for the VM to be stopped before crosvm exited.
+    Requested,
+    /// crosvm exited on its own, such as because the guest powered off or crashed.
+    Exited,
+    /// The VM was stopped to relieve memory pressure on the host.
+    HostMemoryPressure,
+    /// The VM was stopped because services it depends on didn't become reachable in time.
+    DependencyTimeout,
+}
+
+/// The lifecycle of a VM, shared by the monitor thread, the control methods and the stop path so
+/// that every transition happens exactly once, and whichever of stopping and exiting happens first
+/// determines how the VM is reported to have stopped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Lifecycle {
+    /// crosvm is being started.
+    Starting,
+    /// crosvm is running and nobody has asked for it to stop.
+    Running,
+    /// crosvm has suspended the VM's vCPUs.
+    Paused,
+    /// A stop has been requested for the given reason, but crosvm hasn't exited yet.
+    Stopping(StopReason),
+    /// crosvm has exited and the terminal event has been reported.
+    Stopped(StopReason),
+}
+
+/// State machine for a VM's lifecycle, guarded by a mutex, along with when it entered each of its
+/// most recent `MAX_STATE_HISTORY` states. The last entry is the current state.
+#[derive(Debug)]
+struct VmState(Mutex<VecDeque<(SystemTime, Lifecycle)>>);
+
+impl VmState {
+    fn new() -> VmState {
+        VmState(Mutex::new(iter::once((SystemTime::now(), Lifecycle::Starting)).collect()))
+    }
+
+    /// Move to the given state if currently in one of the given states. Returns the new state if
+    /// it changed, or `None` if the transition wasn't allowed.
+    fn transition(&self, from: &[Lifecycle], to: Lifecycle) -> Option<Lifecycle> {
+        let history = &mut *self.0.lock().unwrap();
+        if from.contains(&current(history)) {
+            enter(history, to);
+            Some(to)
+        } else {
+            None
+        }
+    }
+
+    /// Record that crosvm has exited. Returns the new state the first time this is called, in
+    /// which case the caller should report the terminal event, and `None` thereafter.
+    fn exited(&self) -> Option<Lifecycle> {
+        let history = &mut *self.0.lock().unwrap();
+        let reason = match current(history) {
+            Lifecycle::Starting | Lifecycle::Running | Lifecycle::Paused => StopReason::Exited,
+            Lifecycle::Stopping(reason) => reason,
+            Lifecycle::Stopped(_) => return None,
+        };
+        enter(history, Lifecycle::Stopped(reason));
+        Some(Lifecycle::Stopped(reason))
+    }
+
+    /// Get the current state.
+    fn get(&self) -> Lifecycle {
+        current(&self.0.lock().unwrap())
+    }
+
+    /// Return whether crosvm has not yet been seen to exit.
+    fn running(&self) -> bool {
+        !matches!(self.get(), Lifecycle::Stopped(_))
+    }
+
+    /// Get the most recent states along with when they were entered, oldest first.
+    fn history(&self) -> Vec<(SystemTime, Lifecycle)> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Get the current state from the given state history.
+fn current(history: &VecDeque<(SystemTime, Lifecycle)>) -> Lifecycle {
+    history.back().expect("State history is never empty").1
+}
+
+/// Add the given state to the given state history, dropping the oldest if it is full.
+fn enter(history: &mut VecDeque<(SystemTime, Lifecycle)>, lifecycle: Lifecycle) {
+    if history.len() == MAX_STATE_HISTORY {
+        history.pop_front();
+    }
+    history.push_back((SystemTime::now(), lifecycle));
+}
 
 /// Information about a particular instance of a VM which is running.
 #[derive(Debug)]
 pub struct VmInstance {
     /// The crosvm child process.
-    child: SharedChild,
+    child: Arc<SharedChild>,
+    /// The configuration the VM was started with.
+    pub config: VmConfig,
+    /// The crosvm binary running the VM, which is also used to send it control commands.
+    crosvm_path: PathBuf,
+    /// The program and arguments crosvm was run with, with file descriptor paths redacted.
+    pub command_line: Vec<String>,
+    /// The environment variables crosvm was run with on top of those it inherited, as
+    /// `NAME=value`, with sensitive values redacted.
+    environment: Vec<String>,
+    /// The cgroup crosvm was run in, if the VM is paused by freezing it.
+    cgroup: Option<Arc<Cgroup>>,
+    /// The wall-clock time at which the VM was started, just before crosvm was spawned.
+    pub start_time: SystemTime,
+    /// The directory containing the VM's logs and other files. This is moved to where logs are
+    /// retained when the VM stops.
+    pub runtime_dir: PathBuf,
     /// The CID assigned to the VM for vsock communication.
     pub cid: Cid,
     /// The UID of the process which requested the VM.
@@ -42,73 +279,529 @@ pub struct VmInstance {
     /// The PID of the process which requested the VM. Note that this process may no longer exist
     /// and the PID may have been reused for a different process, so this should not be trusted.
     pub requester_debug_pid: i32,
-    /// Whether the VM is still running.
-    running: AtomicBool,
+    /// Whether the VM is still running, and if not why it stopped.
+    state: VmState,
+    /// Held while pausing or resuming the VM, so that checking its state, suspending or resuming
+    /// crosvm and moving to the new state happen as one step.
+    pause_lock: Mutex<()>,
     /// Callbacks to clients of the VM.
     pub callbacks: VirtualMachineCallbacks,
+    /// The VM's memory balloon, which the monitor thread moves towards its target.
+    balloon: Mutex<Balloon>,
+    /// The resource usage of crosvm, sampled periodically by the monitor thread.
+    usage: Mutex<UsageTracker>,
+    /// The vsock connections the Virt Manager has open on behalf of the VM.
+    pub vsock_connections: ConnectionTracker,
+    /// Resources to be released when the VM stops. This is taken when teardown runs, so that it
+    /// only happens once.
+    teardown: Mutex<Option<Teardown>>,
+    /// The guest's kernel version, once its agent has reported it.
+    guest_kernel_version: Mutex<Option<String>>,
+    /// Heartbeats from the monitor thread, to tell if it has died or stalled.
+    monitor_heartbeat: Heartbeat,
 }
 
 impl VmInstance {
-    /// Create a new `VmInstance` for the given process.
-    fn new(
-        child: SharedChild,
-        cid: Cid,
-        requester_uid: u32,
-        requester_sid: String,
-        requester_debug_pid: i32,
-    ) -> VmInstance {
-        VmInstance {
-            child,
-            cid,
-            requester_uid,
-            requester_sid,
-            requester_debug_pid,
-            running: AtomicBool::new(true),
-            callbacks: Default::default(),
-        }
-    }
-
     /// Start an instance of `crosvm` to manage a new VM. The `crosvm` instance will be killed when
-    /// the `VmInstance` is dropped.
+    /// the `VmInstance` is dropped. Fails with `DeadlineExceeded` if the given deadline passes
+    /// before crosvm has been launched, or while waiting for it to settle.
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         config: &VmConfig,
+        crosvm: &CrosvmBinary,
+        callbacks: VirtualMachineCallbacks,
         cid: Cid,
         log_fd: Option<File>,
+        vsock_retry: &VsockRetry,
+        deadline: Option<Instant>,
         requester_uid: u32,
         requester_sid: String,
         requester_debug_pid: i32,
     ) -> Result<Arc<VmInstance>, Error> {
-        let child = run_vm(config, cid, log_fd)?;
-        let instance = Arc::new(VmInstance::new(
+        let runtime_dir = runtime_dir::create(cid)?;
+        let start_time = SystemTime::now();
+        let spawned = create_cgroup(config, &runtime_dir).and_then(|cgroup| {
+            match run_vm(
+                config,
+                crosvm,
+                cid,
+                log_fd,
+                &runtime_dir,
+                cgroup.as_deref(),
+                vsock_retry,
+                deadline,
+            ) {
+                Ok((child, command_line, environment)) => {
+                    Ok((child, command_line, environment, cgroup))
+                }
+                Err(e) => {
+                    if let Some(Err(e)) = cgroup.map(|cgroup| cgroup.remove()) {
+                        error!("Error removing cgroup: {:?}", e);
+                    }
+                    Err(e)
+                }
+            }
+        });
+        let (child, command_line, environment, cgroup) = match spawned {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                if let Err(e) = runtime_dir::retire(&runtime_dir) {
+                    error!("Error retiring runtime directory {:?}: {:?}", runtime_dir, e);
+                }
+                return Err(e);
+            }
+        };
+        let usage = Mutex::new(UsageTracker::new(child.id()));
+        let child = Arc::new(child);
+
+        let mut teardown = Teardown::default();
+        let crosvm = child.clone();
+        teardown.add(TeardownStage::Crosvm, "crosvm", move || {
+            crosvm.kill()?;
+            crosvm.wait()?;
+            Ok(())
+        });
+        let dir = runtime_dir.clone();
+        teardown.add(TeardownStage::Files, "runtime directory", move || runtime_dir::retire(&dir));
+        if let Some(cgroup) = cgroup.clone() {
+            teardown.add(TeardownStage::Files, "cgroup", move || cgroup.remove());
+        }
+
+        let instance = Arc::new(VmInstance {
             child,
+            config: config.clone(),
+            crosvm_path: crosvm.path.clone(),
+            command_line,
+            environment,
+            cgroup,
+            start_time,
+            runtime_dir,
             cid,
             requester_uid,
             requester_sid,
             requester_debug_pid,
-        ));
+            state: VmState::new(),
+            pause_lock: Mutex::new(()),
+            callbacks,
+            balloon: Mutex::new(Balloon::default()),
+            usage,
+            vsock_connections: ConnectionTracker::default(),
+            teardown: Mutex::new(Some(teardown)),
+            guest_kernel_version: Mutex::new(None),
+            monitor_heartbeat: Heartbeat::new(),
+        });
 
         let instance_clone = instance.clone();
         thread::spawn(move || {
             instance_clone.monitor();
         });
+        if instance.config.wait_for.is_empty() {
+            instance.transition(&[Lifecycle::Starting], Lifecycle::Running);
+        } else {
+            let instance = instance.clone();
+            thread::spawn(move || instance.await_dependencies());
+        }
 
         Ok(instance)
     }
 
-    /// Wait for the crosvm child process to finish, then mark the VM as no longer running and call
-    /// any callbacks.
+    /// Wait for the services the VM depends on to accept vsock connections, then mark it as
+    /// running. If they don't within the timeout then the VM is stopped.
+    fn await_dependencies(&self) {
+        let reachable = |dependency: &Dependency| {
+            // If the VM has stopped meanwhile then there is no point waiting any longer.
+            if self.state.get() != Lifecycle::Starting {
+                return true;
+            }
+            let (cid, port) = (dependency.cid, dependency.port);
+            let _tracked = self.vsock_connections.track(ConnectionKind::Readiness, true, cid, port);
+            vsock::connect(cid, port, Some(AGENT_IO_TIMEOUT)).is_ok()
+        };
+        match wait_for_dependencies(&self.config.wait_for, DEPENDENCY_TIMEOUT, reachable) {
+            Ok(()) => {
+                self.transition(&[Lifecycle::Starting], Lifecycle::Running);
+            }
+            Err(dependency) => {
+                error!(
+                    "Port {} of CID {} wasn't reachable in time, stopping VM with CID {}",
+                    dependency.port, dependency.cid, self.cid
+                );
+                self.stop_for(StopReason::DependencyTimeout);
+            }
+        }
+    }
+
+    /// Wait for the crosvm child process to finish, sampling its resource usage and adjusting its
+    /// memory balloon meanwhile, then mark the VM as no longer running, release its resources and
+    /// call any callbacks.
     fn monitor(&self) {
-        match self.child.wait() {
-            Err(e) => error!("Error waiting for crosvm instance to die: {}", e),
-            Ok(status) => info!("crosvm exited with status {}", status),
+        let _watch = self.monitor_heartbeat.watch();
+        let mut next_sample = Instant::now();
+        let status = loop {
+            self.monitor_heartbeat.beat();
+            let now = Instant::now();
+            if now >= next_sample {
+                self.usage.lock().unwrap().sample();
+                next_sample += USAGE_SAMPLE_INTERVAL;
+            }
+            if self.state.get() == Lifecycle::Running {
+                self.step_balloon(now);
+            }
+            match self.child.try_wait() {
+                Ok(Some(status)) => break Some(status),
+                Ok(None) => thread::sleep(EXIT_POLL_INTERVAL),
+                Err(e) => {
+                    error!("Error waiting for crosvm instance to die: {}", e);
+                    break None;
+                }
+            }
+        };
+        let stopped = match self.state.exited() {
+            Some(stopped) => stopped,
+            None => return,
+        };
+        // Read crosvm's log before teardown moves it to where logs are retained.
+        let log_path = self.runtime_dir.join(CROSVM_LOG_FILE);
+        let virtio_failures = match File::open(&log_path) {
+            Ok(log) => virtio_negotiation_failures(BufReader::new(log)),
+            Err(e) => {
+                warn!("Failed to read crosvm log for VM with CID {}: {}", self.cid, e);
+                vec![]
+            }
+        };
+        let device_failure =
+            File::open(&log_path).ok().and_then(|log| device_init_failure(BufReader::new(log)));
+        self.teardown();
+        let reason = match stopped {
+            Lifecycle::Stopped(reason) => reason,
+            _ => StopReason::Exited,
+        };
+        let summary = self.usage.lock().unwrap().summary(status, reason);
+        let crashed = crashed(status, reason);
+        if let Some(level) = lifecycle_log_level(self.config.quiet, crashed) {
+            let what = if crashed { "crashed" } else { "stopped" };
+            log!(level, "VM with CID {} {}: {:?}", self.cid, what, summary);
+        }
+        if crashed {
+            self.callbacks.record_crash(summary.uptime);
         }
-        self.running.store(false, Ordering::Release);
+        if let Some(failure) = device_failure {
+            error!("VM with CID {} stopped because {}", self.cid, failure);
+        }
+        if !virtio_failures.is_empty() {
+            warn!(
+                "Guest of VM with CID {} failed to negotiate virtio devices: {:?}",
+                self.cid, virtio_failures
+            );
+        }
+        self.callbacks.callback_on_state_changed(self.cid, stopped);
+        self.callbacks.callback_on_stopped(self.cid, &summary, &virtio_failures);
         self.callbacks.callback_on_died(self.cid);
     }
 
+    /// Read the current resource usage of the VM's crosvm process, failing if it has exited.
+    pub fn current_usage(&self) -> io::Result<UsageSample> {
+        self.usage.lock().unwrap().current()
+    }
+
+    /// Add a step to release some resource when the VM stops. If the VM has already been torn down
+    /// then the step is run straight away.
+    pub fn add_teardown_step(
+        &self,
+        stage: TeardownStage,
+        name: &str,
+        step: impl FnOnce() -> Result<(), Error> + Send + 'static,
+    ) {
+        if let Some(teardown) = &mut *self.teardown.lock().unwrap() {
+            teardown.add(stage, name, step);
+            return;
+        }
+        if let Err(e) = step() {
+            error!("Teardown step {} failed for VM with CID {}: {:?}", name, self.cid, e);
+        }
+    }
+
+    /// Move the VM to the given state if it is currently in one of the given states, and tell
+    /// clients if it did. Returns whether the transition happened.
+    fn transition(&self, from: &[Lifecycle], to: Lifecycle) -> bool {
+        match self.state.transition(from, to) {
+            Some(state) => {
+                self.callbacks.callback_on_state_changed(self.cid, state);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Release all resources owned by the VM, in order: crosvm first, then network devices, then
+    /// files. Every step is attempted even if an earlier one fails. Does nothing if teardown has
+    /// already happened.
+    fn teardown(&self) {
+        let teardown = self.teardown.lock().unwrap().take();
+        if let Some(teardown) = teardown {
+            let failures = teardown.run();
+            if !failures.is_empty() {
+                error!("{} teardown step(s) failed for VM with CID {}", failures.len(), self.cid);
+            }
+        }
+    }
+
+    /// Get the current stage of the VM's lifecycle.
+    pub fn lifecycle(&self) -> Lifecycle {
+        self.state.get()
+    }
+
     /// Return whether `crosvm` is still running the VM.
     pub fn running(&self) -> bool {
-        self.running.load(Ordering::Acquire)
+        self.state.running()
+    }
+
+    /// Return whether the VM's monitor thread has panicked or stopped making progress while the VM
+    /// is still running. If so, the VM's exit will never be noticed, so it won't be torn down and
+    /// its callbacks won't be told.
+    pub fn monitor_stalled(&self) -> bool {
+        self.running() && self.monitor_heartbeat.stalled(Instant::now())
+    }
+
+    /// Check whether the crosvm process is currently alive, without blocking. Unlike `running`,
+    /// this doesn't wait for the monitor thread to notice that the process has exited.
+    pub fn ping(&self) -> bool {
+        match self.child.try_wait() {
+            Ok(status) => status.is_none(),
+            Err(e) => {
+                error!("Error checking status of crosvm instance: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Stop the VM as cleanly as possible, blocking until crosvm has exited. If the guest has an
+    /// agent then it is first asked to shut down the guest; if that doesn't work then crosvm is
+    /// sent SIGTERM, and finally killed if it still hasn't exited. Does nothing if the VM is
+    /// already stopping or has stopped.
+    pub fn stop(&self) {
+        self.stop_for(StopReason::Requested)
+    }
+
+    /// Stop the VM in the same way as `stop`, reporting the given reason to clients.
+    pub fn stop_for(&self, reason: StopReason) {
+        self.stop_with(reason, || self.ask_agent("shutdown"), GUEST_SHUTDOWN_TIMEOUT)
+    }
+
+    /// Stop the VM in the same way as `stop_for`, asking the guest to shut down with the given
+    /// function if it has an agent, and waiting up to the given timeout for it to power off.
+    fn stop_with(
+        &self,
+        reason: StopReason,
+        ask_shutdown: impl FnOnce() -> Result<String, Error>,
+        shutdown_timeout: Duration,
+    ) {
+        let active = [Lifecycle::Starting, Lifecycle::Running, Lifecycle::Paused];
+        if !self.transition(&active, Lifecycle::Stopping(reason)) {
+            return;
+        }
+        if self.config.agent_port.is_some() {
+            match ask_shutdown() {
+                Ok(_) if self.wait_for_exit(shutdown_timeout) => return,
+                Ok(_) => {
+                    warn!("Guest with CID {} didn't power off after shutdown request", self.cid)
+                }
+                Err(e) => warn!("Failed to ask guest with CID {} to shut down: {:?}", self.cid, e),
+            }
+        }
+        match self.child.send_signal(libc::SIGTERM) {
+            Ok(()) if self.wait_for_exit(SIGTERM_TIMEOUT) => return,
+            Ok(()) => warn!("crosvm for CID {} didn't exit after SIGTERM", self.cid),
+            Err(e) => error!("Error sending SIGTERM to crosvm instance: {}", e),
+        }
+        self.kill();
+    }
+
+    /// Send the given request to the agent in the guest, returning what followed `ok` in its
+    /// reply. Fails if the VM doesn't have an agent.
+    fn ask_agent(&self, request: &str) -> Result<String, Error> {
+        let port = self.config.agent_port.context("VM doesn't have a guest agent")?;
+        let _tracked = self.vsock_connections.track(ConnectionKind::Agent, true, self.cid, port);
+        let stream = vsock::connect(self.cid, port, Some(AGENT_IO_TIMEOUT))
+            .context("Failed to connect to guest agent")?;
+        agent_request(stream, request)
+    }
+
+    /// Get the guest's kernel version, in the form `uname -a` prints it, as reported by its agent.
+    /// The agent is only asked once, and the version cached. Fails if the VM doesn't have an agent
+    /// or the agent can't be reached.
+    pub fn guest_kernel_version(&self) -> Result<String, Error> {
+        // The lock is held while asking the agent so that concurrent callers only ask once.
+        let cached = &mut *self.guest_kernel_version.lock().unwrap();
+        if let Some(version) = cached {
+            return Ok(version.clone());
+        }
+        if !self.running() {
+            bail!("VM with CID {} isn't running", self.cid);
+        }
+        let version = self.ask_agent("uname")?;
+        if version.is_empty() {
+            bail!("Guest agent reported an empty kernel version");
+        }
+        *cached = Some(version.clone());
+        Ok(version)
+    }
+
+    /// Wait up to the given timeout for crosvm to exit, returning whether it did.
+    fn wait_for_exit(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.ping() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(EXIT_POLL_INTERVAL);
+        }
+        true
+    }
+
+    /// Grow the writable disk with the given index to the given size in bytes, returning the size
+    /// it has after resizing.
+    pub fn resize_disk(&self, index: usize, new_size: u64) -> Result<u64, Error> {
+        let disk = self.config.disks.get(index).with_context(|| format!("No disk {}", index))?;
+        if !disk.writable {
+            bail!("Disk {} is read-only, so can't be resized", index);
+        }
+        let current_size = fs::metadata(&disk.image)?.len();
+        if new_size <= current_size {
+            bail!("Disk {} is already {} bytes, can't resize to {}", index, current_size, new_size);
+        }
+        let device_index = self.config.device_index(index).context("Disk not attached")?;
+        self.control_command(&[
+            "disk",
+            "resize",
+            &device_index.to_string(),
+            &new_size.to_string(),
+        ])?;
+        Ok(fs::metadata(&disk.image)?.len())
+    }
+
+    /// Start moving the VM's memory balloon towards the given size in MiB, gradually over the given
+    /// duration.
+    pub fn adjust_memory(&self, balloon_mib: u64, duration: Duration) -> Result<(), Error> {
+        let memory_mib = self.config.guest_memory_mib();
+        if balloon_mib > memory_mib {
+            bail!(
+                "Balloon of {} MiB is larger than the {} MiB of guest memory",
+                balloon_mib,
+                memory_mib
+            );
+        }
+        if self.state.get() != Lifecycle::Running {
+            bail!("VM with CID {} isn't running", self.cid);
+        }
+        self.balloon.lock().unwrap().set_target(balloon_mib, duration, Instant::now());
+        Ok(())
+    }
+
+    /// Get the size in MiB the VM's memory balloon was last set to.
+    pub fn balloon_size_mib(&self) -> u64 {
+        self.balloon.lock().unwrap().size_mib()
+    }
+
+    /// Move the memory balloon a step towards its target, if a step is due.
+    fn step_balloon(&self, now: Instant) {
+        let size_mib = match self.balloon.lock().unwrap().step(now) {
+            Some(size_mib) => size_mib,
+            None => return,
+        };
+        let size = (size_mib << 20).to_string();
+        match self.control_command(&["balloon", &size]) {
+            Ok(()) => self.balloon.lock().unwrap().set_size(size_mib),
+            Err(e) => error!(
+                "Failed to set balloon of VM with CID {} to {} MiB: {:?}",
+                self.cid, size_mib, e
+            ),
+        }
+    }
+
+    /// Suspend the VM's vCPUs. Fails if the VM isn't running or is already paused.
+    pub fn pause(&self) -> Result<(), Error> {
+        let _pausing = self.pause_lock.lock().unwrap();
+        if self.state.get() != Lifecycle::Running {
+            bail!("VM with CID {} isn't running", self.cid);
+        }
+        match &self.cgroup {
+            Some(cgroup) => cgroup.set_frozen(true)?,
+            None => self.control_command(&["suspend"])?,
+        }
+        self.transition(&[Lifecycle::Running], Lifecycle::Paused);
+        Ok(())
+    }
+
+    /// Resume the VM's vCPUs after they were suspended by `pause`. Fails if the VM isn't paused.
+    pub fn resume(&self) -> Result<(), Error> {
+        let _resuming = self.pause_lock.lock().unwrap();
+        if self.state.get() != Lifecycle::Paused {
+            bail!("VM with CID {} isn't paused", self.cid);
+        }
+        match &self.cgroup {
+            Some(cgroup) => cgroup.set_frozen(false)?,
+            None => self.control_command(&["resume"])?,
+        }
+        self.transition(&[Lifecycle::Paused], Lifecycle::Running);
+        Ok(())
+    }
+
+    /// Send a command to the running crosvm instance via its control socket.
+    fn control_command(&self, args: &[&str]) -> Result<(), Error> {
+        let status = Command::new(&self.crosvm_path)
+            .args(args)
+            .arg(self.runtime_dir.join(CONTROL_SOCKET_FILE))
+            .status()
+            .context("Failed to run crosvm control command")?;
+        if !status.success() {
+            bail!("crosvm {} failed with {}", args.join(" "), status);
+        }
+        Ok(())
+    }
+
+    /// Open what the VM's payload has written to its console for reading, from the start. Fails if
+    /// the payload doesn't have a console of its own.
+    pub fn open_payload_output(&self) -> Result<File, Error> {
+        if !self.config.payload.as_ref().map_or(false, |payload| payload.separate_output) {
+            bail!("VM payload doesn't have a console of its own");
+        }
+        let path = self.runtime_dir.join(PAYLOAD_OUTPUT_FILE);
+        File::open(&path).with_context(|| format!("Failed to open {:?}", path))
+    }
+
+    /// Export everything needed to debug the VM as a tar archive, open for reading from the start.
+    /// See `write_diagnostics` for what it contains.
+    pub fn export_diagnostics(&self) -> Result<File, Error> {
+        // The archive is written to an unnamed file, so that nothing is left behind.
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_TMPFILE)
+            .open(&self.runtime_dir)
+            .with_context(|| format!("Failed to create file in {:?}", self.runtime_dir))?;
+        write_diagnostics(
+            &mut file,
+            &self.config,
+            &self.command_line,
+            &self.environment,
+            &self.runtime_dir,
+            self.current_usage(),
+            &self.state.history(),
+        )?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    }
+
+    /// Kill crosvm with the given signal, so that the monitor thread handles it as a crash. This is
+    /// only intended for testing crash handling. Fails if the VM isn't running or the signal isn't
+    /// one which kills crosvm outright.
+    pub fn simulate_crash(&self, signal: libc::c_int) -> Result<(), Error> {
+        if !self.running() {
+            bail!("VM with CID {} isn't running", self.cid);
+        }
+        warn!("Simulating crash of VM with CID {} with signal {}", self.cid, signal);
+        send_crash_signal(&self.child, signal)
     }
 
     /// Kill the crosvm instance.
@@ -120,34 +813,1689 @@ impl VmInstance {
     }
 }
 
-/// Start an instance of `crosvm` to manage a new VM.
-fn run_vm(config: &VmConfig, cid: Cid, log_fd: Option<File>) -> Result<SharedChild, Error> {
+impl Drop for VmInstance {
+    fn drop(&mut self) {
+        self.teardown();
+    }
+}
+
+/// The problems found by checking a VM config without starting the VM.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ConfigCheck {
+    /// Problems which would stop the VM from starting.
+    pub errors: Vec<String>,
+    /// Problems which wouldn't stop the VM from starting, but may mean it doesn't behave as
+    /// intended.
+    pub warnings: Vec<ConfigWarning>,
+}
+
+/// Check whether a VM could be started with the given config and crosvm binary, by validating it
+/// and building the crosvm command line, without creating any files or running crosvm.
+pub fn check_config(config: &VmConfig, crosvm: &CrosvmBinary) -> ConfigCheck {
+    let mut check = ConfigCheck { errors: vec![], warnings: config.warnings() };
+    let runtime_dir = Path::new(runtime_dir::RUNTIME_DIR_BASE).join("check");
+    match build_crosvm_command(config, &crosvm.path, FIRST_GUEST_CID, None, &runtime_dir) {
+        Ok(command) => {
+            if let Err(e) = crosvm.check_run_flags(command.get_args()) {
+                check.errors.push(e.to_string());
+            }
+        }
+        Err(e) => check.errors.push(format!("{:#}", e)),
+    }
+    check
+}
+
+/// Create a cgroup for the VM with the given runtime directory, if it is to be paused by freezing
+/// the cgroup.
+fn create_cgroup(config: &VmConfig, runtime_dir: &Path) -> Result<Option<Arc<Cgroup>>, Error> {
+    if config.pause_mechanism != Some(PauseMechanism::CgroupFreezer) {
+        return Ok(None);
+    }
+    let name = runtime_dir.file_name().context("Runtime directory has no name")?;
+    Ok(Some(Arc::new(Cgroup::create(name)?)))
+}
+
+/// Start an instance of `crosvm` to manage a new VM, in the given cgroup if any, retrying as
+/// given if the vhost-vsock device is unavailable, and giving up if the given deadline passes.
+#[allow(clippy::too_many_arguments)]
+fn run_vm(
+    config: &VmConfig,
+    crosvm: &CrosvmBinary,
+    cid: Cid,
+    mut log_fd: Option<File>,
+    runtime_dir: &Path,
+    cgroup: Option<&Cgroup>,
+    vsock_retry: &VsockRetry,
+    deadline: Option<Instant>,
+) -> Result<(SharedChild, Vec<String>, Vec<String>), Error> {
+    runtime_dir::create_scratch(runtime_dir, config.scratch_size_mib)?;
+    if let Some(label) = &config.process_label {
+        link_crosvm(&crosvm.path, &process_link(runtime_dir, label, cid))?;
+    }
+    let console_sinks = console_sinks(config, &mut log_fd, runtime_dir)?;
+    let mut command = build_crosvm_command(config, &crosvm.path, cid, log_fd, runtime_dir)?;
+    crosvm.check_run_flags(command.get_args())?;
+    if let Some(cgroup) = cgroup {
+        cgroup.add_command(&mut command)?;
+    }
+    if let Some(pflash) = &config.pflash {
+        prepare_pflash(Path::new(pflash))?;
+    }
+    write_bootconfig_initrd(config, runtime_dir)?;
+    if let Some(level) = lifecycle_log_level(config.quiet, false) {
+        log!(level, "Running {:?}", command);
+    }
+    let child = spawn_crosvm(&mut command, runtime_dir, vsock_retry, deadline)?;
+    if let Some(stdout) = child.take_stdout() {
+        console::capture(stdout, console_sinks);
+    }
+    Ok((child, command_line(&command), command_environment(&command)))
+}
+
+/// Copy the VM's initrd into its runtime directory with its bootconfig appended, including a fresh
+/// entropy seed if it asks for one, if it uses bootconfig at all.
+fn write_bootconfig_initrd(config: &VmConfig, runtime_dir: &Path) -> Result<(), Error> {
+    let initrd = match (&config.initrd, config.uses_bootconfig()) {
+        (Some(initrd), true) => initrd,
+        _ => return Ok(()),
+    };
+    let mut params = config.bootconfig.clone();
+    if config.entropy_seed {
+        params.insert(bootconfig::ENTROPY_SEED_KEY.to_owned(), bootconfig::entropy_seed()?);
+    }
+    bootconfig::write_initrd(
+        Path::new(initrd),
+        &params,
+        &runtime_dir.join(runtime_dir::BOOTCONFIG_INITRD_FILE),
+    )
+}
+
+/// Send the given request to a guest agent over the given stream, as a line, and return what
+/// followed `ok` in its reply line. Any other reply is an error.
+fn agent_request(mut stream: impl Read + Write, request: &str) -> Result<String, Error> {
+    stream.write_all(format!("{}\n", request).as_bytes())?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    let response = response.trim();
+    match response.strip_prefix("ok") {
+        Some(rest) if rest.is_empty() || rest.starts_with(' ') => Ok(rest.trim_start().to_owned()),
+        _ => bail!("Guest agent refused {:?}: {:?}", request, response),
+    }
+}
+
+/// Get the name for the crosvm process of the VM with the given CID: the given label followed by
+/// the CID, with the label truncated if need be so that the whole name fits in the kernel's limit.
+fn process_name(label: &str, cid: Cid) -> String {
+    let suffix = format!("-{}", cid);
+    let mut end = MAX_PROCESS_NAME_LEN.saturating_sub(suffix.len()).min(label.len());
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &label[..end], suffix)
+}
+
+/// Get the path of the link to the crosvm binary in the given runtime directory, named after the
+/// VM with the given label and CID.
+fn process_link(runtime_dir: &Path, label: &str, cid: Cid) -> PathBuf {
+    runtime_dir.join(PROCESS_LINK_DIR).join(process_name(label, cid))
+}
+
+/// Create a link at the given path to the given crosvm binary.
+fn link_crosvm(crosvm_path: &Path, link: &Path) -> Result<(), Error> {
+    let dir = link.parent().context("Process link has no parent directory")?;
+    fs::create_dir_all(dir)?;
+    symlink(crosvm_path, link).with_context(|| format!("Failed to link {:?}", link))
+}
+
+/// Spawn the given crosvm command, with its logs going to the given runtime directory. If vsock
+/// retries are enabled then give it `SETTLE_TIME` to start: if it exits in that time because the
+/// vhost-vsock device is unavailable then it is launched again after a delay, and if it exits
+/// because it failed to set up a device then `DeviceInitFailed` is returned. If it exits for any
+/// other reason, or retries aren't enabled, then that is left for the monitor thread to report. If
+/// the given deadline passes before crosvm is launched, between retries or while it is settling
+/// then it is killed and `DeadlineExceeded` is returned.
+fn spawn_crosvm(
+    command: &mut Command,
+    runtime_dir: &Path,
+    vsock_retry: &VsockRetry,
+    deadline: Option<Instant>,
+) -> Result<SharedChild, Error> {
+    let log_path = runtime_dir.join(CROSVM_LOG_FILE);
+    let mut retries = 0;
+    loop {
+        check_deadline(deadline)?;
+        command.stderr(File::create(&log_path)?);
+        let child = SharedChild::spawn(command)?;
+        if vsock_retry.attempts == 0 || !exited_before_settling(&child, deadline)? {
+            return Ok(child);
+        }
+        let log = fs::read(&log_path)?;
+        if retry::is_transient_vsock_error(&log[..]) {
+            if retries == vsock_retry.attempts {
+                return Err(VsockUnavailable { attempts: retries + 1 }.into());
+            }
+            let delay = vsock_retry.delay(retries);
+            if deadline.map_or(false, |deadline| Instant::now() + delay > deadline) {
+                return Err(DeadlineExceeded.into());
+            }
+            warn!("crosvm couldn't open the vhost-vsock device, retrying in {:?}", delay);
+            thread::sleep(delay);
+            retries += 1;
+            continue;
+        }
+        if let Some(failure) = device_init_failure(&log[..]) {
+            return Err(failure.into());
+        }
+        return Ok(child);
+    }
+}
+
+/// Wait for up to `SETTLE_TIME` for the given crosvm process to exit, returning whether it did. If
+/// the given deadline passes first then crosvm is killed and `DeadlineExceeded` is returned.
+fn exited_before_settling(child: &SharedChild, deadline: Option<Instant>) -> Result<bool, Error> {
+    let settled = Instant::now() + SETTLE_TIME;
+    while child.try_wait()?.is_none() {
+        let now = Instant::now();
+        if deadline.map_or(false, |deadline| now > deadline) {
+            child.kill()?;
+            child.wait()?;
+            return Err(DeadlineExceeded.into());
+        }
+        if now >= settled {
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(true)
+}
+
+/// Error returned when the deadline for starting a VM passes before crosvm has started.
+#[derive(Debug, thiserror::Error)]
+#[error("Deadline passed while starting VM.")]
+pub struct DeadlineExceeded;
+
+/// Fail with `DeadlineExceeded` if the given deadline has passed.
+fn check_deadline(deadline: Option<Instant>) -> Result<(), DeadlineExceeded> {
+    match deadline {
+        Some(deadline) if Instant::now() > deadline => Err(DeadlineExceeded),
+        _ => Ok(()),
+    }
+}
+
+/// Error returned when crosvm exits straight after it is launched because it failed to set up one
+/// of the VM's devices, such as if the backend for the device is missing from the host. The VM may
+/// start if that device is disabled.
+#[derive(Debug, thiserror::Error)]
+#[error("crosvm failed to set up the {device} device: {message}")]
+pub struct DeviceInitFailed {
+    /// The name of the device, as in `DEVICE_INIT_FAILURES`.
+    pub device: &'static str,
+    /// The underlying error which crosvm gave for the failure.
+    pub message: String,
+}
+
+/// Find the first message in the given crosvm log saying that it failed to set up a device, if
+/// there is one, and which device it was.
+fn device_init_failure(log: impl BufRead) -> Option<DeviceInitFailed> {
+    log.split(b'\n').flatten().find_map(|line| {
+        let line = String::from_utf8_lossy(&line);
+        // crosvm's errors are chained, so the device is the one whose error comes first.
+        let (end, device) = DEVICE_INIT_FAILURES
+            .iter()
+            .filter_map(|(error, device)| {
+                let error = format!("{}:", error);
+                line.find(&error).map(|start| (start + error.len(), *device))
+            })
+            .min()?;
+        Some(DeviceInitFailed { device, message: line[end..].trim().to_owned() })
+    })
+}
+
+/// Set up the destinations for the console output of a VM, if it needs to be captured by us
+/// rather than going straight from crosvm to the client's log file descriptor or the runtime
+/// directory. If it is mirrored to logcat or limited in size then the client's log file descriptor
+/// is taken to be written to by us. Returns no sinks if the console output doesn't need to be
+/// captured.
+fn console_sinks(
+    config: &VmConfig,
+    log_fd: &mut Option<File>,
+    runtime_dir: &Path,
+) -> Result<Vec<Box<dyn LineSink>>, Error> {
+    // The console output is only rotated if it isn't going to the client.
+    let rotated = config.console_log.is_some() && log_fd.is_none();
+    if config.console_logcat_tag.is_none() && config.max_log_bytes.is_none() && !rotated {
+        // crosvm writes the console output itself.
+        return Ok(vec![]);
+    }
+    let mut sinks: Vec<Box<dyn LineSink>> = vec![];
+    if let Some(tag) = &config.console_logcat_tag {
+        sinks.push(Box::new(Logcat::new(tag)?));
+    }
+    let log: Box<dyn LineSink> = match (log_fd.take(), &config.console_log) {
+        (Some(log_fd), _) => Box::new(log_fd),
+        (None, Some(console_log)) => Box::new(ConsoleLog::create(runtime_dir, console_log)?),
+        (None, None) => Box::new(File::create(runtime_dir.join(CONSOLE_LOG_FILE))?),
+    };
+    sinks.push(match config.max_log_bytes {
+        Some(max_bytes) => Box::new(Truncating::new(log, max_bytes)),
+        None => log,
+    });
+    Ok(sinks)
+}
+
+/// Wait until each of the given dependencies is reachable, in order, returning the first which
+/// doesn't become reachable before the timeout.
+fn wait_for_dependencies(
+    dependencies: &[Dependency],
+    timeout: Duration,
+    mut reachable: impl FnMut(&Dependency) -> bool,
+) -> Result<(), &Dependency> {
+    let deadline = Instant::now() + timeout;
+    for dependency in dependencies {
+        while !reachable(dependency) {
+            if Instant::now() >= deadline {
+                return Err(dependency);
+            }
+            thread::sleep(DEPENDENCY_POLL_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+/// Find the messages in the given crosvm log about guest drivers failing to negotiate features with
+/// virtio devices, without their log prefixes. Repeated messages are only included once.
+fn virtio_negotiation_failures(log: impl BufRead) -> Vec<String> {
+    let mut failures: Vec<String> = vec![];
+    for line in log.split(b'\n').flatten() {
+        let line = String::from_utf8_lossy(&line);
+        if !NEGOTIATION_FAILURE_MARKERS.iter().any(|marker| line.contains(marker)) {
+            continue;
+        }
+        // Lines are of the form "[timestamp LEVEL source] message".
+        let message = match line.find("] ") {
+            Some(end) if line.starts_with('[') => &line[end + 2..],
+            _ => &line[..],
+        };
+        let message = message.trim();
+        if !failures.iter().any(|failure| failure == message) {
+            failures.push(message.to_owned());
+        }
+    }
+    failures
+}
+
+/// Send the given signal to the given crosvm process to make it crash, if it is one of
+/// `CRASH_SIGNALS`.
+fn send_crash_signal(child: &SharedChild, signal: libc::c_int) -> Result<(), Error> {
+    if !CRASH_SIGNALS.contains(&signal) {
+        bail!("Signal {} is not one of {:?}", signal, CRASH_SIGNALS);
+    }
+    child.send_signal(signal).context("Failed to signal crosvm")
+}
+
+/// Check whether crosvm exited unexpectedly, rather than because the guest powered off or we
+/// stopped it.
+fn crashed(status: Option<ExitStatus>, reason: StopReason) -> bool {
+    reason == StopReason::Exited && !status.map_or(false, |status| status.success())
+}
+
+/// Get the level at which to log a lifecycle event of a VM, or `None` if it shouldn't be logged.
+/// Routine events aren't logged for quiet VMs, but crashes always are.
+fn lifecycle_log_level(quiet: bool, crashed: bool) -> Option<Level> {
+    match (quiet, crashed) {
+        (_, true) => Some(Level::Warn),
+        (true, false) => None,
+        (false, false) => Some(Level::Info),
+    }
+}
+
+/// Get the program and arguments of the given command, for reporting to operators. File descriptor
+/// paths are only meaningful within our own process, so are replaced with a marker to show that
+/// the invocation can't be reproduced exactly as is.
+fn command_line(command: &Command) -> Vec<String> {
+    iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            match arg.find(FD_PATH_PREFIX) {
+                Some(start) => format!("{}{}", &arg[..start], REDACTED_FD_PATH),
+                None => arg.into_owned(),
+            }
+        })
+        .collect()
+}
+
+/// Get the environment variables set on the given command, as `NAME=value`, for reporting to
+/// operators. The values of variables which look like they may hold secrets are redacted.
+fn command_environment(command: &Command) -> Vec<String> {
+    command
+        .get_envs()
+        .filter_map(|(name, value)| Some((name.to_string_lossy(), value?.to_string_lossy())))
+        .map(|(name, value)| {
+            let upper = name.to_uppercase();
+            if SENSITIVE_ENV_MARKERS.iter().any(|marker| upper.contains(marker)) {
+                format!("{}={}", name, REDACTED_ENV_VALUE)
+            } else {
+                format!("{}={}", name, value)
+            }
+        })
+        .collect()
+}
+
+/// Write a tar archive of everything needed to debug a VM to the given writer:
+///
+/// * `config.json`: the effective config the VM was started with.
+/// * `crosvm_command.txt`: the crosvm command line, one argument per line, with file descriptor
+///   paths redacted.
+/// * `crosvm_environment.txt`: the environment variables crosvm was given, with sensitive values
+///   redacted.
+/// * `crosvm.log`: the end of what crosvm has written to stderr.
+/// * `console_tail.log`: the end of the console log, including rotated segments, if it is in the
+///   runtime directory.
+/// * `stats.txt`: the current resource usage of crosvm, or why it couldn't be read.
+/// * `state_history.txt`: the VM's most recent lifecycle states, with when they were entered in
+///   milliseconds since the epoch.
+fn write_diagnostics(
+    out: impl Write,
+    config: &VmConfig,
+    command_line: &[String],
+    environment: &[String],
+    runtime_dir: &Path,
+    usage: io::Result<UsageSample>,
+    history: &[(SystemTime, Lifecycle)],
+) -> Result<(), Error> {
+    let read_optional = |name, max_size| match read_tail(&runtime_dir.join(name), max_size) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        result => result.with_context(|| format!("Failed to read {}", name)),
+    };
+    let stats = match usage {
+        Ok(usage) => format!(
+            "uptime_ms: {}\nrss_kib: {}\ncpu_time_ms: {}\n",
+            usage.uptime.as_millis(),
+            usage.rss_kib,
+            usage.cpu_time.as_millis()
+        ),
+        Err(e) => format!("unavailable: {}\n", e),
+    };
+    let history: String = history
+        .iter()
+        .map(|(time, lifecycle)| {
+            let millis = time.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis());
+            format!("{} {:?}\n", millis, lifecycle)
+        })
+        .collect();
+    let lines =
+        |lines: &[String]| lines.iter().map(|line| format!("{}\n", line)).collect::<String>();
+
+    let mut tar = TarWriter::new(out);
+    tar.add("config.json", &serde_json::to_vec_pretty(config)?)?;
+    tar.add("crosvm_command.txt", lines(command_line).as_bytes())?;
+    tar.add("crosvm_environment.txt", lines(environment).as_bytes())?;
+    tar.add(CROSVM_LOG_FILE, &read_optional(CROSVM_LOG_FILE, DIAGNOSTICS_CROSVM_LOG_TAIL_SIZE)?)?;
+    let console_tail = match console::read_tail(runtime_dir, DIAGNOSTICS_CONSOLE_TAIL_SIZE) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        result => result.context("Failed to read console log"),
+    };
+    tar.add("console_tail.log", &console_tail?)?;
+    tar.add("stats.txt", stats.as_bytes())?;
+    tar.add("state_history.txt", history.as_bytes())?;
+    tar.finish()?;
+    Ok(())
+}
+
+/// Read up to the given number of bytes from the end of the given file.
+fn read_tail(path: &Path, max_size: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(max_size)))?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail)?;
+    Ok(tail)
+}
+
+/// Make sure that the given pflash file exists and is writable, so that the variables the
+/// bootloader stores in it persist across boots. If it doesn't exist then it is created, as long as
+/// there is space for it.
+fn prepare_pflash(path: &Path) -> Result<(), Error> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.permissions().readonly() => {
+            bail!("pflash file {:?} is read-only", path);
+        }
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to check pflash file {:?}", path)),
+    }
+    let dir = path.parent().context("pflash path has no parent directory")?;
+    let available = available_space(dir)?;
+    if available < PFLASH_SIZE {
+        bail!("Only {} bytes free in {:?}, need {} for pflash file", available, dir, PFLASH_SIZE);
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("Failed to create pflash file {:?}", path))?;
+    file.set_len(PFLASH_SIZE)?;
+    Ok(())
+}
+
+/// Get the number of bytes available to unprivileged users on the filesystem containing the given
+/// directory.
+fn available_space(dir: &Path) -> Result<u64, Error> {
+    let dir = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+    // Safe because we pass a valid C string and a valid pointer to a statvfs, and check the return
+    // value.
+    if unsafe { libc::statvfs(dir.as_ptr(), stats.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // Safe because statvfs succeeded, so has initialised the value.
+    let stats = unsafe { stats.assume_init() };
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// Build the `crosvm` command line to run a VM with the given configuration.
+fn build_crosvm_command(
+    config: &VmConfig,
+    crosvm_path: &Path,
+    cid: Cid,
+    log_fd: Option<File>,
+    runtime_dir: &Path,
+) -> Result<Command, Error> {
     config.validate()?;
 
-    let mut command = Command::new(CROSVM_PATH);
+    let mut command = match &config.process_label {
+        // The kernel names processes after the file they run, so run crosvm via a link named after
+        // the VM, which `run_vm` creates.
+        Some(label) => Command::new(process_link(runtime_dir, label, cid)),
+        None => Command::new(crosvm_path),
+    };
     // TODO(qwandor): Remove --disable-sandbox.
     command.arg("run").arg("--disable-sandbox").arg("--cid").arg(cid.to_string());
+    command.arg("--socket").arg(runtime_dir.join(CONTROL_SOCKET_FILE));
+    command.env("RUST_LOG", config.crosvm_log_level.as_deref().unwrap_or(DEFAULT_CROSVM_LOG_LEVEL));
+    command.env("TMPDIR", runtime_dir.join(runtime_dir::SCRATCH_DIR));
+    if let Some(device) = &config.vhost_vsock_device {
+        command.arg("--vhost-vsock-device").arg(device);
+    }
     if let Some(log_fd) = log_fd {
         command.stdout(log_fd);
+    } else if config.console_log.is_some()
+        || config.console_logcat_tag.is_some()
+        || config.max_log_bytes.is_some()
+    {
+        // Capture the console output ourselves, so that it can be rotated, mirrored to logcat or
+        // truncated.
+        command.stdout(Stdio::piped());
     } else {
-        // Ignore console output.
-        command.arg("--serial=type=sink");
+        // Keep the console output in the runtime directory.
+        let console_log = runtime_dir.join(CONSOLE_LOG_FILE);
+        command.arg(format!("--serial=type=file,path={}", console_log.display()));
+    }
+    if config.payload.as_ref().map_or(false, |payload| payload.separate_output) {
+        // This is the first virtio console, so is separate from the kernel's serial console.
+        let payload_output = runtime_dir.join(PAYLOAD_OUTPUT_FILE);
+        command.arg(format!(
+            "--serial=type=file,hardware=virtio-console,num=1,path={}",
+            payload_output.display()
+        ));
+    }
+    if let Some(memory_mib) = config.memory_mib {
+        command.arg("--mem").arg(memory_mib.to_string());
+    }
+    for feature in &config.cpu_features {
+        command.arg(format!("--{}", feature));
+    }
+    if let Some(protection) = &config.memory_protection {
+        command.args(protection.crosvm_args());
+    }
+    if let Some(executor) = config.async_executor {
+        command.arg("--async-executor").arg(executor.name());
+    }
+    match config.irqchip {
+        None | Some(IrqChip::Kernel) => {}
+        Some(IrqChip::Split) => {
+            command.arg("--split-irqchip");
+        }
+        Some(IrqChip::Userspace) => {
+            command.arg("--userspace-irqchip");
+        }
+    }
+    if config.watchdog {
+        command.arg("--watchdog");
+        if let Some(timeout) = config.watchdog_timeout_secs {
+            command.arg("--watchdog-timeout").arg(timeout.to_string());
+        }
+    }
+    if config.minimal_platform {
+        command.arg("--no-acpi").arg("--no-rtc").arg("--no-i8042");
     }
     if let Some(bootloader) = &config.bootloader {
         command.arg("--bios").arg(bootloader);
     }
-    if let Some(initrd) = &config.initrd {
+    if let Some(pflash) = &config.pflash {
+        command.arg("--pflash").arg(format!("path={}", pflash));
+    }
+    if config.uses_bootconfig() {
+        // This is created by `run_vm` before crosvm is started.
+        command.arg("--initrd").arg(runtime_dir.join(runtime_dir::BOOTCONFIG_INITRD_FILE));
+    } else if let Some(initrd) = &config.initrd {
         command.arg("--initrd").arg(initrd);
     }
-    if let Some(params) = &config.params {
+    if let Some(params) = config.kernel_params(cid)? {
         command.arg("--params").arg(params);
     }
-    for disk in &config.disks {
-        command.arg(if disk.writable { "--rwdisk" } else { "--disk" }).arg(&disk.image);
+    for index in config.device_order() {
+        let disk = &config.disks[index];
+        command.arg(if disk.writable { "--rwdisk" } else { "--disk" });
+        if disk.sha256.is_some() {
+            // Pass the file which was hashed, rather than letting crosvm open the image again.
+            let file = disk.open_verified()?;
+            let path = pass_file(&mut command, file);
+            command.arg(path);
+        } else {
+            command.arg(&disk.image);
+        }
+    }
+    for hashtree in config.verity_hashtrees() {
+        command.arg("--disk").arg(hashtree);
+    }
+    for address in &config.stub_pci_devices {
+        command.arg("--stub-pci-device").arg(address);
     }
     if let Some(kernel) = &config.kernel {
         command.arg(kernel);
     }
-    info!("Running {:?}", command);
-    Ok(SharedChild::spawn(&mut command)?)
+    if let Some(priority) = config.rt_priority {
+        check_realtime_priority_allowed(priority)?;
+        set_realtime_priority(&mut command, priority);
+    }
+    if let Some(io_priority) = &config.io_priority {
+        set_io_priority(&mut command, io_priority.ioprio());
+    }
+    if let Some(limit) = config.open_files_limit {
+        check_open_files_limit_allowed(limit)?;
+        set_open_files_limit(&mut command, limit);
+    }
+    if let Some(node) = config.numa_node {
+        check_numa_node_exists(node)?;
+        set_numa_node(&mut command, node);
+    }
+    if config.isolate_mounts {
+        let paths = isolated_paths(config, crosvm_path, runtime_dir)?;
+        MountIsolation::new(Path::new(ISOLATED_ROOT_DIR), &paths)?.apply(&mut command);
+    }
+    Ok(command)
+}
+
+/// Get the paths which should be visible to crosvm if its mounts are isolated, each along with
+/// whether it is a directory. Fails if an image the VM uses doesn't exist, so can't be mounted.
+fn isolated_paths(
+    config: &VmConfig,
+    crosvm_path: &Path,
+    runtime_dir: &Path,
+) -> Result<Vec<(PathBuf, bool)>, Error> {
+    let mut paths: Vec<(PathBuf, bool)> = ISOLATED_HOST_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .filter(|path| path.is_dir())
+        .map(|path| (path, true))
+        .collect();
+    paths.push((runtime_dir.to_owned(), true));
+    paths.push((crosvm_path.to_owned(), false));
+    // The pflash file is created if need be before crosvm is started.
+    if let Some(pflash) = &config.pflash {
+        paths.push((PathBuf::from(pflash), false));
+    }
+    let images = config
+        .kernel
+        .iter()
+        .chain(&config.initrd)
+        .chain(&config.bootloader)
+        .chain(config.disks.iter().map(|disk| &disk.image))
+        .map(String::as_str)
+        .chain(config.verity_hashtrees());
+    for image in images {
+        let metadata = fs::metadata(image)
+            .with_context(|| format!("Can't make {} visible to isolated crosvm", image))?;
+        paths.push((PathBuf::from(image), metadata.is_dir()));
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::callback_queue::CallbackQueue;
+    use crate::config::{AsyncExecutor, DiskImage, PayloadConfig};
+    use crate::protection::MemProtectionConfig;
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const CROSVM: &str = "/apex/com.android.virt/bin/crosvm";
+    const RUNTIME_DIR: &str = "/data/misc/virtmanager/vm10_0";
+
+    fn args(command: &Command) -> Vec<&str> {
+        command.get_args().map(|arg| arg.to_str().unwrap()).collect()
+    }
+
+    /// A crosvm binary whose supported flags haven't been probed.
+    fn crosvm() -> CrosvmBinary {
+        CrosvmBinary { path: PathBuf::from(CROSVM), version: "0.10.0".to_owned(), run_flags: None }
+    }
+
+    /// Create an empty temporary directory for the test with the given name, unique to this
+    /// process.
+    pub fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("virtmanager_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write a stub crosvm binary into the given directory which runs the given shell script.
+    pub fn stub_crosvm(dir: &Path, script: &str) -> CrosvmBinary {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join("crosvm");
+        fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        CrosvmBinary { path, ..crosvm() }
+    }
+
+    /// A config for a VM which only has a kernel, which the stub crosvm binaries don't read.
+    pub fn kernel_config() -> VmConfig {
+        VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() }
+    }
+
+    /// Start a VM with the given config and CID on the given stub crosvm binary, as the Virt
+    /// Manager would but without any clients to call back.
+    pub fn start_stub_vm(config: &VmConfig, crosvm: &CrosvmBinary, cid: Cid) -> Arc<VmInstance> {
+        let callbacks = VirtualMachineCallbacks::new(CallbackQueue::default(), Default::default());
+        let retry = VsockRetry::default();
+        VmInstance::start(config, crosvm, callbacks, cid, None, &retry, None, 0, String::new(), 0)
+            .unwrap()
+    }
+
+    /// Kill the given VM's crosvm, and wait for its monitor thread to notice and tear it down.
+    pub fn kill_stub_vm(vm: &VmInstance) {
+        vm.kill();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while vm.running() {
+            assert!(Instant::now() < deadline, "VM with CID {} still running", vm.cid);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn console_goes_to_runtime_dir_without_log_fd() {
+        let config =
+            VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        assert!(args(&command)
+            .contains(&"--serial=type=file,path=/data/misc/virtmanager/vm10_0/console.log"));
+    }
+
+    #[test]
+    fn irqchip_mode_selects_flag() {
+        let flags = |irqchip| {
+            let config = VmConfig {
+                kernel: Some("/data/local/tmp/kernel".to_owned()),
+                irqchip,
+                ..Default::default()
+            };
+            let command =
+                build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                    .unwrap();
+            args(&command)
+                .into_iter()
+                .filter(|arg| arg.ends_with("-irqchip"))
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+        assert!(flags(None).is_empty());
+        assert!(flags(Some(IrqChip::Kernel)).is_empty());
+        if cfg!(target_arch = "x86_64") {
+            assert_eq!(flags(Some(IrqChip::Split)), ["--split-irqchip"]);
+            assert_eq!(flags(Some(IrqChip::Userspace)), ["--userspace-irqchip"]);
+        }
+    }
+
+    #[test]
+    fn vhost_vsock_device_is_passed_to_crosvm() {
+        let config = VmConfig {
+            kernel: Some("/data/local/tmp/kernel".to_owned()),
+            vhost_vsock_device: Some("/dev/null".to_owned()),
+            ..Default::default()
+        };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        assert!(args(&command).windows(2).any(|w| w == ["--vhost-vsock-device", "/dev/null"]));
+
+        let config =
+            VmConfig { vhost_vsock_device: Some("/dev/no-such-vhost-vsock".to_owned()), ..config };
+        assert!(build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+            .is_err());
+    }
+
+    #[test]
+    fn safe_mode_has_only_core_devices() {
+        let disk = |image: &str, id: &str| DiskImage {
+            id: Some(id.to_owned()),
+            image: image.to_owned(),
+            writable: true,
+            verity: None,
+            sha256: None,
+        };
+        let config = VmConfig {
+            kernel: Some("/data/local/tmp/kernel".to_owned()),
+            disks: vec![disk("/data/local/tmp/data.img", "data"), disk("/dev/null", "root")],
+            boot_order: vec!["root".to_owned()],
+            irqchip: Some(IrqChip::Kernel),
+            rt_priority: Some(10),
+            memory_mib: Some(512),
+            watchdog: true,
+            cpu_features: vec!["no-smt".to_owned()],
+            stub_pci_devices: vec!["00:05.0".to_owned()],
+            console_logcat_tag: Some("guest".to_owned()),
+            ..Default::default()
+        };
+        let command = build_crosvm_command(
+            &config.safe_mode(),
+            Path::new(CROSVM),
+            10,
+            None,
+            Path::new(RUNTIME_DIR),
+        )
+        .unwrap();
+        assert_eq!(
+            args(&command),
+            [
+                "run",
+                "--disable-sandbox",
+                "--cid",
+                "10",
+                "--socket",
+                "/data/misc/virtmanager/vm10_0/crosvm.sock",
+                "--serial=type=file,path=/data/misc/virtmanager/vm10_0/console.log",
+                "--mem",
+                "512",
+                "--params",
+                "hostname=vm-10",
+                "--rwdisk",
+                "/dev/null",
+                "/data/local/tmp/kernel",
+            ]
+        );
+    }
+
+    #[test]
+    fn stop_racing_exit_reports_one_terminal_event() {
+        let dir = test_dir("stop_race");
+        // A stub crosvm which exits straight away, so that the monitor thread noticing it exit
+        // races the stop.
+        let crosvm = stub_crosvm(&dir, "exit 0\n");
+        for cid in 220..240 {
+            let vm = start_stub_vm(&kernel_config(), &crosvm, cid);
+            vm.stop();
+            kill_stub_vm(&vm);
+            // A later stop does nothing.
+            vm.stop();
+
+            let history = vm.state.history();
+            let terminal =
+                history.iter().filter(|(_, state)| matches!(state, Lifecycle::Stopped(_)));
+            assert_eq!(terminal.count(), 1, "{:?}", history);
+            assert!(matches!(vm.lifecycle(), Lifecycle::Stopped(_)));
+            assert!(vm.child.try_wait().unwrap().is_some());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn state_transitions_are_ordered_and_deduplicated() {
+        let state = VmState::new();
+        let transitions = vec![
+            state.transition(&[Lifecycle::Starting], Lifecycle::Running),
+            state.transition(&[Lifecycle::Running], Lifecycle::Paused),
+            state.transition(&[Lifecycle::Running], Lifecycle::Paused),
+            state.transition(&[Lifecycle::Paused], Lifecycle::Running),
+            state.transition(&[Lifecycle::Paused], Lifecycle::Running),
+            state.transition(
+                &[Lifecycle::Running, Lifecycle::Paused],
+                Lifecycle::Stopping(StopReason::Requested),
+            ),
+            state.exited(),
+            state.exited(),
+        ];
+        assert_eq!(
+            transitions.into_iter().flatten().collect::<Vec<_>>(),
+            [
+                Lifecycle::Running,
+                Lifecycle::Paused,
+                Lifecycle::Running,
+                Lifecycle::Stopping(StopReason::Requested),
+                Lifecycle::Stopped(StopReason::Requested),
+            ]
+        );
+    }
+
+    #[test]
+    fn pflash_is_created_and_passed_to_crosvm() {
+        let pflash = std::env::temp_dir().join("virtmanager_pflash_test.img");
+        let _ = fs::remove_file(&pflash);
+        let config = VmConfig {
+            bootloader: Some("/data/local/tmp/bootloader".to_owned()),
+            pflash: Some(pflash.to_str().unwrap().to_owned()),
+            ..Default::default()
+        };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        prepare_pflash(&pflash).unwrap();
+
+        assert_eq!(fs::metadata(&pflash).unwrap().len(), PFLASH_SIZE);
+        let expected = format!("path={}", pflash.display());
+        assert!(args(&command).windows(2).any(|w| w == ["--pflash", expected.as_str()]));
+        fs::remove_file(&pflash).unwrap();
+    }
+
+    #[test]
+    fn check_config_reports_errors_and_warnings() {
+        // The default config has nothing to boot, so every config here starts from one which does.
+        let minimal =
+            VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() };
+        assert!(check_config(&minimal, &crosvm()).errors.is_empty());
+        let invalid = VmConfig { rt_priority: Some(100), ..minimal.clone() };
+        let check = check_config(&invalid, &crosvm());
+        assert_eq!(check.errors.len(), 1);
+        assert!(check.errors[0].contains("priority"), "{:?}", check.errors);
+
+        let warns = VmConfig {
+            bootloader: Some("/data/local/tmp/bootloader".to_owned()),
+            hostname: Some("guest".to_owned()),
+            ..Default::default()
+        };
+        let check = check_config(&warns, &crosvm());
+        assert!(check.errors.is_empty());
+        assert_eq!(check.warnings.len(), 1);
+
+        // A crosvm which supports only the flags needed for a minimal config.
+        let command =
+            build_crosvm_command(&minimal, Path::new(CROSVM), 10, None, Path::new("/")).unwrap();
+        let run_flags = args(&command).into_iter().filter(|arg| arg.starts_with("--"));
+        let limited =
+            CrosvmBinary { run_flags: Some(run_flags.map(str::to_owned).collect()), ..crosvm() };
+        assert!(check_config(&minimal, &limited).errors.is_empty());
+        let watchdog = VmConfig { watchdog: true, ..minimal };
+        let check = check_config(&watchdog, &limited);
+        assert_eq!(check.errors.len(), 1);
+        assert!(check.errors[0].contains("--watchdog"), "{:?}", check.errors);
+    }
+
+    #[test]
+    fn crosvm_log_level_is_passed_in_environment() {
+        let rust_log = |crosvm_log_level: Option<&str>| {
+            let config = VmConfig {
+                kernel: Some("/data/local/tmp/kernel".to_owned()),
+                crosvm_log_level: crosvm_log_level.map(str::to_owned),
+                ..Default::default()
+            };
+            let command =
+                build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))?;
+            let rust_log = command.get_envs().find(|(name, _)| name.to_str() == Some("RUST_LOG"));
+            Ok::<_, Error>(rust_log.and_then(|(_, value)| value?.to_str()).map(str::to_owned))
+        };
+        assert_eq!(rust_log(None).unwrap().as_deref(), Some("warn"));
+        assert_eq!(rust_log(Some("debug")).unwrap().as_deref(), Some("debug"));
+        assert!(rust_log(Some("loud")).is_err());
+    }
+
+    #[test]
+    fn scratch_dir_is_crosvm_tmpdir() {
+        let config =
+            VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        let tmpdir = command.get_envs().find(|(name, _)| name.to_str() == Some("TMPDIR"));
+        assert_eq!(
+            tmpdir.and_then(|(_, value)| value?.to_str()),
+            Some("/data/misc/virtmanager/vm10_0/scratch")
+        );
+    }
+
+    #[test]
+    fn command_line_matches_spawned_command() {
+        let config = VmConfig {
+            kernel: Some("/data/local/tmp/kernel".to_owned()),
+            disks: vec![
+                DiskImage {
+                    id: None,
+                    image: "/data/local/tmp/disk.img".to_owned(),
+                    writable: true,
+                    verity: None,
+                    sha256: None,
+                },
+                DiskImage {
+                    id: None,
+                    image: "/proc/self/fd/7".to_owned(),
+                    writable: false,
+                    verity: None,
+                    sha256: None,
+                },
+            ],
+            ..Default::default()
+        };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        let command_line = command_line(&command);
+
+        assert_eq!(command_line[0], CROSVM);
+        assert_eq!(command_line.len(), args(&command).len() + 1);
+        for (reported, spawned) in command_line[1..].iter().zip(args(&command)) {
+            if spawned.contains(FD_PATH_PREFIX) {
+                assert!(reported.ends_with(REDACTED_FD_PATH));
+                assert!(!reported.contains(FD_PATH_PREFIX));
+            } else {
+                assert_eq!(reported, spawned);
+            }
+        }
+        assert!(command_line.contains(&"--rwdisk".to_owned()));
+        assert!(command_line.contains(&"/data/local/tmp/disk.img".to_owned()));
+        assert!(command_line.contains(&REDACTED_FD_PATH.to_owned()));
+    }
+
+    #[test]
+    fn readiness_waits_for_dependency_port() {
+        let a_service = Dependency { cid: 10, port: 1234 };
+        let a_listening = Arc::new(AtomicBool::new(false));
+        let opener = {
+            let a_listening = a_listening.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                a_listening.store(true, Ordering::SeqCst);
+            })
+        };
+
+        let mut attempts = 0;
+        let dependencies = [a_service.clone()];
+        let result = wait_for_dependencies(&dependencies, Duration::from_secs(10), |dependency| {
+            assert_eq!(dependency, &a_service);
+            attempts += 1;
+            a_listening.load(Ordering::SeqCst)
+        });
+        opener.join().unwrap();
+        assert_eq!(result, Ok(()));
+        assert!(attempts > 1);
+
+        let unreachable = [a_service.clone(), Dependency { cid: 11, port: 1234 }];
+        let result = wait_for_dependencies(&unreachable, Duration::from_secs(0), |dependency| {
+            dependency.cid == 10
+        });
+        assert_eq!(result, Err(&unreachable[1]));
+    }
+
+    #[test]
+    fn quiet_vm_only_logs_crashes() {
+        let exit_status = |code| Command::new("sh").arg("-c").arg(code).status().unwrap();
+        let clean = exit_status("exit 0");
+        let failed = exit_status("exit 1");
+
+        // Starting and stopping normally is only logged for VMs which aren't quiet.
+        assert_eq!(lifecycle_log_level(false, false), Some(Level::Info));
+        assert_eq!(lifecycle_log_level(true, false), None);
+        assert!(!crashed(Some(clean), StopReason::Exited));
+        assert!(!crashed(Some(failed), StopReason::Requested));
+        assert!(!crashed(None, StopReason::HostMemoryPressure));
+
+        // Crashes are always logged.
+        assert!(crashed(Some(failed), StopReason::Exited));
+        assert!(crashed(None, StopReason::Exited));
+        assert_eq!(lifecycle_log_level(true, true), Some(Level::Warn));
+        assert_eq!(lifecycle_log_level(false, true), Some(Level::Warn));
+    }
+
+    #[test]
+    fn simulated_crash_is_handled_as_crash() {
+        let child = SharedChild::spawn(Command::new("sleep").arg("10")).unwrap();
+        assert!(send_crash_signal(&child, libc::SIGTERM).is_err());
+        assert!(child.try_wait().unwrap().is_none());
+
+        send_crash_signal(&child, libc::SIGSEGV).unwrap();
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGSEGV));
+        assert!(crashed(Some(status), StopReason::Exited));
+        assert_eq!(
+            lifecycle_log_level(true, crashed(Some(status), StopReason::Exited)),
+            Some(Level::Warn)
+        );
+    }
+
+    #[test]
+    fn protected_vm_flags_are_passed_to_crosvm() {
+        let config =
+            VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        assert!(!args(&command).iter().any(|arg| arg.starts_with("--protected-vm")));
+
+        let config = VmConfig {
+            memory_protection: Some(MemProtectionConfig {
+                protected: true,
+                without_firmware: false,
+            }),
+            ..config
+        };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        assert!(args(&command).contains(&"--protected-vm"));
+    }
+
+    #[test]
+    fn entropy_seed_is_fresh_on_each_boot_and_not_on_command_line() {
+        let dir = test_dir("seed");
+        let initrd = dir.join("initrd");
+        fs::write(&initrd, b"initrd").unwrap();
+        let config = VmConfig {
+            kernel: Some("/data/local/tmp/kernel".to_owned()),
+            initrd: Some(initrd.to_string_lossy().into_owned()),
+            entropy_seed: true,
+            ..Default::default()
+        };
+        let seed_prefix = format!("{} = \"", bootconfig::ENTROPY_SEED_KEY);
+
+        let mut seeds = vec![];
+        for _ in 0..2 {
+            write_bootconfig_initrd(&config, &dir).unwrap();
+            let written = fs::read(dir.join(runtime_dir::BOOTCONFIG_INITRD_FILE)).unwrap();
+            let bootconfig = String::from_utf8_lossy(&written["initrd".len()..]).into_owned();
+            let seed = bootconfig.strip_prefix(&seed_prefix).unwrap();
+            seeds.push(seed[..seed.find('"').unwrap()].to_owned());
+        }
+        let command = build_crosvm_command(&config, Path::new(CROSVM), 10, None, &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_ne!(seeds[0], seeds[1]);
+        let args = args(&command);
+        assert!(args.iter().all(|arg| !arg.contains(&seeds[0]) && !arg.contains(&seeds[1])));
+        let initrd_arg = args.iter().position(|&arg| arg == "--initrd").unwrap() + 1;
+        assert_eq!(Path::new(args[initrd_arg]), dir.join(runtime_dir::BOOTCONFIG_INITRD_FILE));
+    }
+
+    #[test]
+    fn payload_output_is_separate_from_kernel_console() {
+        let dir = test_dir("payload");
+        // A stub crosvm whose guest writes to each console it is given.
+        let crosvm = stub_crosvm(
+            &dir,
+            "for arg; do\n\
+             case \"$arg\" in\n\
+             --serial=*hardware=virtio-console*) echo payload output > \"${arg##*path=}\" ;;\n\
+             --serial=*) echo kernel output > \"${arg##*path=}\" ;;\n\
+             esac\n\
+             done\n",
+        );
+        let config = VmConfig {
+            kernel: Some("/data/local/tmp/kernel".to_owned()),
+            payload: Some(PayloadConfig {
+                binary: "/bin/payload".to_owned(),
+                args: vec![],
+                separate_output: true,
+            }),
+            ..Default::default()
+        };
+
+        let mut command = build_crosvm_command(&config, &crosvm.path, 10, None, &dir).unwrap();
+        assert!(command.status().unwrap().success());
+
+        let console = fs::read_to_string(dir.join(CONSOLE_LOG_FILE)).unwrap();
+        let payload = fs::read_to_string(dir.join(PAYLOAD_OUTPUT_FILE)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(console, "kernel output\n");
+        assert_eq!(payload, "payload output\n");
+    }
+
+    #[test]
+    fn crosvm_is_relaunched_if_vhost_vsock_is_busy() {
+        let dir = test_dir("vsock");
+        // A stub crosvm which finds the vhost-vsock device busy the first time it is run.
+        let attempted = dir.join("attempted");
+        let crosvm = stub_crosvm(
+            &dir,
+            &format!(
+                "if [ ! -e {0} ]; then\n\
+                 touch {0}\n\
+                 echo 'failed to open vhost-vsock device: Device or resource busy' >&2\n\
+                 exit 1\n\
+                 fi\n\
+                 exec sleep 10\n",
+                attempted.display()
+            ),
+        );
+        let config =
+            VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() };
+        let retry = VsockRetry { attempts: 2, delay: Duration::from_millis(10) };
+
+        let (child, _, _) = run_vm(&config, &crosvm, 10, None, &dir, None, &retry, None).unwrap();
+        assert!(attempted.exists());
+        assert!(child.try_wait().unwrap().is_none());
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // Without retries the busy device isn't noticed until crosvm exits.
+        fs::remove_file(&attempted).unwrap();
+        fs::remove_dir(dir.join(runtime_dir::SCRATCH_DIR)).unwrap();
+        let (child, _, _) =
+            run_vm(&config, &crosvm, 10, None, &dir, None, &VsockRetry::default(), None).unwrap();
+        assert!(!child.wait().unwrap().success());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn process_name_fits_kernel_limit() {
+        assert_eq!(process_name("guest", 10), "guest-10");
+        assert_eq!(process_name("a-very-long-label", 10), "a-very-long--10");
+        assert_eq!(process_name("éééééééé", 1234), "ééééé-1234");
+        assert!(process_name("label", Cid::MAX).len() <= MAX_PROCESS_NAME_LEN);
+    }
+
+    #[test]
+    fn process_is_named_after_vm() {
+        let dir = test_dir("comm");
+        // A stub crosvm which keeps running. It doesn't exec, so keeps the name it was run with.
+        let crosvm = stub_crosvm(&dir, "sleep 10\n");
+        let config = VmConfig {
+            kernel: Some("/data/local/tmp/kernel".to_owned()),
+            process_label: Some("media-decoder".to_owned()),
+            ..Default::default()
+        };
+
+        let (child, command_line, _) =
+            run_vm(&config, &crosvm, 10, None, &dir, None, &VsockRetry::default(), None).unwrap();
+        let comm = fs::read_to_string(format!("/proc/{}/comm", child.id())).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(comm, "media-decode-10\n");
+        assert!(command_line[0].ends_with("/bin/media-decode-10"));
+    }
+
+    #[test]
+    fn diagnostics_contain_expected_entries() {
+        let dir = test_dir("diag");
+        let crosvm_log = "x".repeat(DIAGNOSTICS_CROSVM_LOG_TAIL_SIZE as usize) + "[ERROR] broke\n";
+        fs::write(dir.join(CROSVM_LOG_FILE), &crosvm_log).unwrap();
+        let console = "x".repeat(DIAGNOSTICS_CONSOLE_TAIL_SIZE as usize) + "last line\n";
+        fs::write(dir.join(CONSOLE_LOG_FILE), &console).unwrap();
+        let config =
+            VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() };
+        let mut command = build_crosvm_command(&config, Path::new(CROSVM), 10, None, &dir).unwrap();
+        command.env("ACCESS_TOKEN", "hunter2");
+        command.arg(format!("{}3", FD_PATH_PREFIX));
+        let usage = UsageSample {
+            uptime: Duration::from_secs(2),
+            rss_kib: 1024,
+            cpu_time: Duration::from_millis(1500),
+        };
+        let history = [(UNIX_EPOCH, Lifecycle::Starting), (UNIX_EPOCH, Lifecycle::Running)];
+
+        let mut archive = vec![];
+        write_diagnostics(
+            &mut archive,
+            &config,
+            &command_line(&command),
+            &command_environment(&command),
+            &dir,
+            Ok(usage),
+            &history,
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let entries = crate::diagnostics::read_tar(&archive);
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "config.json",
+                "crosvm_command.txt",
+                "crosvm_environment.txt",
+                "crosvm.log",
+                "console_tail.log",
+                "stats.txt",
+                "state_history.txt",
+            ]
+        );
+        let entry = |name| {
+            let (_, contents) = entries.iter().find(|(entry_name, _)| entry_name == name).unwrap();
+            String::from_utf8(contents.clone()).unwrap()
+        };
+        let loaded: VmConfig = serde_json::from_str(&entry("config.json")).unwrap();
+        assert_eq!(loaded.kernel, config.kernel);
+        let command_text = entry("crosvm_command.txt");
+        assert!(command_text.starts_with(&format!("{}\n", CROSVM)));
+        assert!(command_text.contains(REDACTED_FD_PATH));
+        assert!(!command_text.contains(FD_PATH_PREFIX));
+        let environment = entry("crosvm_environment.txt");
+        assert!(environment.contains(&format!("ACCESS_TOKEN={}\n", REDACTED_ENV_VALUE)));
+        assert!(!environment.contains("hunter2"));
+        assert!(environment.contains("RUST_LOG=warn\n"));
+        let crosvm_log = entry("crosvm.log");
+        assert_eq!(crosvm_log.len() as u64, DIAGNOSTICS_CROSVM_LOG_TAIL_SIZE);
+        assert!(crosvm_log.ends_with("[ERROR] broke\n"));
+        let tail = entry("console_tail.log");
+        assert_eq!(tail.len() as u64, DIAGNOSTICS_CONSOLE_TAIL_SIZE);
+        assert!(tail.ends_with("last line\n"));
+        assert!(entry("stats.txt").contains("rss_kib: 1024\n"));
+        assert_eq!(entry("state_history.txt"), "0 Starting\n0 Running\n");
+    }
+
+    #[test]
+    fn shared_base_has_per_vm_verity_parameters() {
+        use crate::config::VerityConfig;
+
+        let dir = test_dir("shared");
+        let base = dir.join("base.img");
+        fs::write(&base, vec![0; 8192]).unwrap();
+        // Each deployment has its own hashtree, and so its own root hash.
+        let config = |deployment: u8| {
+            let hashtree = dir.join(format!("base.{}.hashtree", deployment));
+            fs::write(&hashtree, vec![deployment; 4096]).unwrap();
+            let root_hash = ring::digest::digest(&ring::digest::SHA256, &[deployment; 4096]);
+            VmConfig {
+                kernel: Some("/data/local/tmp/kernel".to_owned()),
+                disks: vec![DiskImage {
+                    id: None,
+                    image: base.to_str().unwrap().to_owned(),
+                    writable: false,
+                    verity: Some(VerityConfig {
+                        hashtree: hashtree.to_str().unwrap().to_owned(),
+                        root_hash: root_hash
+                            .as_ref()
+                            .iter()
+                            .map(|b| format!("{:02x}", b))
+                            .collect(),
+                    }),
+                    sha256: None,
+                }],
+                ..Default::default()
+            }
+        };
+        let configs = [config(1), config(2)];
+
+        for (config, other) in configs.iter().zip(configs.iter().rev()) {
+            config.validate().unwrap();
+            let command =
+                build_crosvm_command(config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                    .unwrap();
+            let args = args(&command);
+            let base = base.to_str().unwrap();
+            let base_args: Vec<&str> =
+                args.windows(2).filter(|pair| pair[1] == base).map(|pair| pair[0]).collect();
+            assert_eq!(base_args, ["--disk"]);
+            let params = args[args.iter().position(|&arg| arg == "--params").unwrap() + 1];
+            let root_hash = |config: &VmConfig| config.disks[0].verity.clone().unwrap().root_hash;
+            assert!(params.contains(&root_hash(config)));
+            assert!(!params.contains(&root_hash(other)));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn agent_reports_kernel_version() {
+        use std::os::unix::net::UnixStream;
+
+        // A stub agent which answers a single request with the given reply.
+        let agent = |reply: &'static str| {
+            let (host, mut guest) = UnixStream::pair().unwrap();
+            let stub = thread::spawn(move || {
+                let mut request = String::new();
+                BufReader::new(&mut guest).read_line(&mut request).unwrap();
+                guest.write_all(reply.as_bytes()).unwrap();
+                request
+            });
+            (host, stub)
+        };
+        let version = "Linux localhost 5.10.43-android12-9 #1 SMP PREEMPT aarch64";
+
+        let (host, stub) = agent("ok Linux localhost 5.10.43-android12-9 #1 SMP PREEMPT aarch64\n");
+        assert_eq!(agent_request(host, "uname").unwrap(), version);
+        assert_eq!(stub.join().unwrap(), "uname\n");
+
+        let (host, stub) = agent("ok\n");
+        assert_eq!(agent_request(host, "shutdown").unwrap(), "");
+        stub.join().unwrap();
+
+        for reply in &["unknown request\n", "okay\n", ""] {
+            let (host, stub) = agent(reply);
+            assert!(agent_request(host, "uname").is_err(), "Reply {:?} was accepted", reply);
+            stub.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn stop_falls_back_to_sigterm_if_guest_does_not_shut_down() {
+        use std::os::unix::net::UnixStream;
+
+        // Stops a VM whose guest agent gives the given reply to the shutdown request, then powers
+        // off the guest if told to. Returns whether crosvm had to be sent SIGTERM.
+        let stop = |name: &str, cid: Cid, reply: &'static str, powers_off: bool| {
+            let dir = test_dir(name);
+            let (powered_off, terminated) = (dir.join("powered_off"), dir.join("terminated"));
+            let crosvm = stub_crosvm(
+                &dir,
+                &format!(
+                    "trap 'touch {1}; exit 1' TERM\n\
+                     while [ ! -e {0} ]; do sleep 0.1; done\n",
+                    powered_off.display(),
+                    terminated.display()
+                ),
+            );
+            let config = VmConfig { agent_port: Some(5678), ..kernel_config() };
+            let vm = start_stub_vm(&config, &crosvm, cid);
+
+            let (host, mut guest) = UnixStream::pair().unwrap();
+            let agent = thread::spawn(move || {
+                let mut request = String::new();
+                BufReader::new(&mut guest).read_line(&mut request).unwrap();
+                guest.write_all(reply.as_bytes()).unwrap();
+                if powers_off {
+                    fs::write(powered_off, "").unwrap();
+                }
+                request
+            });
+            let timeout = Duration::from_secs(2);
+            vm.stop_with(StopReason::Requested, || agent_request(host, "shutdown"), timeout);
+            assert_eq!(agent.join().unwrap(), "shutdown\n");
+            assert!(!vm.ping());
+            let sent_sigterm = terminated.exists();
+            kill_stub_vm(&vm);
+            fs::remove_dir_all(&dir).unwrap();
+            sent_sigterm
+        };
+
+        assert!(!stop("agent_shutdown", 210, "ok\n", true));
+        assert!(stop("agent_refusal", 211, "unknown request\n", false));
+        assert!(stop("agent_timeout", 212, "ok\n", false));
+    }
+
+    #[test]
+    fn concurrent_pauses_and_resumes_are_serialised() {
+        let dir = test_dir("pause_order");
+        let commands = dir.join("commands");
+        // A stub crosvm which records control commands, taking a while over each.
+        let crosvm = stub_crosvm(
+            &dir,
+            &format!(
+                "case \"$1\" in\n\
+                 run) exec sleep 10 ;;\n\
+                 *) echo \"$1\" >> {}; sleep 0.05 ;;\n\
+                 esac\n",
+                commands.display()
+            ),
+        );
+        let vm = start_stub_vm(&kernel_config(), &crosvm, 213);
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let vm = vm.clone();
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        // Most attempts fail as the VM is already in the state they ask for.
+                        let _ = if i % 2 == 0 { vm.pause() } else { vm.resume() };
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Each command was only sent in the state it applies to, so they alternate, and the VM is
+        // left in the state the last one moved it to.
+        let commands = fs::read_to_string(&commands).unwrap();
+        let commands: Vec<&str> = commands.lines().collect();
+        assert!(!commands.is_empty());
+        for (i, command) in commands.iter().enumerate() {
+            assert_eq!(*command, if i % 2 == 0 { "suspend" } else { "resume" }, "{:?}", commands);
+        }
+        let expected = if commands.len() % 2 == 1 { Lifecycle::Paused } else { Lifecycle::Running };
+        assert_eq!(vm.lifecycle(), expected);
+        kill_stub_vm(&vm);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn device_init_failures_name_the_device() {
+        let log = "[INFO] crosvm starting\n\
+                   [ERROR] The architecture failed to build the vm: error creating devices: \
+                   failed to create gpu device: no gpu backend available\n";
+        let failure = device_init_failure(log.as_bytes()).unwrap();
+        assert_eq!(failure.device, "gpu");
+        assert_eq!(failure.message, "no gpu backend available");
+
+        // The device is taken from crosvm's error, not from whatever the underlying error says.
+        let block = "[ERROR] failed to create block device: invalid input\n";
+        let failure = device_init_failure(block.as_bytes()).unwrap();
+        assert_eq!(failure.device, "block");
+        assert_eq!(failure.message, "invalid input");
+        let rng = "[ERROR] failed to set up rng: failed to create block device: nested\n";
+        assert_eq!(device_init_failure(rng.as_bytes()).unwrap().device, "rng");
+
+        let other = "[ERROR] failed to open vhost-vsock device: Device or resource busy\n";
+        assert!(device_init_failure(other.as_bytes()).is_none());
+    }
+
+    #[test]
+    fn launch_is_abandoned_when_deadline_passes() {
+        let dir = test_dir("deadline");
+        // A stub crosvm which hangs while starting up, and one which always finds the vhost-vsock
+        // device busy.
+        let hanging = stub_crosvm(&dir, "exec sleep 10\n");
+        let config =
+            VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() };
+        let mut command = build_crosvm_command(&config, &hanging.path, 10, None, &dir).unwrap();
+        let retry = VsockRetry { attempts: 10, delay: Duration::from_secs(1) };
+
+        let start = Instant::now();
+        let deadline = Some(start + Duration::from_millis(100));
+        let error = spawn_crosvm(&mut command, &dir, &retry, deadline).unwrap_err();
+        assert!(error.downcast_ref::<DeadlineExceeded>().is_some());
+        assert!(start.elapsed() < SETTLE_TIME);
+
+        let busy = stub_crosvm(
+            &dir,
+            "echo 'failed to open vhost-vsock device: Device or resource busy' >&2\nexit 1\n",
+        );
+        let mut command = build_crosvm_command(&config, &busy.path, 10, None, &dir).unwrap();
+        let start = Instant::now();
+        let deadline = Some(start + Duration::from_millis(700));
+        let error = spawn_crosvm(&mut command, &dir, &retry, deadline).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(error.downcast_ref::<DeadlineExceeded>().is_some());
+        // It gives up as soon as it knows the next retry would be too late.
+        assert!(start.elapsed() < Duration::from_millis(700));
+    }
+
+    #[test]
+    fn start_fails_if_crosvm_cannot_set_up_device() {
+        let dir = test_dir("device");
+        // A stub crosvm which exits straight away because the GPU backend is missing.
+        let crosvm = stub_crosvm(
+            &dir,
+            "echo '[ERROR] crosvm has exited with error: failed to create gpu device: \
+             no backend' >&2\n\
+             exit 1\n",
+        );
+        let config =
+            VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() };
+        // crosvm is only waited for to settle if vsock retries are enabled.
+        let retry = VsockRetry { attempts: 1, ..Default::default() };
+
+        let unsettled = dir.join("unsettled");
+        fs::create_dir(&unsettled).unwrap();
+        let start = Instant::now();
+        run_vm(&config, &crosvm, 10, None, &unsettled, None, &VsockRetry::default(), None).unwrap();
+        assert!(start.elapsed() < SETTLE_TIME);
+
+        let error = run_vm(&config, &crosvm, 10, None, &dir, None, &retry, None).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        let failure = error.downcast_ref::<DeviceInitFailed>().unwrap();
+        assert_eq!(failure.device, "gpu");
+        assert_eq!(failure.message, "no backend");
+    }
+
+    #[test]
+    fn async_executor_is_only_passed_when_chosen() {
+        let config =
+            VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        assert!(!args(&command).contains(&"--async-executor"));
+
+        let config = VmConfig { async_executor: Some(AsyncExecutor::Epoll), ..config };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        let args = args(&command);
+        let flag = args.iter().position(|&arg| arg == "--async-executor").unwrap();
+        assert_eq!(args[flag + 1], "epoll");
+    }
+
+    #[test]
+    fn watchdog_flag_is_emitted_when_enabled() {
+        let config =
+            VmConfig { kernel: Some("/data/local/tmp/kernel".to_owned()), ..Default::default() };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        assert!(!args(&command).contains(&"--watchdog"));
+
+        let config = VmConfig { watchdog: true, watchdog_timeout_secs: Some(30), ..config };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        let args = args(&command);
+        let watchdog = args.iter().position(|&arg| arg == "--watchdog").unwrap();
+        assert_eq!(args[watchdog + 1..watchdog + 3], ["--watchdog-timeout", "30"]);
+
+        let config = VmConfig { watchdog: false, ..config };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn cpu_features_select_flags() {
+        let config = VmConfig {
+            kernel: Some("/data/local/tmp/kernel".to_owned()),
+            cpu_model: Some("host".to_owned()),
+            cpu_features: vec!["no-smt".to_owned(), "host-cpu-topology".to_owned()],
+            ..Default::default()
+        };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        let args = args(&command);
+        assert!(args.contains(&"--no-smt"));
+        assert!(args.contains(&"--host-cpu-topology"));
+        assert!(!args.contains(&"--itmt"));
+
+        let config = VmConfig { cpu_features: vec!["avx512".to_owned()], ..config };
+        assert!(build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+            .is_err());
+        let config = VmConfig { cpu_model: Some("skylake".to_owned()), ..Default::default() };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn virtio_negotiation_failures_are_found_in_log() {
+        let log = "[2021-06-01T12:00:00.000Z INFO  crosvm] crosvm started.\n\
+                   [2021-06-01T12:00:01.000Z WARN  devices::virtio::block] virtio-blk: Received \
+                   acknowledge request for unknown feature: 1000000\n\
+                   [2021-06-01T12:00:01.000Z WARN  devices::virtio::block] virtio-blk: Received \
+                   acknowledge request for unknown feature: 1000000\n\
+                   virtio-gpu: driver acked unsupported features 0x8\n\
+                   [2021-06-01T12:00:02.000Z ERROR crosvm] guest panicked\n";
+        assert_eq!(
+            virtio_negotiation_failures(log.as_bytes()),
+            [
+                "virtio-blk: Received acknowledge request for unknown feature: 1000000",
+                "virtio-gpu: driver acked unsupported features 0x8",
+            ]
+        );
+        assert!(virtio_negotiation_failures(&b"[date INFO crosvm] all fine\n"[..]).is_empty());
+    }
+
+    #[test]
+    fn isolated_mounts_include_images() {
+        let kernel = std::env::temp_dir().join("virtmanager_isolated_kernel");
+        fs::write(&kernel, "kernel").unwrap();
+        let config = VmConfig {
+            kernel: Some(kernel.to_str().unwrap().to_owned()),
+            isolate_mounts: true,
+            ..Default::default()
+        };
+        let paths = isolated_paths(&config, Path::new(CROSVM), Path::new(RUNTIME_DIR)).unwrap();
+        assert!(paths.contains(&(kernel.clone(), false)));
+        assert!(paths.contains(&(PathBuf::from(RUNTIME_DIR), true)));
+        assert!(paths.contains(&(PathBuf::from(CROSVM), false)));
+        assert!(paths.contains(&(PathBuf::from("/dev"), true)));
+        fs::remove_file(&kernel).unwrap();
+
+        // Images which don't exist can't be mounted.
+        assert!(build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+            .is_err());
+    }
+
+    #[test]
+    fn stub_pci_devices_are_passed_to_crosvm() {
+        let config = VmConfig {
+            kernel: Some("/data/local/tmp/kernel".to_owned()),
+            stub_pci_devices: vec!["00:05.0".to_owned(), "00:06.0".to_owned()],
+            ..Default::default()
+        };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        let args = args(&command);
+        let stubs: Vec<&str> =
+            args.windows(2).filter(|w| w[0] == "--stub-pci-device").map(|w| w[1]).collect();
+        assert_eq!(stubs, ["00:05.0", "00:06.0"]);
+
+        let config = VmConfig {
+            stub_pci_devices: vec!["00:05.0".to_owned(), "00:05.0".to_owned()],
+            ..config
+        };
+        assert!(build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+            .is_err());
+    }
+
+    #[test]
+    fn minimal_platform_disables_optional_devices() {
+        let config = VmConfig {
+            kernel: Some("/data/local/tmp/kernel".to_owned()),
+            minimal_platform: true,
+            ..Default::default()
+        };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        let args = args(&command);
+        assert!(args.contains(&"--no-acpi"));
+        assert!(args.contains(&"--no-rtc"));
+        assert!(args.contains(&"--no-i8042"));
+
+        let config = VmConfig { minimal_platform: false, ..config };
+        let command =
+            build_crosvm_command(&config, Path::new(CROSVM), 10, None, Path::new(RUNTIME_DIR))
+                .unwrap();
+        assert!(!args(&command).contains(&"--no-acpi"));
+    }
 }
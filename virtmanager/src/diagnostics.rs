@@ -0,0 +1,125 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Writing of diagnostic bundles for bug reports, as tar archives.
+
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The size of tar headers, and the unit in which file contents are padded.
+const BLOCK_SIZE: usize = 512;
+
+/// The longest name a file in the archive may have, in bytes.
+const MAX_NAME_LEN: usize = 100;
+
+/// A tar archive being written, of regular files only.
+pub struct TarWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    /// Start an archive written to the given writer.
+    pub fn new(out: W) -> TarWriter<W> {
+        TarWriter { out }
+    }
+
+    /// Add a file with the given name and contents to the archive.
+    pub fn add(&mut self, name: &str, contents: &[u8]) -> io::Result<()> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Name {:?} is too long for a tar archive", name),
+            ));
+        }
+        let mtime = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_octal(&mut header[100..108], 0o644);
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], contents.len() as u64);
+        write_octal(&mut header[136..148], mtime);
+        header[156] = b'0';
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        // The checksum is calculated with its own field filled with spaces.
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u64 = header.iter().map(|&byte| u64::from(byte)).sum();
+        write_octal(&mut header[148..155], checksum);
+        self.out.write_all(&header)?;
+        self.out.write_all(contents)?;
+        self.out.write_all(&[0; BLOCK_SIZE][..padding(contents.len())])
+    }
+
+    /// Write the end of the archive, and return the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0; 2 * BLOCK_SIZE])?;
+        Ok(self.out)
+    }
+}
+
+/// Write the given value into the given header field as zero-padded octal, followed by a null.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:01$o}", value, field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// Get how much padding must follow file contents of the given length to fill a whole block.
+fn padding(len: usize) -> usize {
+    (BLOCK_SIZE - len % BLOCK_SIZE) % BLOCK_SIZE
+}
+
+/// Get the names and contents of the files in the given tar archive, as written by `TarWriter`.
+#[cfg(test)]
+pub fn read_tar(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut files = vec![];
+    let mut offset = 0;
+    while archive[offset] != 0 {
+        let header = &archive[offset..offset + BLOCK_SIZE];
+        let name_len = header[..MAX_NAME_LEN].iter().position(|&byte| byte == 0).unwrap();
+        let name = String::from_utf8(header[..name_len].to_vec()).unwrap();
+        let size = std::str::from_utf8(&header[124..135]).unwrap();
+        let size = usize::from_str_radix(size, 8).unwrap();
+        offset += BLOCK_SIZE;
+        files.push((name, archive[offset..offset + size].to_vec()));
+        offset += size + padding(size);
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_written_in_blocks() {
+        let mut tar = TarWriter::new(vec![]);
+        tar.add("config.json", b"{}").unwrap();
+        tar.add("empty", b"").unwrap();
+        tar.add("block", &[b'x'; BLOCK_SIZE]).unwrap();
+        assert!(tar.add(&"x".repeat(MAX_NAME_LEN + 1), b"").is_err());
+        let archive = tar.finish().unwrap();
+
+        assert_eq!(archive.len(), 7 * BLOCK_SIZE);
+        assert_eq!(&archive[257..262], b"ustar");
+        assert_eq!(
+            read_tar(&archive),
+            [
+                ("config.json".to_owned(), b"{}".to_vec()),
+                ("empty".to_owned(), vec![]),
+                ("block".to_owned(), vec![b'x'; BLOCK_SIZE]),
+            ]
+        );
+    }
+}
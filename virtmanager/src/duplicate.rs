@@ -0,0 +1,132 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What to do when a VM is started from a config file which another running VM was started from.
+
+use anyhow::{bail, Error};
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// Environment variable with what to do when the same config file is started twice:
+/// `allow_duplicate`, `reject_duplicate` or `reuse_existing`.
+const DUPLICATE_POLICY_VAR: &str = "VIRTMANAGER_DUPLICATE_POLICY";
+
+/// Error returned when a VM is started from a config file which a running VM was already started
+/// from, and duplicates are rejected.
+#[derive(Debug, thiserror::Error)]
+#[error("A VM started from {path:?} is already running.")]
+pub struct AlreadyRunning {
+    /// The canonical path of the config file.
+    pub path: PathBuf,
+}
+
+/// What to do when a VM is started from the same config file as a VM which is already running,
+/// by the same UID. Configs which weren't loaded from a file with a path, such as those sent
+/// through a pipe, are never counted as duplicates.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Start another VM, independent of the first.
+    AllowDuplicate,
+    /// Fail with `AlreadyRunning`.
+    RejectDuplicate,
+    /// Return the VM which is already running rather than starting another.
+    ReuseExisting,
+}
+
+impl Default for DuplicatePolicy {
+    fn default() -> DuplicatePolicy {
+        DuplicatePolicy::AllowDuplicate
+    }
+}
+
+impl DuplicatePolicy {
+    /// Get the policy configured in the environment. Duplicates are allowed if none is configured.
+    pub fn from_env() -> Result<DuplicatePolicy, Error> {
+        Ok(match env::var(DUPLICATE_POLICY_VAR).as_deref() {
+            Ok("allow_duplicate") | Err(_) => DuplicatePolicy::AllowDuplicate,
+            Ok("reject_duplicate") => DuplicatePolicy::RejectDuplicate,
+            Ok("reuse_existing") => DuplicatePolicy::ReuseExisting,
+            Ok(other) => bail!("Invalid {} {:?}", DUPLICATE_POLICY_VAR, other),
+        })
+    }
+
+    /// Decide whether to start a VM from the config file with the given canonical path, given the
+    /// VMs already running and a function to get the config path each was started from. Returns the
+    /// running VM to use instead if there is one, or `None` if a new VM should be started.
+    pub fn admit<T>(
+        &self,
+        path: Option<&Path>,
+        running: impl IntoIterator<Item = T>,
+        path_of: impl Fn(&T) -> Option<&Path>,
+    ) -> Result<Option<T>, AlreadyRunning> {
+        let path = match (self, path) {
+            (DuplicatePolicy::AllowDuplicate, _) | (_, None) => return Ok(None),
+            (_, Some(path)) => path,
+        };
+        let existing = match running.into_iter().find(|vm| path_of(vm) == Some(path)) {
+            Some(existing) => existing,
+            None => return Ok(None),
+        };
+        match self {
+            DuplicatePolicy::ReuseExisting => Ok(Some(existing)),
+            _ => Err(AlreadyRunning { path: path.to_owned() }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG_PATH: &str = "/data/local/tmp/vm_config.json";
+
+    /// Start a VM from the same config file twice with the given policy, returning the result of
+    /// the second start. VMs are represented by the index they were started with.
+    fn start_twice(policy: DuplicatePolicy) -> Result<Option<(usize, PathBuf)>, AlreadyRunning> {
+        fn path_of(vm: &(usize, PathBuf)) -> Option<&Path> {
+            Some(&vm.1)
+        }
+        let path = Path::new(CONFIG_PATH);
+        let mut running = vec![(0, PathBuf::from("/data/local/tmp/other.json"))];
+        assert!(policy.admit(Some(path), running.clone(), path_of).unwrap().is_none());
+        running.push((1, path.to_owned()));
+        policy.admit(Some(path), running, path_of)
+    }
+
+    #[test]
+    fn duplicate_is_allowed() {
+        assert!(start_twice(DuplicatePolicy::AllowDuplicate).unwrap().is_none());
+    }
+
+    #[test]
+    fn duplicate_is_rejected() {
+        let error = start_twice(DuplicatePolicy::RejectDuplicate).unwrap_err();
+        assert_eq!(error.path, Path::new(CONFIG_PATH));
+    }
+
+    #[test]
+    fn existing_vm_is_reused() {
+        let existing = start_twice(DuplicatePolicy::ReuseExisting).unwrap();
+        assert_eq!(existing, Some((1, PathBuf::from(CONFIG_PATH))));
+    }
+
+    #[test]
+    fn configs_without_paths_are_never_duplicates() {
+        let running = vec![None, Some(Path::new(CONFIG_PATH))];
+        for &policy in &[DuplicatePolicy::RejectDuplicate, DuplicatePolicy::ReuseExisting] {
+            let admitted = policy.admit(None, running.clone(), |&path| path);
+            assert!(admitted.unwrap().is_none());
+        }
+    }
+}
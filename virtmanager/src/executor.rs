@@ -0,0 +1,100 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Selection of the async executor crosvm uses for VMs which don't choose their own, if the
+//! service is configured with one.
+
+use crate::config::AsyncExecutor;
+use log::{info, warn};
+use std::env;
+
+/// Environment variable with the async executor to use by default, `uring` or `epoll`.
+const DEFAULT_EXECUTOR_VAR: &str = "VIRTMANAGER_ASYNC_EXECUTOR";
+
+/// The size of `struct io_uring_params`, which the kernel fills in when setting up a ring.
+const IO_URING_PARAMS_SIZE: usize = 120;
+
+/// Get the async executor to use for VMs which don't specify one, as configured in the
+/// environment. If none is configured then `None` is returned, and crosvm is left to use its own
+/// default. If io_uring is configured but the host kernel doesn't support it then epoll is used
+/// instead.
+pub fn default_from_env() -> Option<AsyncExecutor> {
+    let configured = match env::var(DEFAULT_EXECUTOR_VAR).as_deref() {
+        Ok("uring") => AsyncExecutor::Uring,
+        Ok("epoll") => AsyncExecutor::Epoll,
+        Ok(other) => {
+            warn!("Ignoring unknown async executor {:?} in {}", other, DEFAULT_EXECUTOR_VAR);
+            return None;
+        }
+        Err(_) => return None,
+    };
+    let (executor, warning) = choose_default(configured, io_uring_available);
+    if let Some(warning) = warning {
+        warn!("{}", warning);
+    }
+    info!("Using the {} async executor by default", executor.name());
+    Some(executor)
+}
+
+/// Choose the default async executor, falling back to epoll if io_uring is configured but not
+/// available. Returns the executor along with a warning if it had to fall back.
+///
+/// The probe runs in the Virt Manager rather than in crosvm, whose sandbox may still forbid
+/// io_uring, so it can only show that the kernel lacks io_uring, not that crosvm can use it.
+fn choose_default(
+    configured: AsyncExecutor,
+    uring_available: impl FnOnce() -> bool,
+) -> (AsyncExecutor, Option<String>) {
+    match configured {
+        AsyncExecutor::Uring if !uring_available() => (
+            AsyncExecutor::Epoll,
+            Some("io_uring is not available on this host, falling back to epoll".to_owned()),
+        ),
+        executor => (executor, None),
+    }
+}
+
+/// Check whether the host kernel supports io_uring, by trying to set up a ring.
+fn io_uring_available() -> bool {
+    let mut params = [0u8; IO_URING_PARAMS_SIZE];
+    // Safe because we pass a valid pointer to a buffer the size of `struct io_uring_params`, which
+    // is all the kernel writes to, and check the return value.
+    let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, 1, params.as_mut_ptr()) };
+    if fd < 0 {
+        return false;
+    }
+    // Safe because we own the file descriptor we were just given, and don't use it again.
+    unsafe {
+        libc::close(fd as libc::c_int);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_epoll_without_io_uring() {
+        let (executor, warning) = choose_default(AsyncExecutor::Uring, || false);
+        assert_eq!(executor, AsyncExecutor::Epoll);
+        assert!(warning.unwrap().contains("falling back to epoll"));
+
+        assert_eq!(choose_default(AsyncExecutor::Uring, || true), (AsyncExecutor::Uring, None));
+        assert_eq!(
+            choose_default(AsyncExecutor::Epoll, || panic!("Probed unnecessarily")),
+            (AsyncExecutor::Epoll, None)
+        );
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracking of failures across all VMs, for a summary of the health of the service.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The device through which crosvm uses KVM.
+const KVM_DEVICE: &str = "/dev/kvm";
+
+/// The default vhost-vsock device which crosvm uses for guests' vsock devices.
+const VHOST_VSOCK_DEVICE: &str = "/dev/vhost-vsock";
+
+/// A VM which crashes within this long of starting is counted towards a crash loop.
+const EARLY_CRASH_UPTIME: Duration = Duration::from_secs(60);
+
+/// How long early crashes are counted towards a crash loop for.
+const CRASH_LOOP_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// How many early crashes within `CRASH_LOOP_WINDOW` mean that VMs are in a crash loop.
+const CRASH_LOOP_THRESHOLD: usize = 3;
+
+/// A thread which hasn't sent a heartbeat for this long is taken to have stalled. This is much
+/// longer than a VM's monitor thread should ever take between heartbeats, even while it adjusts
+/// the memory balloon.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Which of the host capabilities that VMs need are present.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HostCapabilities {
+    /// Whether KVM is available.
+    pub kvm: bool,
+    /// Whether vhost-vsock is available.
+    pub vsock: bool,
+}
+
+impl HostCapabilities {
+    /// Check which capabilities the host currently has.
+    pub fn probe() -> HostCapabilities {
+        HostCapabilities {
+            kvm: Path::new(KVM_DEVICE).exists(),
+            vsock: Path::new(VHOST_VSOCK_DEVICE).exists(),
+        }
+    }
+}
+
+/// Heartbeats from a thread which should keep running, such as a VM's monitor thread, to tell
+/// whether it has died or stalled.
+#[derive(Debug)]
+pub struct Heartbeat {
+    last: Mutex<Instant>,
+    panicked: AtomicBool,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Heartbeat {
+        Heartbeat::new()
+    }
+}
+
+impl Heartbeat {
+    /// Start expecting heartbeats, counting from now.
+    pub fn new() -> Heartbeat {
+        Heartbeat { last: Mutex::new(Instant::now()), panicked: AtomicBool::new(false) }
+    }
+
+    /// Record that the thread is still making progress.
+    pub fn beat(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    /// Get a guard for the thread to hold while it runs, which records if the thread panics so that
+    /// it is seen to have died straight away, before the heartbeats time out.
+    pub fn watch(&self) -> PanicWatch<'_> {
+        PanicWatch { heartbeat: self }
+    }
+
+    /// Check whether the thread has panicked or not sent a heartbeat for `HEARTBEAT_TIMEOUT` as of
+    /// the given time.
+    pub fn stalled(&self, now: Instant) -> bool {
+        self.panicked.load(Ordering::Acquire)
+            || now.saturating_duration_since(*self.last.lock().unwrap()) > HEARTBEAT_TIMEOUT
+    }
+}
+
+/// Records if the thread holding it panics. See `Heartbeat::watch`.
+pub struct PanicWatch<'a> {
+    heartbeat: &'a Heartbeat,
+}
+
+impl Drop for PanicWatch<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.heartbeat.panicked.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// A summary of the health of the service at one point in time.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Health {
+    /// Whether VMs are in a crash loop, the monitor thread of any VM has stalled, or the host is
+    /// missing a capability which VMs need.
+    pub degraded: bool,
+    /// The number of VMs currently running.
+    pub running_vms: usize,
+    /// The number of running VMs whose monitor threads have died or stalled, so whose state
+    /// changes and crashes are no longer noticed.
+    pub stalled_monitors: usize,
+    /// The number of VMs which have failed to start since the service started.
+    pub failed_starts: u64,
+    /// The number of VMs which crashed soon after starting within the last `CRASH_LOOP_WINDOW`.
+    pub early_crashes: usize,
+    /// The host capabilities which are present.
+    pub capabilities: HostCapabilities,
+}
+
+/// Keeps count of failures across all VMs.
+#[derive(Debug, Default)]
+pub struct HealthTracker {
+    failed_starts: u64,
+    /// When each VM which crashed soon after starting did so, oldest first.
+    early_crashes: VecDeque<Instant>,
+}
+
+impl HealthTracker {
+    /// Record that a VM failed to start.
+    pub fn record_failed_start(&mut self) {
+        self.failed_starts += 1;
+    }
+
+    /// Record that a VM crashed at the given time after running for the given time.
+    pub fn record_crash(&mut self, uptime: Duration, now: Instant) {
+        if uptime < EARLY_CRASH_UPTIME {
+            self.early_crashes.push_back(now);
+        }
+    }
+
+    /// Summarise the health of the service at the given time, given the number of VMs running, how
+    /// many of their monitor threads have stalled, and the host's capabilities.
+    pub fn summary(
+        &mut self,
+        running_vms: usize,
+        stalled_monitors: usize,
+        capabilities: HostCapabilities,
+        now: Instant,
+    ) -> Health {
+        while let Some(&crash) = self.early_crashes.front() {
+            if now.saturating_duration_since(crash) <= CRASH_LOOP_WINDOW {
+                break;
+            }
+            self.early_crashes.pop_front();
+        }
+        let early_crashes = self.early_crashes.len();
+        Health {
+            degraded: early_crashes >= CRASH_LOOP_THRESHOLD
+                || stalled_monitors > 0
+                || !capabilities.kvm
+                || !capabilities.vsock,
+            running_vms,
+            stalled_monitors,
+            failed_starts: self.failed_starts,
+            early_crashes,
+            capabilities,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const CAPABLE: HostCapabilities = HostCapabilities { kvm: true, vsock: true };
+
+    #[test]
+    fn crash_loop_is_degraded() {
+        let start = Instant::now();
+        let mut tracker = HealthTracker::default();
+        tracker.record_failed_start();
+        assert!(!tracker.summary(1, 0, CAPABLE, start).degraded);
+
+        // A VM which ran for a while before crashing isn't part of a crash loop.
+        tracker.record_crash(Duration::from_secs(3600), start);
+        for i in 0..CRASH_LOOP_THRESHOLD as u64 {
+            tracker.record_crash(Duration::from_secs(1), start + Duration::from_secs(i));
+        }
+        let health = tracker.summary(0, 0, CAPABLE, start + Duration::from_secs(10));
+        assert_eq!(
+            health,
+            Health {
+                degraded: true,
+                running_vms: 0,
+                stalled_monitors: 0,
+                failed_starts: 1,
+                early_crashes: CRASH_LOOP_THRESHOLD,
+                capabilities: CAPABLE,
+            }
+        );
+
+        // Once the crashes are old enough the service is healthy again.
+        let later = start + CRASH_LOOP_WINDOW + Duration::from_secs(10);
+        let health = tracker.summary(0, 0, CAPABLE, later);
+        assert!(!health.degraded);
+        assert_eq!(health.early_crashes, 0);
+
+        let no_kvm = HostCapabilities { kvm: false, ..CAPABLE };
+        assert!(tracker.summary(0, 0, no_kvm, later).degraded);
+    }
+
+    #[test]
+    fn panicked_monitor_is_degraded() {
+        let heartbeat = Arc::new(Heartbeat::new());
+        assert!(!heartbeat.stalled(Instant::now()));
+        let monitor_heartbeat = heartbeat.clone();
+        let monitor = thread::spawn(move || {
+            let _watch = monitor_heartbeat.watch();
+            monitor_heartbeat.beat();
+            panic!("Monitor failed");
+        });
+        assert!(monitor.join().is_err());
+        assert!(heartbeat.stalled(Instant::now()));
+
+        let mut tracker = HealthTracker::default();
+        let health = tracker.summary(1, 1, CAPABLE, Instant::now());
+        assert!(health.degraded);
+        assert_eq!(health.stalled_monitors, 1);
+    }
+
+    #[test]
+    fn monitor_without_heartbeats_is_stalled() {
+        let heartbeat = Heartbeat::new();
+        let start = Instant::now();
+        heartbeat.beat();
+        assert!(!heartbeat.stalled(start + HEARTBEAT_TIMEOUT / 2));
+        assert!(heartbeat.stalled(start + HEARTBEAT_TIMEOUT * 2));
+    }
+}
@@ -15,13 +15,36 @@
 //! Android Virt Manager
 
 mod aidl;
+mod balloon;
+mod binaries;
+mod bootconfig;
+mod callback_queue;
+mod cgroup;
 mod config;
+mod console;
 mod crosvm;
+mod diagnostics;
+mod duplicate;
+mod executor;
+mod health;
+mod memory;
+mod pressure;
+mod process;
+mod protection;
+mod quota;
+mod retry;
+mod runtime_dir;
+mod teardown;
+mod usage;
+mod vsock;
 
 use crate::aidl::{VirtManager, BINDER_SERVICE_IDENTIFIER};
+use crate::binaries::CrosvmRegistry;
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtManager::BnVirtManager;
 use android_system_virtmanager::binder::{add_service, BinderFeatures, ProcessState};
-use log::{info, Level};
+use anyhow::{bail, Context, Error};
+use log::{info, warn, Level};
+use std::env;
 
 /// The first CID to assign to a guest VM managed by the Virt Manager. CIDs lower than this are
 /// reserved for the host or other usage.
@@ -29,6 +52,16 @@ const FIRST_GUEST_CID: Cid = 10;
 
 const LOG_TAG: &str = "VirtManager";
 
+/// Environment variable with the maximum number of threads in the Binder thread pool.
+const BINDER_THREADS_VAR: &str = "VIRTMANAGER_BINDER_THREADS";
+
+/// The maximum number of Binder threads if `BINDER_THREADS_VAR` is not set, which is the same as
+/// libbinder's default.
+const DEFAULT_BINDER_THREADS: u32 = 15;
+
+/// The most Binder threads which may be configured, to stop a typo from exhausting the host.
+const MAX_BINDER_THREADS: u32 = 64;
+
 /// The unique ID of a VM used (together with a port number) for vsock communication.
 type Cid = u32;
 
@@ -37,12 +70,59 @@ fn main() {
         android_logger::Config::default().with_tag(LOG_TAG).with_min_level(Level::Trace),
     );
 
-    let virt_manager = VirtManager::default();
+    runtime_dir::clean_up_stale();
+
+    let virt_manager = VirtManager::new(CrosvmRegistry::from_env());
     let virt_manager = BnVirtManager::new_binder(
         virt_manager,
         BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
     );
     add_service(BINDER_SERVICE_IDENTIFIER, virt_manager.as_binder()).unwrap();
-    info!("Registered Binder service, joining threadpool.");
+    let binder_threads = binder_threads(env::var(BINDER_THREADS_VAR).ok().as_deref());
+    ProcessState::set_thread_pool_max_thread_count(binder_threads);
+    ProcessState::start_thread_pool();
+    info!("Registered Binder service, joining threadpool of up to {} threads.", binder_threads);
     ProcessState::join_thread_pool();
 }
+
+/// Get the maximum number of Binder threads given the value of `BINDER_THREADS_VAR` if it is set,
+/// or the default if it isn't or is an invalid number.
+fn binder_threads(configured: Option<&str>) -> u32 {
+    match configured {
+        Some(threads) => parse_binder_threads(threads).unwrap_or_else(|e| {
+            warn!("Ignoring {}: {:#}", BINDER_THREADS_VAR, e);
+            DEFAULT_BINDER_THREADS
+        }),
+        None => DEFAULT_BINDER_THREADS,
+    }
+}
+
+/// Parse a maximum number of Binder threads, which must be positive and no more than
+/// `MAX_BINDER_THREADS`.
+fn parse_binder_threads(threads: &str) -> Result<u32, Error> {
+    let threads: u32 =
+        threads.trim().parse().with_context(|| format!("Invalid thread count {:?}", threads))?;
+    if threads == 0 || threads > MAX_BINDER_THREADS {
+        bail!("Thread count {} is not between 1 and {}", threads, MAX_BINDER_THREADS);
+    }
+    Ok(threads)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binder_threads_must_be_in_range() {
+        assert_eq!(parse_binder_threads("4").unwrap(), 4);
+        assert_eq!(parse_binder_threads(" 64\n").unwrap(), MAX_BINDER_THREADS);
+        assert!(parse_binder_threads("0").is_err());
+        assert!(parse_binder_threads("65").is_err());
+        assert!(parse_binder_threads("-1").is_err());
+        assert!(parse_binder_threads("lots").is_err());
+
+        assert_eq!(binder_threads(Some("8")), 8);
+        assert_eq!(binder_threads(Some("1000")), DEFAULT_BINDER_THREADS);
+        assert_eq!(binder_threads(None), DEFAULT_BINDER_THREADS);
+    }
+}
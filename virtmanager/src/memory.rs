@@ -0,0 +1,110 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Accounting of host memory, to keep some of it for workloads other than VMs.
+
+use anyhow::{bail, Context, Error};
+use std::env;
+use std::fs;
+use std::io;
+
+/// Information about the host's memory usage.
+const MEMINFO_FILE: &str = "/proc/meminfo";
+
+/// Environment variable with the amount of host memory in MiB which VMs may not use. If this isn't
+/// set then no memory is reserved.
+const RESERVE_VAR: &str = "VIRTMANAGER_HOST_MEMORY_RESERVE_MIB";
+
+/// How much host memory is kept for workloads other than VMs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MemoryReserve {
+    /// The total memory of the host, in MiB.
+    pub total_mib: u64,
+    /// The memory which VMs may not use, in MiB.
+    pub reserve_mib: u64,
+}
+
+impl MemoryReserve {
+    /// Get the reserve configured in the environment, or `None` if none is configured.
+    pub fn from_env() -> Result<Option<MemoryReserve>, Error> {
+        let reserve = match env::var(RESERVE_VAR) {
+            Ok(reserve) => reserve,
+            Err(_) => return Ok(None),
+        };
+        let reserve_mib =
+            reserve.parse().with_context(|| format!("Invalid {} {:?}", RESERVE_VAR, reserve))?;
+        Ok(Some(MemoryReserve { total_mib: read_meminfo_mib("MemTotal")?, reserve_mib }))
+    }
+
+    /// Check whether a VM with the given amount of guest memory may be started alongside VMs
+    /// already using the given amounts, without eating into the reserve.
+    pub fn admit(
+        &self,
+        running_mib: impl IntoIterator<Item = u64>,
+        requested_mib: u64,
+    ) -> Result<(), Error> {
+        let budget_mib = self.total_mib.saturating_sub(self.reserve_mib);
+        let running_mib: u64 = running_mib.into_iter().sum();
+        if running_mib + requested_mib > budget_mib {
+            bail!(
+                "VM needs {} MiB but only {} MiB of the {} MiB available to VMs is free, as {} MiB \
+                 of host memory is reserved",
+                requested_mib,
+                budget_mib.saturating_sub(running_mib),
+                budget_mib,
+                self.reserve_mib
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Read the given field of `/proc/meminfo`, in MiB.
+fn read_meminfo_mib(field: &str) -> io::Result<u64> {
+    parse_meminfo_mib(&fs::read_to_string(MEMINFO_FILE)?, field).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("No {} in {}", field, MEMINFO_FILE))
+    })
+}
+
+/// Parse the given field from the contents of `/proc/meminfo`, converting it from KiB to MiB.
+fn parse_meminfo_mib(contents: &str, field: &str) -> Option<u64> {
+    let line = contents.lines().find(|line| line.split(':').next() == Some(field))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib / 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admission_respects_reserve() {
+        let contents = "MemTotal:        8388608 kB\nMemFree:         1048576 kB\n\
+                        MemAvailable:    4194304 kB\n";
+        let total_mib = parse_meminfo_mib(contents, "MemTotal").unwrap();
+        assert_eq!(total_mib, 8192);
+        assert_eq!(parse_meminfo_mib(contents, "MemAvailable"), Some(4096));
+
+        let reserve = MemoryReserve { total_mib, reserve_mib: 2048 };
+        assert!(reserve.admit(vec![2048, 2048], 2048).is_ok());
+        assert!(reserve.admit(vec![2048, 2048], 2049).is_err());
+
+        let reserve = MemoryReserve { total_mib, reserve_mib: 4096 };
+        assert!(reserve.admit(vec![], 4096).is_ok());
+        assert!(reserve.admit(vec![2048], 4096).is_err());
+
+        let reserve = MemoryReserve { total_mib, reserve_mib: 16384 };
+        assert!(reserve.admit(vec![], 1).is_err());
+    }
+}
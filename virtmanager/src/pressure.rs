@@ -0,0 +1,183 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Monitoring of host memory pressure, to stop VMs before the host runs out of memory.
+
+use crate::crosvm::{StopReason, VmInstance};
+use log::{error, info, warn};
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The pressure stall information for memory on the host.
+const MEMORY_PRESSURE_FILE: &str = "/proc/pressure/memory";
+
+/// Environment variable which enables stopping VMs under critical memory pressure if set to `1`.
+const ENABLED_VAR: &str = "VIRTMANAGER_MEMORY_PRESSURE_STOP";
+
+/// Environment variable with the critical memory pressure threshold, as the percentage of the last
+/// 10 seconds in which all non-idle tasks on the host were stalled waiting for memory.
+const CRITICAL_THRESHOLD_VAR: &str = "VIRTMANAGER_MEMORY_PRESSURE_CRITICAL";
+
+/// The default critical memory pressure threshold.
+const DEFAULT_CRITICAL_THRESHOLD: f64 = 40.0;
+
+/// How often to check the host memory pressure.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait after stopping a VM before stopping another. The pressure is averaged over
+/// 10 seconds, so until then it still reflects the time before the last VM was stopped.
+const STOP_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// When to stop VMs because of host memory pressure.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PressurePolicy {
+    /// The memory pressure above which VMs are stopped, as a percentage of time in which all
+    /// non-idle tasks were stalled on memory.
+    pub critical_threshold: f64,
+}
+
+impl PressurePolicy {
+    /// Get the policy configured in the environment, or `None` if stopping VMs under memory
+    /// pressure isn't enabled.
+    pub fn from_env() -> Option<PressurePolicy> {
+        if env::var(ENABLED_VAR).ok()? != "1" {
+            return None;
+        }
+        let critical_threshold = env::var(CRITICAL_THRESHOLD_VAR)
+            .ok()
+            .and_then(|threshold| threshold.parse().ok())
+            .unwrap_or(DEFAULT_CRITICAL_THRESHOLD);
+        Some(PressurePolicy { critical_threshold })
+    }
+}
+
+/// Decides when to stop a VM, from successive samples of the host memory pressure.
+#[derive(Debug)]
+struct StopDecider {
+    policy: PressurePolicy,
+    /// When a VM was last stopped, if one has been.
+    last_stop: Option<Instant>,
+}
+
+impl StopDecider {
+    fn new(policy: PressurePolicy) -> StopDecider {
+        StopDecider { policy, last_stop: None }
+    }
+
+    /// Decide whether to stop a VM given the memory pressure sampled at the given time. Once a VM
+    /// is stopped no more are until `STOP_COOLDOWN` has passed, so that the effect of stopping it
+    /// shows up in the pressure.
+    fn should_stop(&mut self, pressure: f64, now: Instant) -> bool {
+        if pressure <= self.policy.critical_threshold {
+            return false;
+        }
+        if self.last_stop.map_or(false, |last_stop| now < last_stop + STOP_COOLDOWN) {
+            return false;
+        }
+        self.last_stop = Some(now);
+        true
+    }
+}
+
+/// Spawn a thread which periodically checks the host memory pressure, and while it is above the
+/// critical threshold stops the lowest-priority VM from those returned by `vms`, one at a time.
+pub fn spawn_monitor(
+    policy: PressurePolicy,
+    vms: impl Fn() -> Vec<Arc<VmInstance>> + Send + 'static,
+) {
+    info!("Stopping VMs when memory pressure exceeds {}%", policy.critical_threshold);
+    let mut decider = StopDecider::new(policy);
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        let pressure = match fs::read_to_string(MEMORY_PRESSURE_FILE) {
+            Ok(contents) => parse_full_avg10(&contents),
+            Err(e) => {
+                error!("Failed to read memory pressure, no longer monitoring it: {}", e);
+                return;
+            }
+        };
+        let pressure = match pressure {
+            Some(pressure) if decider.should_stop(pressure, Instant::now()) => pressure,
+            _ => continue,
+        };
+        let running = vms().into_iter().filter(|vm| vm.running());
+        if let Some(vm) = lowest_priority(running.map(|vm| (vm.config.priority, vm))) {
+            warn!(
+                "Memory pressure is {}%, stopping VM with CID {} which has priority {}",
+                pressure, vm.cid, vm.config.priority
+            );
+            vm.stop_for(StopReason::HostMemoryPressure);
+        }
+    });
+}
+
+/// Parse the `full` 10 second average from the contents of a pressure stall information file.
+fn parse_full_avg10(contents: &str) -> Option<f64> {
+    let full = contents.lines().find(|line| line.starts_with("full "))?;
+    let avg10 = full.split_whitespace().find_map(|field| field.strip_prefix("avg10="))?;
+    avg10.parse().ok()
+}
+
+/// Choose the item with the lowest priority. Where several have the same priority, the first is
+/// chosen.
+fn lowest_priority<T>(items: impl IntoIterator<Item = (i32, T)>) -> Option<T> {
+    let mut lowest: Option<(i32, T)> = None;
+    for (priority, item) in items {
+        if lowest.as_ref().map_or(true, |(lowest_priority, _)| priority < *lowest_priority) {
+            lowest = Some((priority, item));
+        }
+    }
+    lowest.map(|(_, item)| item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn critical_pressure_stops_lowest_priority_first() {
+        let contents = "some avg10=92.10 avg60=50.00 avg300=20.00 total=123456\n\
+                        full avg10=61.25 avg60=30.00 avg300=10.00 total=65432\n";
+        let pressure = parse_full_avg10(contents).unwrap();
+        assert!(pressure > DEFAULT_CRITICAL_THRESHOLD);
+
+        let mut vms = vec![(10, "cid 10"), (-5, "cid 11"), (0, "cid 12"), (-5, "cid 13")];
+        let mut stopped = vec![];
+        while let Some(vm) = lowest_priority(vms.iter().copied()) {
+            vms.retain(|&(_, other)| other != vm);
+            stopped.push(vm);
+        }
+        assert_eq!(stopped, ["cid 11", "cid 13", "cid 12", "cid 10"]);
+    }
+
+    #[test]
+    fn vms_are_stopped_no_faster_than_pressure_can_respond() {
+        let policy = PressurePolicy { critical_threshold: DEFAULT_CRITICAL_THRESHOLD };
+        let mut decider = StopDecider::new(policy);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(!decider.should_stop(10.0, at(0)));
+        assert!(decider.should_stop(61.25, at(5)));
+        // The average still includes the time before the VM was stopped.
+        assert!(!decider.should_stop(55.0, at(10)));
+        assert!(decider.should_stop(45.0, at(15)));
+        assert!(!decider.should_stop(20.0, at(25)));
+        // Once pressure has been low for a while, the next spike is acted on straight away.
+        assert!(decider.should_stop(50.0, at(30)));
+    }
+}
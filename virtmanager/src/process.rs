@@ -0,0 +1,428 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Functions for setting up the crosvm child process before it starts running crosvm.
+
+use anyhow::{bail, Error};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem::{size_of, MaybeUninit};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr::null;
+
+/// The directory in sysfs with an entry for each NUMA node of the host.
+const NUMA_NODE_DIR: &str = "/sys/devices/system/node";
+
+/// The `ioprio_set` target type for a single process.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// The `set_mempolicy` mode which restricts memory allocation to the given nodes.
+const MPOL_BIND: libc::c_int = 2;
+
+/// Check that this process is allowed to give its children the given real-time priority. This
+/// requires either running as root or having a high enough `RLIMIT_RTPRIO`.
+pub fn check_realtime_priority_allowed(priority: u32) -> Result<(), Error> {
+    // Safe because this doesn't modify any memory.
+    if unsafe { libc::geteuid() } == 0 {
+        return Ok(());
+    }
+    let limit = get_rlimit(libc::RLIMIT_RTPRIO)?;
+    if limit.rlim_cur < priority as libc::rlim_t {
+        bail!("Real-time priority {} exceeds RLIMIT_RTPRIO of {}", priority, limit.rlim_cur);
+    }
+    Ok(())
+}
+
+/// Run the child process, and so all of its threads, with the `SCHED_FIFO` real-time scheduling
+/// policy at the given priority.
+pub fn set_realtime_priority(command: &mut Command, priority: u32) {
+    let param = libc::sched_param { sched_priority: priority as libc::c_int };
+    // Safe because the closure only makes a syscall which is safe to call between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Run the child process with the given I/O priority, as passed to `ioprio_set`.
+pub fn set_io_priority(command: &mut Command, ioprio: libc::c_int) {
+    // Safe because the closure only makes a syscall which is safe to call between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Check that the given limit on open files is within this process's hard limit, so that it can be
+/// applied to its children.
+pub fn check_open_files_limit_allowed(limit: u64) -> Result<(), Error> {
+    let current = get_rlimit(libc::RLIMIT_NOFILE)?;
+    if limit as libc::rlim_t > current.rlim_max {
+        bail!("Open files limit {} exceeds hard RLIMIT_NOFILE of {}", limit, current.rlim_max);
+    }
+    Ok(())
+}
+
+/// Set the soft `RLIMIT_NOFILE` of the child process to the given limit, leaving its hard limit
+/// alone.
+pub fn set_open_files_limit(command: &mut Command, limit: u64) {
+    // Safe because the closure only makes syscalls which are safe to call between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            let mut rlimit = get_rlimit(libc::RLIMIT_NOFILE)?;
+            rlimit.rlim_cur = limit as libc::rlim_t;
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Let the child process inherit the given file, returning the path by which it can open it. The
+/// file is kept open for as long as the command is.
+pub fn pass_file(command: &mut Command, file: File) -> PathBuf {
+    let path = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()));
+    // Safe because the closure only makes a syscall which is safe to call between fork and exec,
+    // on a file descriptor which it keeps open.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::fcntl(file.as_raw_fd(), libc::F_SETFD, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    path
+}
+
+/// Check that the host has a NUMA node with the given index.
+pub fn check_numa_node_exists(node: u32) -> Result<(), Error> {
+    if !Path::new(NUMA_NODE_DIR).join(format!("node{}", node)).is_dir() {
+        bail!("Host has no NUMA node {}", node);
+    }
+    Ok(())
+}
+
+/// Bind all memory allocations of the child process to the given NUMA node.
+pub fn set_numa_node(command: &mut Command, node: u32) {
+    let bits_per_word = 8 * size_of::<libc::c_ulong>();
+    let mut node_mask = vec![0 as libc::c_ulong; node as usize / bits_per_word + 1];
+    node_mask[node as usize / bits_per_word] |= 1 << (node as usize % bits_per_word);
+    // The kernel interprets the mask length as one more than the number of bits it holds.
+    let max_node = (node_mask.len() * bits_per_word + 1) as libc::c_ulong;
+    // Safe because the closure only makes a syscall which is safe to call between fork and exec,
+    // passing it a pointer to a mask which lives as long as the closure and its correct length.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::syscall(libc::SYS_set_mempolicy, MPOL_BIND, node_mask.as_ptr(), max_node) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// A restricted view of the filesystem for the child process, in its own mount namespace, in which
+/// only some paths of the host are visible.
+#[derive(Debug)]
+pub struct MountIsolation {
+    /// The directory on which a tmpfs is mounted to become the child's root directory.
+    root: CString,
+    /// Directories to create under the new root as mount points or their parents, parents first.
+    dirs: Vec<CString>,
+    /// Files to create under the new root as mount points.
+    files: Vec<CString>,
+    /// Paths to bind mount, each along with where to mount it under the new root.
+    binds: Vec<(CString, CString)>,
+}
+
+impl MountIsolation {
+    /// Prepare a view of the filesystem rooted at the given directory of the host, in which only
+    /// the given absolute paths are visible. Each path is given along with whether it is a
+    /// directory. Paths within others are visible anyway, so aren't mounted separately.
+    pub fn new(root: &Path, paths: &[(PathBuf, bool)]) -> Result<MountIsolation, Error> {
+        let mut paths: Vec<&(PathBuf, bool)> = paths.iter().collect();
+        // Parents sort before their children.
+        paths.sort();
+        let mut isolation = MountIsolation {
+            root: path_to_cstring(root)?,
+            dirs: vec![],
+            files: vec![],
+            binds: vec![],
+        };
+        let mut visible: Vec<&Path> = vec![];
+        for (path, is_dir) in paths {
+            let relative = match path.strip_prefix("/") {
+                Ok(relative) if relative.as_os_str().is_empty() => bail!("Can't bind mount /"),
+                Ok(relative) => relative,
+                Err(_) => bail!("Path {:?} to make visible is not absolute", path),
+            };
+            if visible.iter().any(|visible| path.starts_with(visible)) {
+                continue;
+            }
+            let mut parents: Vec<&Path> = relative.ancestors().skip(1).collect();
+            parents.retain(|parent| !parent.as_os_str().is_empty());
+            for parent in parents.into_iter().rev() {
+                let parent = path_to_cstring(&root.join(parent))?;
+                if !isolation.dirs.contains(&parent) {
+                    isolation.dirs.push(parent);
+                }
+            }
+            let target = path_to_cstring(&root.join(relative))?;
+            if *is_dir {
+                isolation.dirs.push(target.clone());
+            } else {
+                isolation.files.push(target.clone());
+            }
+            isolation.binds.push((path_to_cstring(path)?, target));
+            visible.push(path);
+        }
+        Ok(isolation)
+    }
+
+    /// Run the child process with this view of the filesystem.
+    pub fn apply(self, command: &mut Command) {
+        // Safe because the closure only makes syscalls which are safe to call between fork and
+        // exec, passing them C strings which live as long as the closure, and checks their return
+        // values. Changing the root directory only affects the child.
+        unsafe {
+            command.pre_exec(move || self.enter());
+        }
+    }
+
+    /// Move the calling process into a new mount namespace with this view of the filesystem.
+    ///
+    /// # Safety
+    ///
+    /// This must only be called in a child process between fork and exec, as it changes the root
+    /// directory of the process.
+    unsafe fn enter(&self) -> io::Result<()> {
+        check(libc::unshare(libc::CLONE_NEWNS))?;
+        // Stop mounts in the new namespace from propagating back to the host's.
+        let root_dir = b"/\0".as_ptr() as *const libc::c_char;
+        check(libc::mount(null(), root_dir, null(), libc::MS_REC | libc::MS_PRIVATE, null()))?;
+        mkdir(&self.root)?;
+        let tmpfs = b"tmpfs\0".as_ptr() as *const libc::c_char;
+        let flags = libc::MS_NOSUID | libc::MS_NODEV;
+        check(libc::mount(tmpfs, self.root.as_ptr(), tmpfs, flags, null()))?;
+        for dir in &self.dirs {
+            mkdir(dir)?;
+        }
+        for file in &self.files {
+            let fd =
+                libc::open(file.as_ptr(), libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC, 0o600);
+            check(fd)?;
+            libc::close(fd);
+        }
+        for (source, target) in &self.binds {
+            let flags = libc::MS_BIND | libc::MS_REC;
+            check(libc::mount(source.as_ptr(), target.as_ptr(), null(), flags, null()))?;
+        }
+        check(libc::chroot(self.root.as_ptr()))?;
+        check(libc::chdir(root_dir))
+    }
+}
+
+/// Create the given directory, if it doesn't already exist. This doesn't allocate, so may be called
+/// between fork and exec.
+fn mkdir(path: &CString) -> io::Result<()> {
+    // Safe because we pass a valid C string and check the return value.
+    if unsafe { libc::mkdir(path.as_ptr(), 0o755) } < 0 {
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EEXIST) {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Convert the return value of a syscall to an error if it is negative.
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Convert the given path to a C string, failing if it contains a NUL byte.
+fn path_to_cstring(path: &Path) -> Result<CString, Error> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Get the current limits for the given resource for this process.
+fn get_rlimit(resource: libc::c_int) -> io::Result<libc::rlimit> {
+    let mut limit = MaybeUninit::<libc::rlimit>::uninit();
+    // Safe because we pass a valid pointer to an rlimit, and check the return value.
+    if unsafe { libc::getrlimit(resource as _, limit.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because getrlimit succeeded, so has initialised the value.
+    Ok(unsafe { limit.assume_init() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_priority_is_set_on_child() {
+        // Idle class, which an unprivileged process may use.
+        let ioprio = 3 << 13;
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        set_io_priority(&mut command, ioprio);
+        let mut child = command.spawn().unwrap();
+        // Safe because this doesn't modify any memory.
+        let actual = unsafe {
+            libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, child.id() as libc::c_int)
+        };
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(actual, libc::c_long::from(ioprio));
+    }
+
+    #[test]
+    fn realtime_priority_is_set_on_child() {
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        set_realtime_priority(&mut command, 10);
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            // Real-time scheduling needs privileges which the test may not have.
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => return,
+            Err(e) => panic!("Failed to run command with real-time priority: {}", e),
+        };
+        let pid = child.id() as libc::pid_t;
+        let mut param = libc::sched_param { sched_priority: 0 };
+        // Safe because these only write to the given parameter, which outlives the call.
+        let (policy, ret) =
+            unsafe { (libc::sched_getscheduler(pid), libc::sched_getparam(pid, &mut param)) };
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(policy, libc::SCHED_FIFO);
+        assert_eq!(ret, 0);
+        assert_eq!(param.sched_priority, 10);
+    }
+
+    #[test]
+    fn isolated_child_only_sees_permitted_mounts() {
+        let base = std::env::temp_dir().join("virtmanager_mount_isolation_test");
+        let _ = std::fs::remove_dir_all(&base);
+        let shared = base.join("shared");
+        std::fs::create_dir_all(&shared).unwrap();
+        std::fs::write(shared.join("image"), "image").unwrap();
+        // The shell and its libraries must be visible for it to run.
+        let mut paths: Vec<(PathBuf, bool)> = ["/bin", "/lib", "/lib64", "/usr"]
+            .iter()
+            .map(PathBuf::from)
+            .filter(|path| path.exists())
+            .map(|path| (path, true))
+            .collect();
+        let mut expected: Vec<String> = paths
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect();
+        paths.push((shared.join("image"), false));
+        paths.push((shared.clone(), true));
+        expected.push(base.components().nth(1).unwrap().as_os_str().to_str().unwrap().to_owned());
+        expected.sort();
+
+        let isolation = MountIsolation::new(&base.join("root"), &paths).unwrap();
+        // The image is visible anyway as it is within a shared directory.
+        assert_eq!(isolation.binds.len(), paths.len() - 1);
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!("ls /; cat {}/image", shared.display()));
+        isolation.apply(&mut command);
+        let output = match command.output() {
+            Ok(output) => output,
+            // Creating a mount namespace needs privileges which the test may not have.
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => return,
+            Err(e) => panic!("Failed to run isolated command: {}", e),
+        };
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let mut lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines.pop(), Some("image"));
+        lines.sort();
+        assert_eq!(lines, expected);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn open_files_limit_is_applied_to_child() {
+        check_open_files_limit_allowed(100).unwrap();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("ulimit -n");
+        set_open_files_limit(&mut command, 100);
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "100");
+    }
+
+    #[test]
+    fn numa_node_binding_is_applied_to_child() {
+        if check_numa_node_exists(0).is_err() {
+            // The host kernel doesn't support NUMA.
+            return;
+        }
+        let mut command = Command::new("cat");
+        command.arg("/proc/self/numa_maps");
+        set_numa_node(&mut command, 0);
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert!(String::from_utf8(output.stdout).unwrap().contains(" bind:0 "));
+    }
+
+    #[test]
+    fn passed_file_is_opened_by_child() {
+        let path = std::env::temp_dir().join("virtmanager_pass_file_test");
+        std::fs::write(&path, "passed\n").unwrap();
+        let mut command = Command::new("cat");
+        let passed = pass_file(&mut command, File::open(&path).unwrap());
+        command.arg(&passed);
+        // The child gets the file which was opened, even once it is gone from its path.
+        std::fs::remove_file(&path).unwrap();
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"passed\n");
+    }
+
+    #[test]
+    fn missing_numa_node_is_rejected() {
+        assert!(check_numa_node_exists(9999).is_err());
+    }
+
+    #[test]
+    fn open_files_limit_above_hard_limit_is_rejected() {
+        let hard_limit = get_rlimit(libc::RLIMIT_NOFILE).unwrap().rlim_max;
+        if hard_limit < libc::RLIM_INFINITY {
+            assert!(check_open_files_limit_allowed(hard_limit as u64 + 1).is_err());
+        }
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Protection of guest memory from the host by the hypervisor, for platforms running pKVM.
+
+use anyhow::{bail, Error};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// The host kernel's command line, which says whether KVM is running in protected mode.
+const HOST_CMDLINE: &str = "/proc/cmdline";
+
+/// The host kernel parameter which runs KVM as pKVM, which can run protected VMs.
+const PROTECTED_MODE_PARAM: &str = "kvm-arm.mode=protected";
+
+/// How the hypervisor protects a VM's memory from the host.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MemProtectionConfig {
+    /// Whether to run the VM as a protected VM, whose memory the host can't access once it has been
+    /// given to the guest. This needs the host to be running pKVM.
+    #[serde(default)]
+    pub protected: bool,
+    /// Whether to boot the protected VM straight into its kernel, rather than through the pKVM
+    /// firmware which measures the images it boots. This is only for testing, as the guest can
+    /// then make no claims about what it booted.
+    #[serde(default)]
+    pub without_firmware: bool,
+}
+
+impl MemProtectionConfig {
+    /// Check that the options make sense together.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.without_firmware && !self.protected {
+            bail!("Running without protected VM firmware is only possible for protected VMs.");
+        }
+        Ok(())
+    }
+
+    /// Check that the host supports the options, returning which option it doesn't if any.
+    pub fn check_host(&self, host: ProtectionSupport) -> Result<(), ProtectionUnsupported> {
+        if self.protected && !host.protected_vm {
+            return Err(ProtectionUnsupported { option: "protected" });
+        }
+        Ok(())
+    }
+
+    /// Get the crosvm flags for the options.
+    pub fn crosvm_args(&self) -> Vec<&'static str> {
+        match (self.protected, self.without_firmware) {
+            (false, _) => vec![],
+            (true, false) => vec!["--protected-vm"],
+            (true, true) => vec!["--protected-vm-without-firmware"],
+        }
+    }
+}
+
+/// Which memory protection the host's hypervisor supports.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ProtectionSupport {
+    /// Whether the host is running pKVM, so can run protected VMs.
+    pub protected_vm: bool,
+}
+
+impl ProtectionSupport {
+    /// Check which memory protection the host supports. This can't change without rebooting the
+    /// host, so only needs to be checked once.
+    pub fn probe() -> ProtectionSupport {
+        let cmdline = fs::read_to_string(HOST_CMDLINE).unwrap_or_default();
+        ProtectionSupport {
+            protected_vm: cmdline.split_whitespace().any(|param| param == PROTECTED_MODE_PARAM),
+        }
+    }
+}
+
+/// Error returned when a VM's config asks for memory protection which the host doesn't support.
+#[derive(Debug, thiserror::Error)]
+#[error("The host doesn't support memory protection option {option:?}.")]
+pub struct ProtectionUnsupported {
+    /// The unsupported option in the config, such as `protected`.
+    pub option: &'static str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PKVM: ProtectionSupport = ProtectionSupport { protected_vm: true };
+
+    #[test]
+    fn protected_vm_needs_pkvm() {
+        let config = MemProtectionConfig { protected: true, without_firmware: false };
+        config.validate().unwrap();
+        config.check_host(PKVM).unwrap();
+        assert_eq!(config.crosvm_args(), ["--protected-vm"]);
+
+        let error = config.check_host(ProtectionSupport::default()).unwrap_err();
+        assert_eq!(error.option, "protected");
+    }
+
+    #[test]
+    fn skipping_firmware_needs_protected_vm() {
+        let config = MemProtectionConfig { protected: true, without_firmware: true };
+        config.validate().unwrap();
+        assert_eq!(config.crosvm_args(), ["--protected-vm-without-firmware"]);
+
+        let unprotected = MemProtectionConfig { protected: false, ..config };
+        assert!(unprotected.validate().is_err());
+        unprotected.check_host(ProtectionSupport::default()).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limits on how many VMs each UID may run at once, so that one client can't take all the capacity
+//! of the host.
+
+use anyhow::{bail, Context, Error};
+use std::env;
+
+/// Environment variable with the maximum number of VMs which each UID may run at once.
+const PER_UID_MAX_VMS_VAR: &str = "VIRTMANAGER_PER_UID_MAX_VMS";
+
+/// UIDs which may run any number of VMs: root and system.
+const EXEMPT_UIDS: [u32; 2] = [0, 1000];
+
+/// The maximum number of VMs which each UID may run at once.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct UidQuota {
+    /// The maximum number of running VMs per UID, or `None` if there is no limit.
+    pub max_vms: Option<usize>,
+}
+
+impl UidQuota {
+    /// Get the quota configured in the environment. There is no limit if none is configured.
+    pub fn from_env() -> Result<UidQuota, Error> {
+        let max_vms = match env::var(PER_UID_MAX_VMS_VAR) {
+            Ok(max_vms) => Some(
+                max_vms
+                    .parse()
+                    .with_context(|| format!("Invalid {} {:?}", PER_UID_MAX_VMS_VAR, max_vms))?,
+            ),
+            Err(_) => None,
+        };
+        Ok(UidQuota { max_vms })
+    }
+
+    /// Check whether the given UID may start another VM, given the UIDs which own the VMs already
+    /// running.
+    pub fn admit(
+        &self,
+        uid: u32,
+        running_owners: impl IntoIterator<Item = u32>,
+    ) -> Result<(), Error> {
+        let max_vms = match self.max_vms {
+            Some(max_vms) if !EXEMPT_UIDS.contains(&uid) => max_vms,
+            _ => return Ok(()),
+        };
+        let running = running_owners.into_iter().filter(|&owner| owner == uid).count();
+        if running >= max_vms {
+            bail!("UID {} already has {} VMs running, which is its quota", uid, running);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_is_per_uid() {
+        let quota = UidQuota { max_vms: Some(2) };
+        let running = vec![10001, 10002, 10001];
+        assert!(quota.admit(10001, running.clone()).is_err());
+        assert!(quota.admit(10002, running.clone()).is_ok());
+        assert!(quota.admit(10003, running.clone()).is_ok());
+
+        // Privileged UIDs aren't limited.
+        assert!(quota.admit(1000, vec![1000, 1000, 1000]).is_ok());
+        assert!(quota.admit(0, vec![0, 0]).is_ok());
+
+        assert!(UidQuota::default().admit(10001, running).is_ok());
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retrying crosvm launches which fail because the vhost-vsock device is momentarily unavailable,
+//! such as while the host is booting.
+
+use anyhow::{Context, Error};
+use std::env;
+use std::io::BufRead;
+use std::time::Duration;
+
+/// Environment variable with how many times to retry launching crosvm if it fails because the
+/// vhost-vsock device is unavailable.
+const ATTEMPTS_VAR: &str = "VIRTMANAGER_VSOCK_RETRY_ATTEMPTS";
+
+/// Environment variable with how long to wait before the first retry, in milliseconds.
+const DELAY_VAR: &str = "VIRTMANAGER_VSOCK_RETRY_DELAY_MS";
+
+/// How long to wait before the first retry if none is configured.
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+/// The longest to wait between retries, however many there have been.
+const MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// Descriptions of errors from opening the vhost-vsock device which may go away if tried again.
+const TRANSIENT_ERRORS: [&str; 2] = ["Device or resource busy", "Resource temporarily unavailable"];
+
+/// Error returned when crosvm still couldn't open the vhost-vsock device after all retries.
+#[derive(Debug, thiserror::Error)]
+#[error("vhost-vsock device was still unavailable after {attempts} attempts to launch crosvm.")]
+pub struct VsockUnavailable {
+    /// How many times crosvm was launched.
+    pub attempts: u32,
+}
+
+/// How to retry launching crosvm if the vhost-vsock device is unavailable. The delay doubles
+/// after each retry, up to `MAX_DELAY`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VsockRetry {
+    /// How many times to retry. If this is zero then crosvm is launched only once.
+    pub attempts: u32,
+    /// How long to wait before the first retry.
+    pub delay: Duration,
+}
+
+impl Default for VsockRetry {
+    /// Don't retry.
+    fn default() -> VsockRetry {
+        VsockRetry { attempts: 0, delay: DEFAULT_DELAY }
+    }
+}
+
+impl VsockRetry {
+    /// Get the retry policy configured in the environment. There are no retries if none is
+    /// configured.
+    pub fn from_env() -> Result<VsockRetry, Error> {
+        let mut retry = VsockRetry::default();
+        if let Ok(attempts) = env::var(ATTEMPTS_VAR) {
+            retry.attempts = attempts
+                .parse()
+                .with_context(|| format!("Invalid {} {:?}", ATTEMPTS_VAR, attempts))?;
+        }
+        if let Ok(delay) = env::var(DELAY_VAR) {
+            let millis =
+                delay.parse().with_context(|| format!("Invalid {} {:?}", DELAY_VAR, delay))?;
+            retry.delay = Duration::from_millis(millis);
+        }
+        Ok(retry)
+    }
+
+    /// Get how long to wait before the given retry, counting from zero.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry).unwrap_or(u32::MAX);
+        self.delay.checked_mul(factor).map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY))
+    }
+}
+
+/// Check whether the given crosvm log shows that it failed because the vhost-vsock device was
+/// momentarily unavailable.
+pub fn is_transient_vsock_error(log: impl BufRead) -> bool {
+    log.lines().filter_map(Result::ok).any(|line| {
+        line.contains("vsock") && TRANSIENT_ERRORS.iter().any(|error| line.contains(error))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_up_to_maximum() {
+        let retry = VsockRetry { attempts: 10, delay: Duration::from_millis(300) };
+        let delays: Vec<Duration> = (0..5).map(|retry_index| retry.delay(retry_index)).collect();
+        assert_eq!(
+            delays,
+            [300, 600, 1200, 2000, 2000]
+                .iter()
+                .map(|&ms| Duration::from_millis(ms))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(retry.delay(40), MAX_DELAY);
+    }
+
+    #[test]
+    fn only_transient_vsock_errors_are_retried() {
+        let busy =
+            "[ERROR] failed to open vhost-vsock device: Device or resource busy (os error 16)";
+        assert!(is_transient_vsock_error(busy.as_bytes()));
+        let missing = "[ERROR] failed to open vhost-vsock device: No such file or directory";
+        assert!(!is_transient_vsock_error(missing.as_bytes()));
+        let other = "[ERROR] failed to open disk: Device or resource busy (os error 16)";
+        assert!(!is_transient_vsock_error(other.as_bytes()));
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Management of the per-VM runtime directories, and of the logs retained from them after VMs
+//! stop.
+
+use crate::Cid;
+use anyhow::{Context, Error};
+use log::{error, info};
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The directory under which each running VM gets its own runtime directory, for logs and other
+/// files.
+pub const RUNTIME_DIR_BASE: &str = "/data/misc/virtmanager";
+
+/// The directory under which the runtime directories of stopped VMs are retained for post-mortem
+/// debugging.
+const RETAINED_DIR_BASE: &str = "/data/misc/virtmanager_logs";
+
+/// The directory within each runtime directory for scratch files, which is deleted rather than
+/// retained when the VM stops.
+pub const SCRATCH_DIR: &str = "scratch";
+
+/// The file in each runtime directory to which the initrd is copied with the VM's bootconfig
+/// appended, if it has any or is given an entropy seed. This is deleted rather than retained when
+/// the VM stops, as the seed must not outlive the VM.
+pub const BOOTCONFIG_INITRD_FILE: &str = "initrd.bootconfig";
+
+/// Environment variable with the maximum number of stopped VMs to retain logs for.
+const RETENTION_COUNT_VAR: &str = "VIRTMANAGER_LOG_RETENTION_COUNT";
+
+/// Environment variable with the maximum age in seconds of retained logs, if any.
+const RETENTION_AGE_VAR: &str = "VIRTMANAGER_LOG_RETENTION_SECS";
+
+/// The default number of stopped VMs to retain logs for.
+const DEFAULT_RETENTION_COUNT: usize = 10;
+
+/// How long to keep the logs of VMs after they have stopped.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogRetention {
+    /// The maximum number of stopped VMs to keep logs for. Logs of older VMs are deleted.
+    pub max_count: usize,
+    /// The maximum time to keep logs for after they were last modified, if any.
+    pub max_age: Option<Duration>,
+}
+
+impl LogRetention {
+    /// Get the log retention policy configured in the environment, or the default.
+    pub fn from_env() -> LogRetention {
+        let max_count = env::var(RETENTION_COUNT_VAR)
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(DEFAULT_RETENTION_COUNT);
+        let max_age = env::var(RETENTION_AGE_VAR)
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs);
+        LogRetention { max_count, max_age }
+    }
+}
+
+/// Get the directory under which runtime directories are created.
+#[cfg(not(test))]
+fn runtime_base() -> PathBuf {
+    PathBuf::from(RUNTIME_DIR_BASE)
+}
+
+/// Get the directory under which the runtime directories of stopped VMs are retained.
+#[cfg(not(test))]
+fn retained_base() -> PathBuf {
+    PathBuf::from(RETAINED_DIR_BASE)
+}
+
+/// Tests can't write to `RUNTIME_DIR_BASE`, so use a directory of their own to create runtime
+/// directories in.
+#[cfg(test)]
+fn runtime_base() -> PathBuf {
+    test_base("runtime")
+}
+
+/// Tests can't write to `RETAINED_DIR_BASE`, so use a directory of their own to retain runtime
+/// directories in.
+#[cfg(test)]
+fn retained_base() -> PathBuf {
+    test_base("retained")
+}
+
+/// Get a directory for tests with the given name and unique to this process, creating it if need
+/// be.
+#[cfg(test)]
+fn test_base(name: &str) -> PathBuf {
+    let base = env::temp_dir().join(format!("virtmanager_{}_{}", name, std::process::id()));
+    fs::create_dir_all(&base).unwrap();
+    base
+}
+
+/// Create a new runtime directory for a VM with the given CID, accessible only to the Virt Manager.
+pub fn create(cid: Cid) -> Result<PathBuf, Error> {
+    create_in(&runtime_base(), cid)
+}
+
+/// Create a new runtime directory for a VM with the given CID in the given base directory, which
+/// must already exist. The runtime directory itself must not, so that it can't have been created,
+/// or replaced by a link, by anyone else.
+fn create_in(base: &Path, cid: Cid) -> Result<PathBuf, Error> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    // Start with the timestamp so that directories sort in the order they were created.
+    let runtime_dir = base.join(format!("{}_vm{}", timestamp, cid));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&runtime_dir)
+        .with_context(|| format!("Failed to create runtime directory {:?}", runtime_dir))?;
+    Ok(runtime_dir)
+}
+
+/// Create the scratch directory within the given runtime directory. If a size is given then a
+/// tmpfs of that many MiB is mounted on it, so that the VM can't use more than that. Mounting needs
+/// `CAP_SYS_ADMIN`, without which this fails.
+pub fn create_scratch(runtime_dir: &Path, size_mib: Option<u64>) -> Result<(), Error> {
+    let scratch = runtime_dir.join(SCRATCH_DIR);
+    fs::DirBuilder::new().mode(0o700).create(&scratch)?;
+    if let Some(size_mib) = size_mib {
+        mount_tmpfs(&scratch, size_mib)
+            .with_context(|| format!("Failed to mount {} MiB tmpfs on {:?}", size_mib, scratch))?;
+    }
+    Ok(())
+}
+
+/// Delete the scratch directory within the given runtime directory, if there is one, unmounting
+/// it first if it is a tmpfs.
+fn remove_scratch(runtime_dir: &Path) -> Result<(), Error> {
+    let scratch = runtime_dir.join(SCRATCH_DIR);
+    let metadata = match fs::metadata(&scratch) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    // A mount point is on a different device to the directory containing it.
+    if metadata.dev() != fs::metadata(runtime_dir)?.dev() {
+        let path = CString::new(scratch.as_os_str().as_bytes())?;
+        // Safe because we pass a valid C string and check the return value.
+        if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Failed to unmount {:?}", scratch));
+        }
+    }
+    fs::remove_dir_all(&scratch)?;
+    Ok(())
+}
+
+/// Delete the VM's copy of its initrd from the given runtime directory, if there is one.
+fn remove_bootconfig_initrd(runtime_dir: &Path) -> Result<(), Error> {
+    let initrd = runtime_dir.join(BOOTCONFIG_INITRD_FILE);
+    match fs::remove_file(&initrd) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to delete {:?}", initrd))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Mount a tmpfs of the given size in MiB on the given directory.
+fn mount_tmpfs(dir: &Path, size_mib: u64) -> Result<(), Error> {
+    let source = CString::new("tmpfs")?;
+    let target = CString::new(dir.as_os_str().as_bytes())?;
+    let options = CString::new(format!("size={}m,mode=0700", size_mib))?;
+    // Safe because we pass valid C strings and check the return value.
+    let ret = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            source.as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            options.as_ptr() as *const libc::c_void,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Move the runtime directory of a VM which has stopped to where logs are retained, then delete
+/// any retained logs which are beyond the retention policy. The scratch directory is deleted
+/// rather than retained, though failing to delete it doesn't stop the logs being retained. The
+/// copy of the initrd is deleted too, and if that fails then nothing is retained.
+pub fn retire(runtime_dir: &Path) -> Result<(), Error> {
+    let retained_base = retained_base();
+    if let Err(e) = remove_scratch(runtime_dir) {
+        error!("Failed to remove scratch directory of {:?}: {:?}", runtime_dir, e);
+    }
+    remove_bootconfig_initrd(runtime_dir)?;
+    retain(runtime_dir, &retained_base)?;
+    prune(&retained_base, &LogRetention::from_env())
+}
+
+/// Retain the logs of any runtime directories left behind by a previous instance of the Virt
+/// Manager, which must have died without cleaning up, and then apply the retention policy. This
+/// should be called on startup before any VMs are started.
+pub fn clean_up_stale() {
+    let retained_base = retained_base();
+    retain_stale(&runtime_base(), &retained_base);
+    if let Err(e) = prune(&retained_base, &LogRetention::from_env()) {
+        error!("Failed to apply log retention policy: {:?}", e);
+    }
+}
+
+/// Move all runtime directories in the given base directory into the given retained logs
+/// directory, deleting their scratch directories and copies of initrds.
+fn retain_stale(runtime_base: &Path, retained_base: &Path) {
+    match fs::read_dir(runtime_base) {
+        Ok(entries) => {
+            for entry in entries.flatten() {
+                info!("Retaining stale runtime directory {:?}", entry.path());
+                if let Err(e) = remove_scratch(&entry.path()) {
+                    error!(
+                        "Failed to delete stale scratch directory in {:?}: {:?}",
+                        entry.path(),
+                        e
+                    );
+                }
+                if let Err(e) = remove_bootconfig_initrd(&entry.path()) {
+                    error!("Not retaining stale runtime directory {:?}: {:?}", entry.path(), e);
+                    continue;
+                }
+                if let Err(e) = retain(&entry.path(), retained_base) {
+                    error!("Failed to retain stale runtime directory {:?}: {:?}", entry.path(), e);
+                }
+            }
+        }
+        Err(e) => info!("No stale runtime directories to clean up: {}", e),
+    }
+}
+
+/// Move the given runtime directory into the given retained logs directory.
+fn retain(runtime_dir: &Path, retained_base: &Path) -> Result<(), Error> {
+    fs::create_dir_all(retained_base)?;
+    let name = runtime_dir.file_name().unwrap_or_default();
+    fs::rename(runtime_dir, retained_base.join(name))?;
+    Ok(())
+}
+
+/// Delete retained logs beyond the given retention policy, oldest first.
+fn prune(retained_base: &Path, retention: &LogRetention) -> Result<(), Error> {
+    let mut retained: Vec<PathBuf> =
+        fs::read_dir(retained_base)?.filter_map(|entry| Some(entry.ok()?.path())).collect();
+    // Names start with the creation timestamp, so this sorts oldest first.
+    retained.sort();
+    let excess = retained.len().saturating_sub(retention.max_count);
+    for (index, dir) in retained.iter().enumerate() {
+        if index < excess || is_older_than(dir, retention.max_age) {
+            info!("Deleting retained logs {:?}", dir);
+            fs::remove_dir_all(dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// Return whether the given file was last modified longer ago than the given age.
+fn is_older_than(path: &Path, age: Option<Duration>) -> bool {
+    let modified = match (age, fs::metadata(path).and_then(|metadata| metadata.modified())) {
+        (Some(_), Ok(modified)) => modified,
+        _ => return false,
+    };
+    match modified.elapsed() {
+        Ok(elapsed) => Some(elapsed) > age,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_deletes_oldest_beyond_count() {
+        let base = env::temp_dir().join("virtmanager_retention_test");
+        let _ = fs::remove_dir_all(&base);
+        for name in &["1000_vm10", "2000_vm11", "3000_vm10"] {
+            fs::create_dir_all(base.join(name)).unwrap();
+            fs::write(base.join(name).join("console.log"), "log").unwrap();
+        }
+
+        prune(&base, &LogRetention { max_count: 2, max_age: None }).unwrap();
+
+        assert!(!base.join("1000_vm10").exists());
+        assert!(base.join("2000_vm11").join("console.log").exists());
+        assert!(base.join("3000_vm10").join("console.log").exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn runtime_dir_is_private_and_retained() {
+        let runtime_dir = create(10).unwrap();
+        assert!(runtime_dir.starts_with(runtime_base()));
+        assert_eq!(fs::metadata(&runtime_dir).unwrap().mode() & 0o777, 0o700);
+        create_scratch(&runtime_dir, None).unwrap();
+        fs::write(runtime_dir.join("console.log"), "log").unwrap();
+        fs::write(runtime_dir.join(BOOTCONFIG_INITRD_FILE), "initrd with seed").unwrap();
+
+        retire(&runtime_dir).unwrap();
+        assert!(!runtime_dir.exists());
+        let retained = retained_base().join(runtime_dir.file_name().unwrap());
+        assert!(retained.join("console.log").exists());
+        assert!(!retained.join(SCRATCH_DIR).exists());
+        assert!(!retained.join(BOOTCONFIG_INITRD_FILE).exists());
+
+        // Missing parent directories aren't created, as they wouldn't be private.
+        assert!(create_in(&runtime_base().join("missing"), 10).is_err());
+    }
+
+    #[test]
+    fn stale_scratch_is_swept_on_startup() {
+        let base = env::temp_dir().join("virtmanager_scratch_test");
+        let _ = fs::remove_dir_all(&base);
+        let (runtime_base, retained_base) = (base.join("runtime"), base.join("retained"));
+        let runtime_dir = runtime_base.join("1000_vm10");
+        fs::create_dir_all(&runtime_dir).unwrap();
+        fs::write(runtime_dir.join("console.log"), "log").unwrap();
+
+        create_scratch(&runtime_dir, None).unwrap();
+        let scratch = runtime_dir.join(SCRATCH_DIR);
+        assert!(scratch.is_dir());
+        fs::write(scratch.join("temp"), "scratch file").unwrap();
+        fs::write(runtime_dir.join(BOOTCONFIG_INITRD_FILE), "initrd with seed").unwrap();
+
+        // The service dies without retiring the runtime directory, then starts again.
+        retain_stale(&runtime_base, &retained_base);
+
+        assert!(!runtime_dir.exists());
+        assert!(retained_base.join("1000_vm10").join("console.log").exists());
+        assert!(!retained_base.join("1000_vm10").join(SCRATCH_DIR).exists());
+        assert!(!retained_base.join("1000_vm10").join(BOOTCONFIG_INITRD_FILE).exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn logs_are_retained_even_if_scratch_cant_be_removed() {
+        let runtime_dir = create(11).unwrap();
+        fs::write(runtime_dir.join("console.log"), "log").unwrap();
+        // A file in place of the scratch directory can't be removed as one.
+        fs::write(runtime_dir.join(SCRATCH_DIR), "not a directory").unwrap();
+
+        retire(&runtime_dir).unwrap();
+        assert!(!runtime_dir.exists());
+        let retained = retained_base().join(runtime_dir.file_name().unwrap());
+        assert!(retained.join("console.log").exists());
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ordered release of the resources owned by a VM when it stops.
+
+use anyhow::Error;
+use log::error;
+use std::fmt::{self, Debug, Formatter};
+
+/// The stage of teardown at which a resource is released. Stages run in the order they are
+/// declared here, so that nothing is removed while crosvm may still be using it.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum TeardownStage {
+    /// Stopping and reaping the crosvm process itself.
+    Crosvm,
+    /// Host network devices such as TAP interfaces.
+    Network,
+    /// Files and directories on the host, such as swap files, overlays and the runtime directory.
+    Files,
+}
+
+type CleanupStep = Box<dyn FnOnce() -> Result<(), Error> + Send>;
+
+/// A set of cleanup steps to be run when a VM stops. Steps are run in order of their stage, and
+/// within a stage in the order they were added. A failing step is logged but doesn't prevent the
+/// remaining steps from running.
+#[derive(Default)]
+pub struct Teardown {
+    steps: Vec<(TeardownStage, String, CleanupStep)>,
+}
+
+impl Teardown {
+    /// Add a step to be run at the given stage of teardown.
+    pub fn add(
+        &mut self,
+        stage: TeardownStage,
+        name: impl Into<String>,
+        step: impl FnOnce() -> Result<(), Error> + Send + 'static,
+    ) {
+        self.steps.push((stage, name.into(), Box::new(step)));
+    }
+
+    /// Run all the steps, returning the names of those which failed along with their errors.
+    pub fn run(mut self) -> Vec<(String, Error)> {
+        // `sort_by_key` is stable, so steps within a stage keep the order they were added in.
+        self.steps.sort_by_key(|(stage, _, _)| *stage);
+        let mut failures = vec![];
+        for (stage, name, step) in self.steps {
+            if let Err(e) = step() {
+                error!("Error during {:?} teardown step {}: {:?}", stage, name, e);
+                failures.push((name, e));
+            }
+        }
+        failures
+    }
+}
+
+impl Debug for Teardown {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_list().entries(self.steps.iter().map(|(stage, name, _)| (stage, name))).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn steps_run_in_stage_order_despite_failure() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut teardown = Teardown::default();
+        for &(stage, name) in &[
+            (TeardownStage::Files, "runtime dir"),
+            (TeardownStage::Network, "tap"),
+            (TeardownStage::Crosvm, "crosvm"),
+            (TeardownStage::Files, "swap file"),
+        ] {
+            let log = log.clone();
+            teardown.add(stage, name, move || {
+                log.lock().unwrap().push(name);
+                if name == "tap" {
+                    Err(anyhow!("injected failure"))
+                } else {
+                    Ok(())
+                }
+            });
+        }
+
+        let failures = teardown.run();
+
+        assert_eq!(*log.lock().unwrap(), ["crosvm", "tap", "runtime dir", "swap file"]);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, "tap");
+    }
+}
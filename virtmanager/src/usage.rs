@@ -0,0 +1,200 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Accounting of the resources used by a VM's crosvm process over its lifetime.
+
+use crate::crosvm::StopReason;
+use std::fs;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+
+/// A summary of the resources used by a VM, reported when it stops.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UsageSummary {
+    /// How long the VM was running for.
+    pub uptime: Duration,
+    /// The highest resident set size of the crosvm process seen, in KiB.
+    pub peak_rss_kib: u64,
+    /// The total CPU time used by the crosvm process, in both user and kernel mode, as of the last
+    /// sample before it exited.
+    pub cpu_time: Duration,
+    /// Why the VM stopped.
+    pub stop_reason: StopReason,
+    /// A human-readable description of how crosvm exited.
+    pub exit_reason: String,
+}
+
+/// The resource usage of a running VM at one point in time.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UsageSample {
+    /// How long the VM has been running for.
+    pub uptime: Duration,
+    /// The current resident set size of the crosvm process, in KiB.
+    pub rss_kib: u64,
+    /// The total CPU time used by the crosvm process so far, in both user and kernel mode.
+    pub cpu_time: Duration,
+}
+
+/// Periodically samples the resource usage of a process, keeping track of the peak values.
+#[derive(Debug)]
+pub struct UsageTracker {
+    pid: u32,
+    started: Instant,
+    peak_rss_kib: u64,
+    cpu_time: Duration,
+}
+
+impl UsageTracker {
+    /// Start tracking the process with the given PID, which has just started.
+    pub fn new(pid: u32) -> UsageTracker {
+        UsageTracker {
+            pid,
+            started: Instant::now(),
+            peak_rss_kib: 0,
+            cpu_time: Duration::from_secs(0),
+        }
+    }
+
+    /// Sample the current resource usage of the process. This silently does nothing if the
+    /// process has already exited, as its usage can no longer be read.
+    pub fn sample(&mut self) {
+        if let Ok(rss_kib) = read_peak_rss_kib(self.pid) {
+            self.peak_rss_kib = self.peak_rss_kib.max(rss_kib);
+        }
+        if let Ok(cpu_time) = read_cpu_time(self.pid) {
+            self.cpu_time = self.cpu_time.max(cpu_time);
+        }
+    }
+
+    /// Read the current resource usage of the process, failing if it has already exited.
+    pub fn current(&self) -> io::Result<UsageSample> {
+        Ok(UsageSample {
+            uptime: self.started.elapsed(),
+            rss_kib: read_status_kib(self.pid, &["VmRSS:"])?,
+            cpu_time: read_cpu_time(self.pid)?,
+        })
+    }
+
+    /// Summarise the usage sampled so far, now that the process has exited with the given status,
+    /// if it is known.
+    pub fn summary(&self, status: Option<ExitStatus>, stop_reason: StopReason) -> UsageSummary {
+        UsageSummary {
+            uptime: self.started.elapsed(),
+            peak_rss_kib: self.peak_rss_kib,
+            cpu_time: self.cpu_time,
+            stop_reason,
+            exit_reason: exit_reason(status),
+        }
+    }
+}
+
+/// Describe why a process exited.
+fn exit_reason(status: Option<ExitStatus>) -> String {
+    match status {
+        Some(status) => match (status.code(), status.signal()) {
+            (Some(code), _) => format!("exited with status {}", code),
+            (None, Some(signal)) => format!("killed by signal {}", signal),
+            (None, None) => status.to_string(),
+        },
+        None => "unknown".to_owned(),
+    }
+}
+
+/// Read the resident set size of the given process in KiB from `/proc/<pid>/status`. This takes the
+/// larger of the current value and the high water mark recorded by the kernel, so that peaks
+/// between samples aren't missed.
+fn read_peak_rss_kib(pid: u32) -> io::Result<u64> {
+    read_status_kib(pid, &["VmRSS:", "VmHWM:"])
+}
+
+/// Read the largest of the given memory fields of `/proc/<pid>/status` for the given process, in
+/// KiB.
+fn read_status_kib(pid: u32, fields: &[&str]) -> io::Result<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+    status
+        .lines()
+        .filter(|line| fields.iter().any(|field| line.starts_with(field)))
+        .filter_map(|line| line.split_whitespace().nth(1)?.parse().ok())
+        .max()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No RSS in process status"))
+}
+
+/// Read the total CPU time used so far by the given process from `/proc/<pid>/stat`.
+fn read_cpu_time(pid: u32) -> io::Result<Duration> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // The command name may contain spaces, so skip past it before splitting. The remaining fields
+    // start from the state, so utime and stime (fields 14 and 15) are at indices 11 and 12.
+    let fields: Vec<&str> = match stat.rfind(')') {
+        Some(end) => stat[end + 1..].split_whitespace().collect(),
+        None => vec![],
+    };
+    let ticks = fields
+        .get(11..13)
+        .and_then(|times| times.iter().map(|time| time.parse::<u64>().ok()).sum::<Option<u64>>())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed process stat"))?;
+    // Safe because this doesn't modify any memory.
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Duration::from_millis(ticks * 1000 / ticks_per_second as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn peak_rss_is_at_least_observed() {
+        let mut child = Command::new("sleep").arg("1").spawn().unwrap();
+        let mut tracker = UsageTracker::new(child.id());
+        tracker.sample();
+        let observed = read_peak_rss_kib(child.id()).unwrap();
+        tracker.sample();
+        let status = child.wait().unwrap();
+        tracker.sample();
+
+        let summary = tracker.summary(Some(status), StopReason::Exited);
+        assert!(observed > 0);
+        assert!(summary.peak_rss_kib >= observed);
+        assert_eq!(summary.exit_reason, "exited with status 0");
+    }
+
+    #[test]
+    fn current_usage_skips_exited_processes() {
+        let mut children: Vec<_> =
+            (0..3).map(|_| Command::new("sleep").arg("10").spawn().unwrap()).collect();
+        let trackers: Vec<_> = children.iter().map(|child| UsageTracker::new(child.id())).collect();
+        children[1].kill().unwrap();
+        children[1].wait().unwrap();
+
+        let live: Vec<u32> = trackers
+            .iter()
+            .filter_map(|tracker| tracker.current().ok().map(|sample| (tracker.pid, sample)))
+            .map(|(pid, sample)| {
+                assert!(sample.rss_kib > 0);
+                pid
+            })
+            .collect();
+        assert_eq!(live, [children[0].id(), children[2].id()]);
+
+        for child in &mut children {
+            let _ = child.kill();
+            child.wait().unwrap();
+        }
+    }
+}
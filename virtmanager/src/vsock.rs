@@ -0,0 +1,420 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for vsock connections from the host to guest VMs.
+
+use crate::Cid;
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the thread forwarding connections between VMs checks whether it should stop.
+const FORWARDER_STOP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a vsock connection maintained by the Virt Manager is for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionKind {
+    /// Checking whether a service which a VM depends on is reachable yet.
+    Readiness,
+    /// Sending a request to the agent in a guest.
+    Agent,
+    /// One side of a connection forwarded from one VM to another.
+    Forwarding,
+}
+
+impl ConnectionKind {
+    /// The name of the connection kind, for debugging.
+    pub fn name(self) -> &'static str {
+        match self {
+            ConnectionKind::Readiness => "readiness",
+            ConnectionKind::Agent => "agent",
+            ConnectionKind::Forwarding => "forwarding",
+        }
+    }
+}
+
+/// Details of a vsock connection maintained by the Virt Manager.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    /// What the connection is for.
+    pub kind: ConnectionKind,
+    /// Whether the host connected to the guest, rather than the guest to the host.
+    pub outgoing: bool,
+    /// The CID of the other end of the connection.
+    pub cid: Cid,
+    /// The port connected to, which is on the guest for outgoing connections and on the host for
+    /// incoming ones.
+    pub port: u32,
+    /// When the connection was opened.
+    pub opened: Instant,
+}
+
+/// The vsock connections maintained by the Virt Manager on behalf of a VM, for debugging leaked
+/// or hung connections. Clones share the same set of connections.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionTracker {
+    connections: Arc<Mutex<(u64, BTreeMap<u64, ConnectionInfo>)>>,
+}
+
+impl ConnectionTracker {
+    /// Start tracking a connection, until the returned guard is dropped.
+    pub fn track(&self, kind: ConnectionKind, outgoing: bool, cid: Cid, port: u32) -> Tracked {
+        let (next_id, connections) = &mut *self.connections.lock().unwrap();
+        let id = *next_id;
+        *next_id += 1;
+        connections
+            .insert(id, ConnectionInfo { kind, outgoing, cid, port, opened: Instant::now() });
+        Tracked { tracker: self.clone(), id }
+    }
+
+    /// Get the connections currently being tracked, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.connections.lock().unwrap().1.values().cloned().collect()
+    }
+}
+
+/// A connection which is being tracked by a `ConnectionTracker` until this is dropped.
+#[derive(Debug)]
+pub struct Tracked {
+    tracker: ConnectionTracker,
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.tracker.connections.lock().unwrap().1.remove(&self.id);
+    }
+}
+
+/// Connect to the given port of the VM with the given CID. If a timeout is given then reads and
+/// writes on the returned stream will time out after it. The socket is owned by the returned file
+/// from the start, so it is closed if connecting fails.
+pub fn connect(cid: Cid, port: u32, timeout: Option<Duration>) -> io::Result<File> {
+    let socket = new_socket()?;
+    let address = vm_address(cid, port);
+    // Safe because we pass a valid address along with its correct size, and check the return
+    // value.
+    let ret = unsafe {
+        libc::connect(
+            socket.as_raw_fd(),
+            &address as *const libc::sockaddr_vm as *const libc::sockaddr,
+            size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if let Some(timeout) = timeout {
+        set_timeout(&socket, libc::SO_RCVTIMEO, timeout)?;
+        set_timeout(&socket, libc::SO_SNDTIMEO, timeout)?;
+    }
+    Ok(socket)
+}
+
+/// Create a socket listening for connections from guests on a port of the host chosen by the
+/// kernel, returning it along with the port.
+pub fn listen() -> io::Result<(File, u32)> {
+    let socket = new_socket()?;
+    let address = vm_address(libc::VMADDR_CID_HOST, libc::VMADDR_PORT_ANY);
+    // Safe because we pass a valid address along with its correct size, and check the return
+    // value.
+    let ret = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &address as *const libc::sockaddr_vm as *const libc::sockaddr,
+            size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because this doesn't modify any memory and we check the return value.
+    if unsafe { libc::listen(socket.as_raw_fd(), 1) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let port = local_port(&socket)?;
+    Ok((socket, port))
+}
+
+/// Accept a connection on the given listening socket, returning it along with the CID it came
+/// from.
+fn accept(listener: &File) -> io::Result<(File, Cid)> {
+    let mut address = vm_address(0, 0);
+    let mut length = size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+    // Safe because we pass a valid address buffer and its correct size, and check the return
+    // value.
+    let fd = unsafe {
+        libc::accept4(
+            listener.as_raw_fd(),
+            &mut address as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+            &mut length,
+            libc::SOCK_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we have just accepted the connection so we own it.
+    Ok((unsafe { File::from_raw_fd(fd) }, address.svm_cid))
+}
+
+/// Wait up to the given timeout for a connection to be ready to accept on the given listening
+/// socket, returning whether one is.
+fn wait_for_connection(listener: &File, timeout: Duration) -> io::Result<bool> {
+    let mut poll_fd = libc::pollfd { fd: listener.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    // Safe because we pass a single valid pollfd, and check the return value.
+    let ret = unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret > 0)
+}
+
+/// Spawn a thread which accepts connections on the given listening socket from the VM with the
+/// source CID, and forwards each to the given port of the VM with the target CID. Connections from
+/// any other CID are refused. This continues until `stop` is set, after which the listening socket
+/// is closed. Each side of the forwarded connections is tracked by the given tracker of the VM on
+/// that side, until the connection is closed.
+pub fn spawn_forwarder(
+    listener: File,
+    source: (Cid, &ConnectionTracker),
+    target: (Cid, u32, &ConnectionTracker),
+    stop: Arc<AtomicBool>,
+) {
+    let (source_cid, source_connections) = (source.0, source.1.clone());
+    let (target_cid, target_port, target_connections) = (target.0, target.1, target.2.clone());
+    let host_port = match local_port(&listener) {
+        Ok(port) => port,
+        Err(e) => {
+            warn!("Failed to get port of vsock forwarder: {}", e);
+            libc::VMADDR_PORT_ANY
+        }
+    };
+    thread::spawn(move || loop {
+        let accepted = wait_for_connection(&listener, FORWARDER_STOP_POLL_INTERVAL)
+            .and_then(|ready| if ready { accept(&listener).map(Some) } else { Ok(None) });
+        if stop.load(Ordering::Acquire) {
+            info!("Stopped forwarding vsock from CID {} to {}", source_cid, target_cid);
+            return;
+        }
+        let (connection, peer_cid) = match accepted {
+            Ok(Some(accepted)) => accepted,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Failed to accept vsock connection to forward, giving up: {}", e);
+                return;
+            }
+        };
+        if peer_cid != source_cid {
+            warn!("Refusing forwarded vsock connection from unexpected CID {}", peer_cid);
+            continue;
+        }
+        let accepted =
+            source_connections.track(ConnectionKind::Forwarding, false, source_cid, host_port);
+        let connected =
+            target_connections.track(ConnectionKind::Forwarding, true, target_cid, target_port);
+        // Forwarded connections may be idle for a long time, so don't time out.
+        match connect(target_cid, target_port, None) {
+            Ok(target) => {
+                if let Err(e) = proxy(connection, target, Arc::new((accepted, connected))) {
+                    error!("Failed to proxy vsock connection: {}", e);
+                }
+            }
+            Err(e) => error!("Failed to connect to CID {} port {}: {}", target_cid, target_port, e),
+        }
+    });
+}
+
+/// Copy data in both directions between the two given sockets on separate threads, until each
+/// direction reaches the end of its stream. The given guard is dropped once both directions have
+/// finished.
+fn proxy<T: Send + Sync + 'static>(a: File, b: File, guard: Arc<T>) -> io::Result<()> {
+    for (mut from, mut to) in vec![(a.try_clone()?, b.try_clone()?), (b, a)] {
+        let guard = guard.clone();
+        thread::spawn(move || {
+            let _guard = guard;
+            if let Err(e) = io::copy(&mut from, &mut to) {
+                warn!("Error proxying vsock connection: {}", e);
+            }
+            // Let the other side know that there is no more data in this direction.
+            // Safe because this doesn't modify any memory.
+            unsafe { libc::shutdown(to.as_raw_fd(), libc::SHUT_WR) };
+        });
+    }
+    Ok(())
+}
+
+/// Get the port to which the given socket is bound.
+fn local_port(socket: &File) -> io::Result<u32> {
+    let mut address = vm_address(0, 0);
+    let mut length = size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+    // Safe because we pass a valid address buffer and its correct size, and check the return
+    // value.
+    let ret = unsafe {
+        libc::getsockname(
+            socket.as_raw_fd(),
+            &mut address as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+            &mut length,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(address.svm_port)
+}
+
+/// Create a new unbound vsock stream socket.
+fn new_socket() -> io::Result<File> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we have just created the socket so we own it, and `from_raw_fd` takes ownership
+    // of it. This ensures it is closed if a later step fails.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Build a vsock address for the given CID and port.
+fn vm_address(cid: Cid, port: u32) -> libc::sockaddr_vm {
+    libc::sockaddr_vm {
+        svm_family: libc::AF_VSOCK as libc::sa_family_t,
+        svm_reserved1: 0,
+        svm_port: port,
+        svm_cid: cid,
+        svm_zero: [0; 4],
+    }
+}
+
+/// Set the given timeout socket option.
+fn set_timeout(socket: &File, option: libc::c_int, timeout: Duration) -> io::Result<()> {
+    let timeval = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
+    // Safe because we pass a valid timeval along with its correct size, and check the return
+    // value.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &timeval as *const libc::timeval as *const libc::c_void,
+            size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    /// Count the file descriptors open in this process.
+    fn open_fd_count() -> usize {
+        std::fs::read_dir("/proc/self/fd").unwrap().count()
+    }
+
+    /// Create a connected pair of Unix stream sockets, as stand-ins for vsock streams.
+    fn socket_pair() -> (File, File) {
+        let (a, b) = UnixStream::pair().unwrap();
+        // Safe because we own the sockets, and `into_raw_fd` gives up ownership of them.
+        unsafe { (File::from_raw_fd(a.into_raw_fd()), File::from_raw_fd(b.into_raw_fd())) }
+    }
+
+    #[test]
+    fn proxy_connects_source_to_target_service() {
+        // The source guest's end of its connection, and the end the forwarder accepted.
+        let (mut source_guest, accepted) = socket_pair();
+        // The end the forwarder connected to the target, and the target guest's service's end.
+        let (connected, mut target_service) = socket_pair();
+        proxy(accepted, connected, Arc::new(())).unwrap();
+
+        source_guest.write_all(b"ping").unwrap();
+        let mut request = [0; 4];
+        target_service.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"ping");
+        target_service.write_all(b"pong").unwrap();
+        drop(target_service);
+
+        let mut response = vec![];
+        source_guest.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"pong");
+    }
+
+    #[test]
+    fn tracked_connections_are_listed_until_closed() {
+        let tracker = ConnectionTracker::default();
+        let readiness = tracker.track(ConnectionKind::Readiness, true, 11, 5678);
+        let agent = tracker.track(ConnectionKind::Agent, true, 10, 9999);
+
+        let connections = tracker.list();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].kind, ConnectionKind::Readiness);
+        assert_eq!((connections[0].cid, connections[0].port), (11, 5678));
+        assert!(connections[0].outgoing);
+        assert_eq!(connections[1].kind, ConnectionKind::Agent);
+
+        drop(readiness);
+        assert_eq!(tracker.clone().list().len(), 1);
+        drop(agent);
+        assert!(tracker.list().is_empty());
+    }
+
+    #[test]
+    fn proxy_releases_guard_when_both_directions_close() {
+        let tracker = ConnectionTracker::default();
+        let (source_guest, accepted) = socket_pair();
+        let (connected, target_service) = socket_pair();
+        let tracked = tracker.track(ConnectionKind::Forwarding, false, 11, 1234);
+        proxy(accepted, connected, Arc::new(tracked)).unwrap();
+        assert_eq!(tracker.list().len(), 1);
+
+        drop(source_guest);
+        drop(target_service);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !tracker.list().is_empty() {
+            assert!(Instant::now() < deadline, "Forwarded connection still tracked");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn failed_connects_do_not_leak_sockets() {
+        // CID 1 is the local loopback, on which nothing listens on this port in tests, so every
+        // connection is refused straight away.
+        const LOCAL_CID: Cid = 1;
+        let before = open_fd_count();
+        for _ in 0..100 {
+            assert!(connect(LOCAL_CID, 0xdead, None).is_err());
+        }
+        // Allow for other tests running concurrently opening a few files.
+        assert!(open_fd_count() < before + 16);
+    }
+}
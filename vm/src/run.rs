@@ -20,6 +20,8 @@ use android_system_virtmanager::aidl::android::system::virtmanager::IVirtualMach
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtualMachineCallback::{
     BnVirtualMachineCallback, IVirtualMachineCallback,
 };
+use android_system_virtmanager::aidl::android::system::virtmanager::VirtualMachineState::VirtualMachineState;
+use android_system_virtmanager::aidl::android::system::virtmanager::VmStopSummary::VmStopSummary;
 use android_system_virtmanager::binder::{
     BinderFeatures, DeathRecipient, IBinder, ParcelFileDescriptor, Strong,
 };
@@ -42,7 +44,7 @@ pub fn command_run(
     );
     let stdout_file = ParcelFileDescriptor::new(duplicate_stdout()?);
     let stdout = if daemonize { None } else { Some(&stdout_file) };
-    let vm = virt_manager.startVm(&config_file, stdout).context("Failed to start VM")?;
+    let vm = virt_manager.startVm(&config_file, stdout, 0).context("Failed to start VM")?;
 
     let cid = vm.getCid().context("Failed to get CID")?;
     println!("Started VM from {} with CID {}.", config_filename, cid);
@@ -98,6 +100,22 @@ impl IVirtualMachineCallback for VirtualMachineCallback {
         self.dead.raise();
         Ok(())
     }
+
+    fn onStateChanged(&self, _cid: i32, _state: VirtualMachineState) -> BinderResult<()> {
+        Ok(())
+    }
+
+    fn onStopped(&self, _cid: i32, summary: &VmStopSummary) -> BinderResult<()> {
+        println!(
+            "VM {} ({:?}) after {} ms, peak RSS {} KiB, CPU time {} ms",
+            summary.exitReason,
+            summary.stopReason,
+            summary.uptimeMillis,
+            summary.peakRssKib,
+            summary.cpuTimeMillis
+        );
+        Ok(())
+    }
 }
 
 /// Safely duplicate the standard output file descriptor.